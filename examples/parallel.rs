@@ -0,0 +1,79 @@
+use std::fs;
+use std::thread;
+use std::time::Instant;
+use intel8080::intel8080::ExecutionMode;
+use intel8080::invaders::{Inputs, Invaders};
+
+const ROM_PATH: &str = "cpu_tests/invaders.concatenated";
+const DEFAULT_INSTANCES: usize = 32;
+// A minute of play after the game has started
+const DEFAULT_FRAMES: usize = 3600;
+// Player 1's score, two BCD bytes low first
+const P1_SCORE: u16 = 0x20F8;
+
+// A player that mashes fire and wanders left and right, different for every seed
+fn random_inputs(state: &mut u64) -> Inputs {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    let mut inputs = Inputs::new();
+    inputs.p1shoot = *state & 3 == 0;
+    inputs.p1left = *state >> 8 & 3 == 0;
+    inputs.p1right = *state >> 8 & 3 == 1;
+    inputs
+}
+
+fn score(machine: &Invaders) -> u32 {
+    let bytes = machine.cpu.memory.slice(P1_SCORE, P1_SCORE + 1);
+    let bcd = u16::from_le_bytes([bytes[0], bytes[1]]);
+    (0..4).rev().fold(0, |score, digit| score * 10 + (bcd >> (digit * 4) & 0xF) as u32)
+}
+
+// Starts one game, then plays copies of it on separate threads with different random
+// inputs and reports each one's score. Takes the number of instances and frames, both
+// optional.
+fn main() {
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse().expect("arguments must be numbers"));
+    let instances = args.next().unwrap_or(DEFAULT_INSTANCES);
+    let frames = args.next().unwrap_or(DEFAULT_FRAMES);
+
+    let rom = fs::read(ROM_PATH).expect("Unable to read file");
+    let mut machine = Invaders::new(&rom);
+    machine.cpu.execution_mode = ExecutionMode::BlockCache;
+    for frame in 0..180 {
+        let mut inputs = Inputs::new();
+        inputs.coin = (60..70).contains(&frame);
+        inputs.p1start = (120..130).contains(&frame);
+        machine.cpu.io.inputs = inputs;
+        machine.run_frame().expect("trapped while starting the game");
+    }
+
+    let start = Instant::now();
+    let players: Vec<_> = (0..instances)
+        .map(|seed| {
+            let mut machine = machine.clone();
+            thread::spawn(move || {
+                let mut state = (seed as u64).wrapping_mul(0x9E3779B97F4A7C15) + 1;
+                for _ in 0..frames {
+                    machine.cpu.io.inputs = random_inputs(&mut state);
+                    machine.run_frame().expect("trapped during play");
+                    machine.cpu.io.sound_writes.clear();
+                }
+                machine
+            })
+        })
+        .collect();
+    let mut results: Vec<(usize, Invaders)> = players.into_iter()
+        .map(|player| player.join().unwrap())
+        .enumerate()
+        .collect();
+    let seconds = start.elapsed().as_secs_f64();
+
+    results.sort_by_key(|(_, machine)| std::cmp::Reverse(score(machine)));
+    for (seed, machine) in &results {
+        println!("seed {:3}  score {:4}  RAM hash {:016x}", seed, score(machine), machine.ram_hash());
+    }
+    let instructions: u64 = results.iter().map(|(_, machine)| machine.cpu.total_instructions).sum();
+    println!("{} instances x {} frames in {:.3}s, {:.2} million instructions per second in total",
+        instances, frames, seconds, instructions as f64 / seconds / 1e6);
+}
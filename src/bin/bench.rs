@@ -0,0 +1,54 @@
+use std::fs;
+use std::time::Instant;
+use intel8080::intel8080::ExecutionMode;
+use intel8080::invaders::{Inputs, Invaders, REFRESH_RATE};
+
+const ROM_PATH: &str = "cpu_tests/invaders.concatenated";
+// A minute of emulated time
+const DEFAULT_FRAMES: usize = 3600;
+
+// Inserts a coin and starts a game so the benchmark covers gameplay, not just the attract mode
+fn scripted_inputs(frame: usize) -> Inputs {
+    let mut inputs = Inputs::new();
+    inputs.coin = (60..70).contains(&frame);
+    inputs.p1start = (120..130).contains(&frame);
+    inputs.p1shoot = frame % 40 < 5;
+    inputs.p1left = frame % 200 < 100;
+    inputs.p1right = !inputs.p1left;
+    inputs
+}
+
+// Runs the bundled Space Invaders ROM headless as fast as it will go and reports the
+// instruction rate. Takes the number of frames to run and --block-cache, --jit or
+// --jit-differential (with the jit feature), all optional.
+fn main() {
+    let mut frames = DEFAULT_FRAMES;
+    let mut mode = ExecutionMode::Interpreter;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--block-cache" => mode = ExecutionMode::BlockCache,
+            #[cfg(feature = "jit")]
+            "--jit" => mode = ExecutionMode::Jit,
+            #[cfg(feature = "jit")]
+            "--jit-differential" => mode = ExecutionMode::JitDifferential,
+            _ => frames = arg.parse().expect("frame count must be a number"),
+        }
+    }
+    let rom = fs::read(ROM_PATH).expect("Unable to read file");
+    let mut machine = Invaders::new(&rom);
+    machine.cpu.execution_mode = mode;
+
+    let start = Instant::now();
+    for frame in 0..frames {
+        machine.cpu.io.inputs = scripted_inputs(frame);
+        machine.run_frame().expect("benchmark trapped");
+        machine.cpu.io.sound_writes.clear();
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    let instructions = machine.cpu.total_instructions;
+
+    println!("{:?}: {} frames, {} instructions in {:.3}s", mode, frames, instructions, seconds);
+    println!("{:.2} million instructions per second", instructions as f64 / seconds / 1e6);
+    println!("{:.1}x real time", frames as f64 / REFRESH_RATE as f64 / seconds);
+    println!("RAM hash: {:016x}", machine.ram_hash());
+}
@@ -0,0 +1,46 @@
+use std::fs;
+use std::process;
+use intel8080::dap::DapServer;
+use intel8080::host::StdClock;
+use intel8080::invaders::{Invaders, REFRESH_RATE};
+use intel8080::scheduler::FrameScheduler;
+
+const DEFAULT_ROM: &str = "cpu_tests/invaders.concatenated";
+
+// Runs a Space Invaders machine without a window, at real speed, under a DAP server. Talks
+// DAP on stdin and stdout unless --port N is given. Takes a ROM path, optional. Exits when
+// the client disconnects or terminates it.
+fn main() {
+    let mut rom_path = String::from(DEFAULT_ROM);
+    let mut port = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = Some(args.next().and_then(|port| port.parse().ok()).unwrap_or_else(|| {
+                eprintln!("--port needs a port number");
+                process::exit(2);
+            })),
+            _ => rom_path = arg,
+        }
+    }
+    let rom = fs::read(&rom_path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", rom_path, error);
+        process::exit(1);
+    });
+    let mut machine = Invaders::new(&rom);
+    let mut server = match port {
+        Some(port) => DapServer::listen(port).unwrap_or_else(|error| {
+            eprintln!("Could not listen on port {}: {}", port, error);
+            process::exit(1);
+        }),
+        None => DapServer::stdio(),
+    };
+    let mut scheduler = FrameScheduler::new(REFRESH_RATE, StdClock::new());
+    while !server.closed && !server.terminated {
+        if let Err(trap) = machine.run_frame_with(|cpu, cycles| server.run_cycles(cpu, cycles)) {
+            eprintln!("{}", trap);
+            process::exit(1);
+        }
+        scheduler.wait_for_next_frame();
+    }
+}
@@ -0,0 +1,18 @@
+use std::fs;
+use std::process;
+use intel8080::Disassembler;
+
+// Prints a listing of a ROM, one instruction per line with its offset in hex
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: disassemble ROM");
+        process::exit(2);
+    };
+    let rom = fs::read(&path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        process::exit(1);
+    });
+    let mut disassembler = Disassembler::new();
+    disassembler.load(rom);
+    disassembler.dump_all();
+}
@@ -0,0 +1,43 @@
+use std::fs;
+use std::process;
+use intel8080::gdb::GdbStub;
+use intel8080::host::StdClock;
+use intel8080::invaders::{Invaders, REFRESH_RATE};
+use intel8080::scheduler::FrameScheduler;
+
+const DEFAULT_ROM: &str = "cpu_tests/invaders.concatenated";
+const DEFAULT_PORT: u16 = 1234;
+
+// Runs a Space Invaders machine without a window, at real speed, under a GDB stub.
+// Takes --port N and a ROM path, both optional. Exits when GDB detaches or kills it.
+fn main() {
+    let mut rom_path = String::from(DEFAULT_ROM);
+    let mut port = DEFAULT_PORT;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = args.next().and_then(|port| port.parse().ok()).unwrap_or_else(|| {
+                eprintln!("--port needs a port number");
+                process::exit(2);
+            }),
+            _ => rom_path = arg,
+        }
+    }
+    let rom = fs::read(&rom_path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", rom_path, error);
+        process::exit(1);
+    });
+    let mut machine = Invaders::new(&rom);
+    let mut stub = GdbStub::listen(port).unwrap_or_else(|error| {
+        eprintln!("Could not listen on port {}: {}", port, error);
+        process::exit(1);
+    });
+    let mut scheduler = FrameScheduler::new(REFRESH_RATE, StdClock::new());
+    while !stub.closed {
+        if let Err(trap) = machine.run_frame_with(|cpu, cycles| stub.run_cycles(cpu, cycles)) {
+            eprintln!("{}", trap);
+            process::exit(1);
+        }
+        scheduler.wait_for_next_frame();
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::process;
+use intel8080::{recompiler, symbols};
+
+const USAGE: &str = "usage: recompile ROM OUTPUT.rs [--origin ADDR] [--entry ADDR]...";
+
+fn parse_address(text: &str) -> u16 {
+    symbols::parse_address(text).unwrap_or_else(|| {
+        eprintln!("{} is not a hex address", text);
+        process::exit(2);
+    })
+}
+
+// Translates a ROM into a Rust module of recompiled blocks, see src/recompiler.rs. The origin
+// defaults to 0 and the entry points to the origin; interrupt handlers (0x08 and 0x10 for
+// Space Invaders) need their own --entry.
+fn main() {
+    let mut paths = Vec::new();
+    let mut origin = 0;
+    let mut entries = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--origin" | "--entry" => {
+                let Some(value) = args.next() else {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                };
+                if arg == "--origin" {
+                    origin = parse_address(&value);
+                } else {
+                    entries.push(parse_address(&value));
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [rom_path, output_path] = paths.as_slice() else {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    if entries.is_empty() {
+        entries.push(origin);
+    }
+
+    let rom = fs::read(rom_path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", rom_path, error);
+        process::exit(1);
+    });
+    let program = recompiler::discover(&rom, origin, &entries);
+    if let Err(error) = fs::write(output_path, recompiler::generate(&program, "intel8080")) {
+        eprintln!("{}: {}", output_path, error);
+        process::exit(1);
+    }
+    println!("{} blocks covering {} of {} bytes", program.block_count(), program.covered_bytes(), rom.len());
+}
@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::{fs, process};
+use intel8080::golden::Comparison;
+use intel8080::intel8080::Intel8080;
+use intel8080::invaders::Invaders;
+use intel8080::memory::MemoryBus;
+use intel8080::symbols;
+use intel8080::trace::{InstructionClass, TraceFormat, Tracer};
+
+const USAGE: &str = "usage: trace ROM OUTPUT [--cpm] [--frames N] [--compact] [--no-annotations] [--range FROM-TO]... \
+                     [--class CLASS]... [--start ADDR] [--stop ADDR]\n       \
+                     trace ROM --compare REFERENCE [--cpm] [--frames N] [--ignore-cycles]";
+// Where CP/M loads programs
+const CPM_ORIGIN: u16 = 0x100;
+// A second of emulated time
+const DEFAULT_FRAMES: usize = 60;
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn parse_address(text: &str) -> u16 {
+    symbols::parse_address(text).unwrap_or_else(|| {
+        eprintln!("{} is not a hex address", text);
+        process::exit(2);
+    })
+}
+
+fn fail(path: &str, error: std::io::Error) -> ! {
+    eprintln!("{}: {}", path, error);
+    process::exit(1);
+}
+
+// Writes an instruction trace of a ROM to a file, see src/trace.rs. By default the ROM runs
+// on the Space Invaders machine, with no inputs, for a number of frames. With --cpm it is
+// loaded at 0x100 and runs until it jumps back to 0, which is how the CPU test programs end.
+// Classes are transfer, arithmetic, logical, branch, stack, io and control.
+// With --compare the ROM runs the same way in lockstep with an unfiltered reference trace,
+// from another emulator or an earlier build, and stops at the first instruction that differs.
+fn main() {
+    let mut paths = Vec::new();
+    let mut cpm = false;
+    let mut frames = DEFAULT_FRAMES;
+    let mut reference_path = None;
+    let mut compare_cycles = true;
+    let mut tracer = Tracer::new(TraceFormat::Full);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--cpm" => cpm = true,
+            "--compare" => reference_path = Some(value()),
+            "--ignore-cycles" => compare_cycles = false,
+            "--compact" => tracer.format = TraceFormat::Compact,
+            "--no-annotations" => tracer.annotate = false,
+            "--frames" => frames = value().parse().unwrap_or_else(|_| usage()),
+            "--range" => {
+                let range = value();
+                let Some((from, to)) = range.split_once('-') else {
+                    usage();
+                };
+                tracer.ranges.push(parse_address(from)..=parse_address(to));
+            }
+            "--class" => {
+                let name = value();
+                let Some(&class) = InstructionClass::ALL.iter().find(|class| class.name() == name) else {
+                    eprintln!("{} is not an instruction class", name);
+                    process::exit(2);
+                };
+                tracer.classes.insert(class);
+            }
+            "--start" => tracer.start = Some(parse_address(&value())),
+            "--stop" => tracer.stop = Some(parse_address(&value())),
+            _ if arg.starts_with("--") => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    if let Some(reference_path) = reference_path {
+        let [rom_path] = paths.as_slice() else {
+            usage();
+        };
+        let filtered = !tracer.ranges.is_empty() || !tracer.classes.is_empty() || tracer.start.is_some() || tracer.stop.is_some();
+        if filtered {
+            eprintln!("a filtered trace can't be compared");
+            process::exit(2);
+        }
+        compare(rom_path, &reference_path, cpm, frames, compare_cycles);
+        return;
+    }
+    let [rom_path, output_path] = paths.as_slice() else {
+        usage();
+    };
+
+    let rom = fs::read(rom_path).unwrap_or_else(|error| fail(rom_path, error));
+    let mut output = BufWriter::new(File::create(output_path).unwrap_or_else(|error| fail(output_path, error)));
+    let mut text = String::new();
+    let mut flush = |text: &mut String| {
+        if let Err(error) = output.write_all(text.as_bytes()) {
+            fail(output_path, error);
+        }
+        text.clear();
+    };
+    let trapped = if cpm {
+        let mut cpu = Intel8080::new();
+        for (offset, &byte) in rom.iter().enumerate() {
+            cpu.memory.poke(CPM_ORIGIN.wrapping_add(offset as u16), byte);
+        }
+        cpu.PC = CPM_ORIGIN;
+        while cpu.PC != 0 && !cpu.halted {
+            tracer.step(&mut cpu, &mut text);
+            if text.len() > 1 << 16 {
+                flush(&mut text);
+            }
+        }
+        println!();
+        None
+    } else {
+        let mut machine = Invaders::new(&rom);
+        (0..frames).find_map(|_| {
+            let result = machine.run_frame_with(|cpu, cycles| tracer.run_cycles(cpu, cycles, &mut text));
+            flush(&mut text);
+            result.err()
+        })
+    };
+    flush(&mut text);
+    if let Err(error) = output.flush() {
+        fail(output_path, error);
+    }
+    if let Some(trap) = trapped {
+        eprintln!("{}", trap);
+        process::exit(1);
+    }
+}
+
+fn compare(rom_path: &str, reference_path: &str, cpm: bool, frames: usize, compare_cycles: bool) {
+    let rom = fs::read(rom_path).unwrap_or_else(|error| fail(rom_path, error));
+    let reference = fs::read_to_string(reference_path).unwrap_or_else(|error| fail(reference_path, error));
+    let mut comparison = Comparison::new(&reference);
+    comparison.compare_cycles = compare_cycles;
+    if cpm {
+        let mut cpu = Intel8080::new();
+        for (offset, &byte) in rom.iter().enumerate() {
+            cpu.memory.poke(CPM_ORIGIN.wrapping_add(offset as u16), byte);
+        }
+        cpu.PC = CPM_ORIGIN;
+        while cpu.PC != 0 && !cpu.halted && comparison.step(&mut cpu) {}
+        println!();
+    } else {
+        let mut machine = Invaders::new(&rom);
+        for _ in 0..frames {
+            if !comparison.running() {
+                break;
+            }
+            if let Err(trap) = machine.run_frame_with(|cpu, cycles| Ok(comparison.run_cycles(cpu, cycles))) {
+                eprintln!("{}", trap);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(divergence) = &comparison.divergence {
+        print!("{}", divergence);
+        process::exit(1);
+    }
+    if let Some(line) = comparison.next_line() {
+        println!("Stopped after {} matching instructions, the reference goes on from line {}", comparison.matched, line);
+        process::exit(1);
+    }
+    println!("All {} instructions match", comparison.matched);
+}
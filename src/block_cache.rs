@@ -0,0 +1,289 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+// alloc has no HashMap, so without std blocks are kept in a BTreeMap
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as BlockMap;
+#[cfg(feature = "std")]
+use std::collections::HashMap as BlockMap;
+use crate::intel8080::{is_undocumented, Op, OPCODES};
+#[cfg(test)]
+use crate::intel8080::{ExecutionMode, Intel8080};
+use crate::memory::MemoryBus;
+
+// Longest run of instructions decoded into one block
+const MAX_BLOCK_LEN: usize = 64;
+// CP/M's BDOS entry point, which the CPU intercepts before running whatever is there
+pub(crate) const BDOS_ENTRY: u16 = 5;
+const PAGE_COUNT: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DecodedOp {
+    pub op: Op,
+    // The opcode and its operands, unused trailing bytes are zero
+    pub bytes: [u8; 3],
+    // Address of the instruction after this one
+    pub next_pc: u16,
+}
+
+impl Op {
+    pub(crate) fn size(self) -> u16 {
+        match self {
+            Op::Lxi(_) | Op::Shld | Op::Lhld | Op::Sta | Op::Lda | Op::Jcc(_) | Op::Jmp | Op::Ccc(_) | Op::Call => 3,
+            Op::Mvi(_) | Op::AluImmediate(_) | Op::Out | Op::In => 2,
+            _ => 1,
+        }
+    }
+
+    // States taken when a conditional return or call isn't taken and when it is, matching
+    // what the interpreter charges. Both are the same for everything else.
+    pub(crate) fn states(self) -> (usize, usize) {
+        let states = match self {
+            Op::Nop | Op::Rlc | Op::Rrc | Op::Ral | Op::Rar | Op::Daa | Op::Cma | Op::Stc | Op::Cmc
+                | Op::Xchg | Op::Di | Op::Ei => 4,
+            Op::Lxi(_) | Op::Dad(_) | Op::Pop(_) | Op::Jcc(_) | Op::Jmp | Op::Ret | Op::Out | Op::In => 10,
+            Op::Stax(_) | Op::Ldax(_) | Op::AluImmediate(_) | Op::Hlt => 7,
+            Op::Inx(_) | Op::Dcx(_) | Op::Pchl | Op::Sphl => 5,
+            Op::Inr(6) | Op::Dcr(6) | Op::Mvi(6) => 10,
+            Op::Inr(_) | Op::Dcr(_) => 5,
+            Op::Mvi(_) => 7,
+            Op::Shld | Op::Lhld => 16,
+            Op::Sta | Op::Lda => 13,
+            Op::Mov(6, _) | Op::Mov(_, 6) => 7,
+            Op::Mov(_, _) => 5,
+            Op::Alu(_, 6) => 7,
+            Op::Alu(_, _) => 4,
+            Op::Rcc(_) => return (5, 11),
+            Op::Ccc(_) => return (11, 17),
+            Op::Push(_) | Op::Rst(_) => 11,
+            Op::Call => 17,
+            Op::Xthl => 18,
+        };
+        (states, states)
+    }
+
+    // Anything that can move PC somewhere other than the next instruction ends a block, as do
+    // HLT and EI because they change when interrupts are taken
+    pub(crate) fn ends_block(self) -> bool {
+        matches!(self, Op::Jcc(_) | Op::Jmp | Op::Ccc(_) | Op::Call | Op::Rcc(_) | Op::Ret
+            | Op::Rst(_) | Op::Pchl | Op::Hlt | Op::Ei)
+    }
+}
+
+#[derive(Clone)]
+struct Block {
+    start: u16,
+    // Bytes of code covered, which may wrap past 0xFFFF
+    len: u16,
+    ops: Arc<[DecodedOp]>,
+}
+
+impl Block {
+    fn contains(&self, address: u16) -> bool {
+        address.wrapping_sub(self.start) < self.len
+    }
+
+    fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        let first = self.start as usize >> 8;
+        let last = self.start.wrapping_add(self.len - 1) as usize >> 8;
+        let count = (last + PAGE_COUNT - first) % PAGE_COUNT + 1;
+        (0..count).map(move |n| (first + n) % PAGE_COUNT)
+    }
+}
+
+// Straight-line runs of code decoded ahead of time and keyed by their start address.
+// Blocks are read with peek, so the memory bus must not have side effects on code fetches.
+// Writes the CPU makes are passed to invalidate; anything else that changes code (loaders,
+// debuggers) has to call clear.
+#[derive(Clone)]
+pub(crate) struct BlockCache {
+    blocks: BlockMap<u16, Block>,
+    // Start addresses of the blocks with code in each 256 byte page
+    pages: Vec<Vec<u16>>,
+    // Bumped whenever a block is dropped, so a block that is running can tell it went stale
+    pub generation: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache { blocks: BlockMap::new(), pages: vec![Vec::new(); PAGE_COUNT], generation: 0 }
+    }
+
+    // None if the instruction at `pc` has to go through the interpreter (undocumented
+    // opcodes, so that traps are reported the same way, and the BDOS hook)
+    pub fn get<M: MemoryBus>(&mut self, memory: &M, pc: u16) -> Option<Arc<[DecodedOp]>> {
+        if let Some(block) = self.blocks.get(&pc) {
+            return Some(block.ops.clone());
+        }
+        let block = build(memory, pc)?;
+        let ops = block.ops.clone();
+        for page in block.pages() {
+            self.pages[page].push(pc);
+        }
+        self.blocks.insert(pc, block);
+        Some(ops)
+    }
+
+    // Drops every block with code at `address`
+    pub fn invalidate(&mut self, address: u16) {
+        let page = address as usize >> 8;
+        if self.pages[page].is_empty() {
+            return;
+        }
+        let stale: Vec<u16> = self.pages[page].iter()
+            .copied()
+            .filter(|start| self.blocks[start].contains(address))
+            .collect();
+        for start in stale {
+            let block = self.blocks.remove(&start).unwrap();
+            for page in block.pages() {
+                self.pages[page].retain(|other| *other != start);
+            }
+            self.generation += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        for page in &mut self.pages {
+            page.clear();
+        }
+        self.generation += 1;
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+fn build<M: MemoryBus>(memory: &M, start: u16) -> Option<Block> {
+    let ops = decode_block(memory, start, |_| true);
+    if ops.is_empty() {
+        return None;
+    }
+    let len = ops.iter().map(|decoded| decoded.op.size()).sum();
+    Some(Block { start, len, ops: ops.into() })
+}
+
+// Decodes from `start` up to the end of the block, stopping early before any op `accept`
+// turns down. Empty if the first instruction can't go in a block.
+pub(crate) fn decode_block<M: MemoryBus>(memory: &M, start: u16, accept: impl Fn(Op) -> bool) -> Vec<DecodedOp> {
+    let mut ops = Vec::new();
+    if start == BDOS_ENTRY {
+        return ops;
+    }
+    let mut pc = start;
+    while ops.len() < MAX_BLOCK_LEN {
+        let opcode = memory.peek(pc);
+        let op = OPCODES[opcode as usize];
+        if is_undocumented(opcode) || !accept(op) {
+            break;
+        }
+        let size = op.size();
+        let mut bytes = [opcode, 0, 0];
+        for n in 1..size {
+            bytes[n as usize] = memory.peek(pc.wrapping_add(n));
+        }
+        pc = pc.wrapping_add(size);
+        ops.push(DecodedOp { op, bytes, next_pc: pc });
+        if op.ends_block() || pc == BDOS_ENTRY {
+            break;
+        }
+    }
+    ops
+}
+
+// Runs a CP/M CPU test program from cpu_tests/ in `mode` next to the plain interpreter for up
+// to `cycles` states or until it exits, panicking as soon as the two differ. Returns false
+// without running anything when the program isn't there.
+#[cfg(test)]
+pub(crate) fn matches_interpreter_on(name: &str, mode: ExecutionMode) -> bool {
+    // States run between comparisons
+    const CHUNK: usize = 1_000_000;
+    let Ok(program) = std::fs::read(std::format!("cpu_tests/{}", name)) else {
+        std::eprintln!("cpu_tests/{} not found, skipping", name);
+        return false;
+    };
+    let mut expected = Intel8080::new();
+    for (offset, &byte) in program.iter().enumerate() {
+        expected.memory.poke(0x0100 + offset as u16, byte);
+    }
+    // The hook that prints is off in tests, so BDOS calls just return and exiting halts
+    expected.memory.poke(BDOS_ENTRY, 0xC9);
+    expected.memory.poke(0x0000, 0x76);
+    expected.PC = 0x0100;
+    let mut actual = expected.clone();
+    actual.execution_mode = mode;
+    while !expected.halted {
+        expected.run_cycles(CHUNK).unwrap();
+        actual.run_cycles(CHUNK).unwrap();
+        assert_eq!(actual.state(), expected.state(), "{} in {:?}", name, mode);
+        for address in 0..=0xFFFF {
+            assert_eq!(actual.memory.peek(address), expected.memory.peek(address), "{} in {:?} at {:04x} after {} states",
+                       name, mode, address, expected.total_ticks);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn blocks_end_at_control_flow(){
+        let mut memory = FlatMemory::new();
+        // MVI A,1; INR A; JMP 0x0100; NOP
+        for (n, byte) in [0x3E, 0x01, 0x3C, 0xC3, 0x00, 0x01, 0x00].iter().enumerate() {
+            memory.poke(0x0010 + n as u16, *byte);
+        }
+        let mut cache = BlockCache::new();
+        let ops = cache.get(&memory, 0x0010).unwrap();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0].bytes, [0x3E, 0x01, 0x00]);
+        assert_eq!(ops[2].op, Op::Jmp);
+        assert_eq!(ops[2].next_pc, 0x0016);
+
+        // Undocumented opcodes are left to the interpreter
+        memory.poke(0x0020, 0xCB);
+        assert!(cache.get(&memory, 0x0020).is_none());
+        assert!(cache.get(&memory, BDOS_ENTRY).is_none());
+    }
+
+    #[test]
+    fn writes_invalidate_covering_blocks(){
+        let mut memory = FlatMemory::new();
+        // A block of NOPs from 0x00F0 ending in RET at 0x0110 spans two pages
+        memory.poke(0x0110, 0xC9);
+        let mut cache = BlockCache::new();
+        cache.get(&memory, 0x00F0).unwrap();
+        cache.get(&memory, 0x0108).unwrap();
+        assert_eq!(cache.len(), 2);
+
+        cache.invalidate(0x0200);
+        cache.invalidate(0x00E0);
+        assert_eq!(cache.len(), 2);
+        cache.invalidate(0x0100);
+        assert_eq!(cache.len(), 1);
+        let generation = cache.generation;
+        cache.invalidate(0x0110);
+        assert_eq!(cache.len(), 0);
+        assert_ne!(cache.generation, generation);
+        assert!(cache.pages.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn matches_interpreter_on_cpu_tests(){
+        matches_interpreter_on("TST8080.COM", ExecutionMode::BlockCache);
+        matches_interpreter_on("8080PRE.COM", ExecutionMode::BlockCache);
+    }
+
+    // Takes minutes, run with cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn matches_interpreter_on_8080exm(){
+        matches_interpreter_on("8080EXM.COM", ExecutionMode::BlockCache);
+    }
+}
@@ -0,0 +1,809 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use serde_json::{json, Value};
+use crate::debugger::{instructions_around, Action, Debugger, Stop};
+use crate::intel8080::{CpuState, Flag, IOHandler, Intel8080, Trap};
+use crate::memory::MemoryBus;
+use crate::symbols::{parse_address, Listing, Symbols};
+
+// Requests are checked this often while the CPU runs
+const POLL_CYCLES: usize = 10_000;
+const THREAD_ID: u64 = 1;
+
+// variablesReference values. Memory is split into 256 byte pages shown as rows of 16.
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+const MEMORY: u64 = 3;
+const MEMORY_PAGE: u64 = 0x100;
+
+const REGISTER_NAMES: [&str; 13] = ["A", "F", "B", "C", "D", "E", "H", "L", "BC", "DE", "HL", "SP", "PC"];
+const FLAG_NAMES: [(&str, Flag); 5] = [("S", Flag::Sign), ("Z", Flag::Zero), ("AC", Flag::AuxCarry), ("P", Flag::Parity), ("CY", Flag::Carry)];
+
+// Reads Content-Length framed messages on a thread of their own, so requests like pause
+// arrive while the CPU is running
+fn read_messages(input: impl Read + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        } else if line.is_empty() && length.is_some() {
+            break;
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+// Numbers typed into the client are decimal unless marked as hex with 0x, $ or H, so a
+// bare 10 is never read as 0x10
+fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if text.starts_with("0x") || text.starts_with('$') || text.ends_with(['h', 'H']) {
+        return parse_address(text);
+    }
+    text.parse().ok()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, &byte)| bits | (byte as u32) << (16 - index * 8));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - index * 6)) as usize & 63] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn register(state: &CpuState, name: &str) -> Option<u16> {
+    let pair = |high: u8, low: u8| u16::from_be_bytes([high, low]);
+    Some(match name.to_ascii_uppercase().as_str() {
+        "A" => state.a as u16,
+        "F" => state.f as u16,
+        "B" => state.b as u16,
+        "C" => state.c as u16,
+        "D" => state.d as u16,
+        "E" => state.e as u16,
+        "H" => state.h as u16,
+        "L" => state.l as u16,
+        "BC" => pair(state.b, state.c),
+        "DE" => pair(state.d, state.e),
+        "HL" => pair(state.h, state.l),
+        "SP" => state.sp,
+        "PC" => state.pc,
+        _ => return None,
+    })
+}
+
+fn set_register(state: &mut CpuState, name: &str, value: u16) -> Option<()> {
+    let [high, low] = value.to_be_bytes();
+    let byte = u8::try_from(value).ok();
+    match name.to_ascii_uppercase().as_str() {
+        "A" => state.a = byte?,
+        "F" => state.f = byte?,
+        "B" => state.b = byte?,
+        "C" => state.c = byte?,
+        "D" => state.d = byte?,
+        "E" => state.e = byte?,
+        "H" => state.h = byte?,
+        "L" => state.l = byte?,
+        "BC" => (state.b, state.c) = (high, low),
+        "DE" => (state.d, state.e) = (high, low),
+        "HL" => (state.h, state.l) = (high, low),
+        "SP" => state.sp = value,
+        "PC" => state.pc = value,
+        _ => return None,
+    }
+    Some(())
+}
+
+fn format_register(name: &str, value: u16) -> String {
+    if name.len() == 1 { format!("0x{:02X}", value) } else { format!("0x{:04X}", value) }
+}
+
+// A Debug Adapter Protocol server for one client, over stdio or a local TCP connection.
+// There is one thread (the CPU), stack frames come from the calls the debugger tracks, and
+// source lines come from a listing named in the launch or attach request. Like GdbStub it
+// runs in a frontend's cycle slices and owns the CPU while the client has it stopped.
+pub struct DapServer {
+    pub debugger: Debugger,
+    messages: Receiver<Value>,
+    output: Box<dyn Write + Send>,
+    seq: u64,
+    symbols: Symbols,
+    // The listing's path as the client named it
+    listing: Option<(String, Listing)>,
+    // Addresses asked for by each kind of breakpoint request, debugger.breakpoints holds
+    // their union along with anything set from the debug console
+    source_breakpoints: BTreeMap<String, Vec<u16>>,
+    function_breakpoints: Vec<u16>,
+    instruction_breakpoints: Vec<u16>,
+    placed: BTreeSet<u16>,
+    stop_on_entry: bool,
+    stopped: bool,
+    // Events to send once the current response is out
+    events: Vec<Value>,
+    // Set when the client disconnected, the CPU then runs freely
+    pub closed: bool,
+    // Set when the client asked for the emulator to end
+    pub terminated: bool,
+}
+
+impl DapServer {
+    pub fn new(input: impl Read + Send + 'static, output: impl Write + Send + 'static) -> DapServer {
+        let mut debugger = Debugger::new();
+        debugger.track_calls = true;
+        DapServer {
+            debugger,
+            messages: read_messages(input),
+            output: Box::new(output),
+            seq: 0,
+            symbols: Symbols::new(),
+            listing: None,
+            source_breakpoints: BTreeMap::new(),
+            function_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            placed: BTreeSet::new(),
+            stop_on_entry: false,
+            stopped: true,
+            events: Vec::new(),
+            closed: false,
+            terminated: false,
+        }
+    }
+
+    // Talks to the client over stdin and stdout, which nothing else may then print to
+    pub fn stdio() -> DapServer {
+        DapServer::new(io::stdin(), io::stdout())
+    }
+
+    // Waits for a client on localhost
+    pub fn listen(port: u16) -> io::Result<DapServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for a DAP client on port {}", port);
+        let (stream, _) = listener.accept()?;
+        let _ = stream.set_nodelay(true);
+        Ok(DapServer::new(stream.try_clone()?, stream))
+    }
+
+    // Same contract as Intel8080::run_cycles. The target is stopped until the client sends
+    // configurationDone, and while it is stopped requests are served here.
+    pub fn run_cycles<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, cycles: usize) -> Result<usize, Trap> {
+        let start = cpu.total_ticks;
+        while !self.terminated && cpu.total_ticks - start < cycles {
+            if self.closed {
+                cpu.run_cycles(cycles - (cpu.total_ticks - start))?;
+                break;
+            }
+            self.serve(cpu, start + cycles);
+        }
+        Ok(cpu.total_ticks - start)
+    }
+
+    fn serve<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, until: usize) {
+        while self.stopped && !self.closed {
+            match self.messages.recv() {
+                Ok(message) => self.handle(cpu, &message),
+                Err(_) => self.close(),
+            }
+        }
+        while !self.stopped && !self.closed && cpu.total_ticks < until {
+            match self.messages.try_recv() {
+                Ok(message) => {
+                    self.handle(cpu, &message);
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => self.close(),
+                Err(TryRecvError::Empty) => {}
+            }
+            let budget = (until - cpu.total_ticks).min(POLL_CYCLES);
+            if let (_, Some(stop)) = self.debugger.run_cycles(cpu, budget) {
+                self.stop(stop);
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.debugger = Debugger::new();
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let written = write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|_| self.output.flush());
+        if written.is_err() {
+            self.close();
+        }
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.events.push(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stop(&mut self, stop: Stop) {
+        self.stopped = true;
+        let (reason, text) = match stop {
+            Stop::Paused => ("pause", None),
+            Stop::Breakpoint(_) => ("breakpoint", None),
+            Stop::Watchpoint(_) => ("data breakpoint", None),
+            Stop::Stepped => ("step", None),
+            Stop::Trap(trap) => ("exception", Some(trap.to_string())),
+        };
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body);
+        self.flush_events();
+    }
+
+    fn flush_events(&mut self) {
+        for event in std::mem::take(&mut self.events) {
+            self.send(event);
+        }
+    }
+
+    // Answers one message. Anything but a request is ignored.
+    pub fn handle<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, message: &Value) {
+        if message["type"] != "request" {
+            return;
+        }
+        let command = message["command"].as_str().unwrap_or("");
+        let arguments = &message["arguments"];
+        let mut response = json!({ "type": "response", "request_seq": message["seq"], "command": command });
+        match self.request(cpu, command, arguments) {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(error) => {
+                response["success"] = json!(false);
+                response["message"] = json!(error);
+            }
+        }
+        self.send(response);
+        self.flush_events();
+        if command == "disconnect" {
+            self.close();
+        }
+    }
+
+    fn request<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, command: &str, arguments: &Value) -> Result<Value, String> {
+        let argument = |name: &str| arguments[name].as_i64();
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSetVariable": true,
+                "supportsSteppingGranularity": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" | "attach" => {
+                if let Some(path) = arguments["symbols"].as_str() {
+                    let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+                    self.symbols = Symbols::parse(&text);
+                }
+                if let Some(path) = arguments["listing"].as_str() {
+                    let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+                    self.listing = Some((path.to_string(), Listing::parse(&text)));
+                }
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                self.event("initialized", json!({}));
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.event("stopped", json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }));
+                } else {
+                    self.stopped = false;
+                }
+                Ok(json!({}))
+            }
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().unwrap_or("").to_string();
+                let listing = self.listing.as_ref().filter(|(listing, _)| same_file(listing, &path)).map(|(_, listing)| listing);
+                let mut addresses = Vec::new();
+                let mut breakpoints = Vec::new();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+                    match listing.and_then(|listing| listing.address(line)) {
+                        Some((line, address)) => {
+                            addresses.push(address);
+                            breakpoints.push(json!({ "verified": true, "line": line, "instructionReference": format!("0x{:04X}", address) }));
+                        }
+                        None if listing.is_some() => breakpoints.push(json!({ "verified": false, "message": "no code at or after this line" })),
+                        None => breakpoints.push(json!({ "verified": false, "message": "not the loaded listing" })),
+                    }
+                }
+                self.source_breakpoints.insert(path, addresses);
+                self.place_breakpoints();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setFunctionBreakpoints" => {
+                self.function_breakpoints.clear();
+                let mut breakpoints = Vec::new();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let name = breakpoint["name"].as_str().unwrap_or("");
+                    match self.symbols.address(name).or_else(|| parse_number(name)) {
+                        Some(address) => {
+                            self.function_breakpoints.push(address);
+                            breakpoints.push(json!({ "verified": true, "instructionReference": format!("0x{:04X}", address) }));
+                        }
+                        None => breakpoints.push(json!({ "verified": false, "message": "unknown symbol" })),
+                    }
+                }
+                self.place_breakpoints();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setInstructionBreakpoints" => {
+                self.instruction_breakpoints.clear();
+                let mut breakpoints = Vec::new();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let address = breakpoint["instructionReference"].as_str().and_then(parse_number)
+                        .map(|address| address.wrapping_add(breakpoint["offset"].as_i64().unwrap_or(0) as u16));
+                    match address {
+                        Some(address) => {
+                            self.instruction_breakpoints.push(address);
+                            breakpoints.push(json!({ "verified": true, "instructionReference": format!("0x{:04X}", address) }));
+                        }
+                        None => breakpoints.push(json!({ "verified": false, "message": "bad instruction reference" })),
+                    }
+                }
+                self.place_breakpoints();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "8080" }] })),
+            "stackTrace" => {
+                let frames = self.stack_frames(cpu);
+                let total = frames.len();
+                let start = argument("startFrame").unwrap_or(0) as usize;
+                let levels = argument("levels").filter(|&levels| levels > 0).map_or(total, |levels| levels as usize);
+                let frames: Vec<Value> = frames.into_iter().skip(start).take(levels).collect();
+                Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+            }
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "presentationHint": "registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY, "expensive": true },
+            ] })),
+            "variables" => Ok(json!({ "variables": self.variables(cpu, arguments["variablesReference"].as_u64().unwrap_or(0)) })),
+            "setVariable" => {
+                let name = arguments["name"].as_str().unwrap_or("");
+                let value = arguments["value"].as_str().unwrap_or("");
+                let mut state = cpu.state();
+                let shown = match arguments["variablesReference"].as_u64() {
+                    Some(REGISTERS) => {
+                        let number = parse_number(value).ok_or(format!("bad number {}", value))?;
+                        set_register(&mut state, name, number).ok_or(format!("can't set {} to {}", name, value))?;
+                        format_register(name, number)
+                    }
+                    Some(FLAGS) => {
+                        let set = match value {
+                            "1" | "true" => true,
+                            "0" | "false" => false,
+                            _ => return Err(format!("{} is a flag, use 0 or 1", name)),
+                        };
+                        match name {
+                            "INTE" => state.inte = set,
+                            "HALTED" => state.halted = set,
+                            _ => {
+                                let (_, flag) = FLAG_NAMES.iter().find(|(flag, _)| *flag == name).ok_or(format!("unknown flag {}", name))?;
+                                state.set_flag(*flag, set);
+                            }
+                        }
+                        String::from(if set { "1" } else { "0" })
+                    }
+                    _ => return Err(String::from("only registers and flags can be changed")),
+                };
+                cpu.set_state(&state);
+                Ok(json!({ "value": shown }))
+            }
+            "continue" => {
+                self.debugger.resume(cpu);
+                self.stopped = false;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                match command {
+                    "next" => self.debugger.step_over(cpu),
+                    "stepIn" => self.debugger.single_step(cpu),
+                    _ => self.debugger.step_out(cpu),
+                }
+                self.stopped = false;
+                Ok(json!({}))
+            }
+            "pause" => {
+                if !self.stopped {
+                    self.stopped = true;
+                    self.event("stopped", json!({ "reason": "pause", "threadId": THREAD_ID, "allThreadsStopped": true }));
+                }
+                Ok(json!({}))
+            }
+            "disassemble" => {
+                let base = arguments["memoryReference"].as_str().and_then(parse_number).ok_or("bad memory reference")?;
+                let address = base.wrapping_add(argument("offset").unwrap_or(0) as u16);
+                let offset = argument("instructionOffset").unwrap_or(0);
+                let count = argument("instructionCount").unwrap_or(0).max(0) as usize;
+                Ok(json!({ "instructions": self.disassemble(cpu, address, offset, count) }))
+            }
+            "readMemory" => {
+                let base = arguments["memoryReference"].as_str().and_then(parse_number).ok_or("bad memory reference")?;
+                let address = base.wrapping_add(argument("offset").unwrap_or(0) as u16);
+                let count = argument("count").unwrap_or(0).clamp(0, 0x10000) as usize;
+                let bytes: Vec<u8> = (0..count).map(|offset| cpu.memory.peek(address.wrapping_add(offset as u16))).collect();
+                Ok(json!({ "address": format!("0x{:04X}", address), "data": base64(&bytes) }))
+            }
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or("");
+                if arguments["context"] != "repl" {
+                    let value = register(&cpu.state(), expression).map(|value| format_register(expression, value))
+                        .or_else(|| self.symbols.address(expression).map(|address| format!("0x{:04X}", address)))
+                        .ok_or(format!("{} is not a register or symbol", expression))?;
+                    return Ok(json!({ "result": value, "variablesReference": 0 }));
+                }
+                // The debug console takes the debugger REPL's commands
+                let mut output = String::new();
+                match self.debugger.execute(cpu, expression, &mut output) {
+                    Action::Stay => {}
+                    Action::Resume => {
+                        self.stopped = false;
+                        self.event("continued", json!({ "threadId": THREAD_ID, "allThreadsContinued": true }));
+                    }
+                    Action::Quit => {
+                        self.terminated = true;
+                        self.event("terminated", json!({}));
+                    }
+                }
+                Ok(json!({ "result": output.trim_end(), "variablesReference": 0 }))
+            }
+            "disconnect" => {
+                if arguments["terminateDebuggee"].as_bool().unwrap_or(false) {
+                    self.terminated = true;
+                }
+                Ok(json!({}))
+            }
+            "terminate" => {
+                self.terminated = true;
+                self.event("terminated", json!({}));
+                Ok(json!({}))
+            }
+            _ => Err(format!("{} is not supported", command)),
+        }
+    }
+
+    fn place_breakpoints(&mut self) {
+        for address in std::mem::take(&mut self.placed) {
+            self.debugger.breakpoints.remove(&address);
+        }
+        let wanted = self.source_breakpoints.values().flatten().chain(&self.function_breakpoints).chain(&self.instruction_breakpoints);
+        self.placed = wanted.copied().filter(|address| !self.debugger.breakpoints.contains(address)).collect();
+        self.debugger.breakpoints.extend(&self.placed);
+    }
+
+    // SYMBOL, SYMBOL+3 or 0x1A3B
+    fn name_of(&self, address: u16) -> String {
+        match self.symbols.lookup(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("0x{:04X}", address),
+        }
+    }
+
+    // The source line for `address`, when the listing has it
+    fn location(&self, address: u16) -> Option<(Value, usize)> {
+        let (path, listing) = self.listing.as_ref()?;
+        let line = listing.line(address)?;
+        let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned());
+        Some((json!({ "name": name, "path": path }), line))
+    }
+
+    // Innermost first: where the CPU is, then where each tracked call returns to. A frame is
+    // named after the subroutine it is in when the call into it was seen.
+    fn stack_frames<M: MemoryBus, IO: IOHandler>(&self, cpu: &Intel8080<M, IO>) -> Vec<Value> {
+        let calls = &self.debugger.calls;
+        let mut frames = vec![(cpu.PC, calls.last().map(|call| call.entry))];
+        for (index, call) in calls.iter().enumerate().rev() {
+            let entry = index.checked_sub(1).map(|caller| calls[caller].entry);
+            frames.push((call.return_to, entry));
+        }
+        frames.into_iter().enumerate().map(|(id, (pc, entry))| {
+            let mut frame = json!({
+                "id": id,
+                "name": self.name_of(entry.unwrap_or(pc)),
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("0x{:04X}", pc),
+            });
+            if let Some((source, line)) = self.location(pc) {
+                frame["source"] = source;
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+            frame
+        }).collect()
+    }
+
+    fn variables<M: MemoryBus, IO: IOHandler>(&self, cpu: &Intel8080<M, IO>, reference: u64) -> Vec<Value> {
+        let state = cpu.state();
+        let variable = |name: &str, value: String, reference: u64| json!({ "name": name, "value": value, "variablesReference": reference });
+        match reference {
+            REGISTERS => REGISTER_NAMES.iter().map(|&name| {
+                let value = register(&state, name).unwrap_or(0);
+                let mut variable = variable(name, format_register(name, value), 0);
+                if name.len() == 2 {
+                    variable["memoryReference"] = json!(format!("0x{:04X}", value));
+                }
+                variable
+            }).collect(),
+            FLAGS => {
+                let flag = |set: bool| String::from(if set { "1" } else { "0" });
+                let mut flags: Vec<Value> = FLAG_NAMES.iter().map(|&(name, bit)| variable(name, flag(state.flag(bit)), 0)).collect();
+                flags.push(variable("INTE", flag(state.inte), 0));
+                flags.push(variable("HALTED", flag(state.halted), 0));
+                flags
+            }
+            MEMORY => (0..0x100).map(|page| variable(&format!("0x{:04X}", page << 8), String::new(), MEMORY_PAGE + page)).collect(),
+            _ if (MEMORY_PAGE..MEMORY_PAGE + 0x100).contains(&reference) => {
+                let page = ((reference - MEMORY_PAGE) << 8) as u16;
+                (0..16).map(|row| {
+                    let address = page + row * 16;
+                    let bytes: Vec<String> = (0..16).map(|offset| format!("{:02X}", cpu.memory.peek(address + offset))).collect();
+                    let mut variable = variable(&format!("0x{:04X}", address), bytes.join(" "), 0);
+                    variable["memoryReference"] = json!(format!("0x{:04X}", address));
+                    variable
+                }).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // `count` instructions starting `offset` instructions away from `address`. Where code
+    // before `address` can't be decoded the gap is filled with invalid entries.
+    fn disassemble<M: MemoryBus, IO: IOHandler>(&self, cpu: &Intel8080<M, IO>, address: u16, offset: i64, count: usize) -> Vec<Value> {
+        let before = (-offset).max(0) as usize;
+        let skip = offset.max(0) as usize;
+        let after = (count + skip).saturating_sub(before).max(1);
+        let decoded = instructions_around(cpu, address, before, after);
+        let found = decoded.len() - after;
+        let missing = (0..before - found).rev().map(|back| {
+            let address = address.wrapping_sub((found + back + 1) as u16);
+            json!({ "address": format!("0x{:04X}", address), "instruction": "", "presentationHint": "invalid" })
+        });
+        let decoded = decoded.into_iter().map(|(address, length, text)| {
+            let bytes: Vec<String> = (0..length).map(|index| format!("{:02X}", cpu.memory.peek(address.wrapping_add(index)))).collect();
+            let mut instruction = json!({
+                "address": format!("0x{:04X}", address),
+                "instructionBytes": bytes.join(" "),
+                "instruction": text,
+            });
+            if let Some((name, 0)) = self.symbols.lookup(address) {
+                instruction["symbol"] = json!(name);
+            }
+            if let Some((source, line)) = self.location(address) {
+                instruction["location"] = source;
+                instruction["line"] = json!(line);
+            }
+            instruction
+        });
+        missing.chain(decoded).skip(skip).take(count).collect()
+    }
+}
+
+// Clients send absolute paths, launch configurations are often relative
+fn same_file(a: &str, b: &str) -> bool {
+    a == b || match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Collects what the server writes
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn messages(&self) -> Vec<Value> {
+            let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+            let mut input = &bytes[..];
+            std::iter::from_fn(|| read_message(&mut input).unwrap()).collect()
+        }
+    }
+
+    // A client that never sends anything, requests are handed to the server directly
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            loop {
+                thread::park();
+            }
+        }
+    }
+
+    fn server() -> (DapServer, Output) {
+        let output = Output::default();
+        (DapServer::new(Silent, output.clone()), output)
+    }
+
+    fn request(server: &mut DapServer, cpu: &mut Intel8080, output: &Output, command: &str, arguments: Value) -> Vec<Value> {
+        server.handle(cpu, &json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }));
+        let messages = output.messages();
+        assert_eq!(messages[0]["success"], true, "{}", messages[0]);
+        messages
+    }
+
+    // run_cycles would go on to wait for requests once the target stops
+    fn run_until_stopped(server: &mut DapServer, cpu: &mut Intel8080) {
+        while !server.stopped {
+            server.serve(cpu, cpu.total_ticks + 1000);
+        }
+    }
+
+    // 0000 LXI SP,0100  0003 CALL 0010  0006 JMP 0006
+    // 0010 MVI A,42     0012 RET
+    fn cpu() -> Intel8080 {
+        let mut program = vec![0; 0x13];
+        program[..9].copy_from_slice(&[0x31, 0x00, 0x01, 0xCD, 0x10, 0x00, 0xC3, 0x06, 0x00]);
+        program[0x10..].copy_from_slice(&[0x3E, 0x42, 0xC9]);
+        let mut cpu = Intel8080::new();
+        cpu.load_program(program);
+        cpu
+    }
+
+    #[test]
+    fn stops_at_breakpoints_with_a_stack(){
+        let (mut server, output) = server();
+        let mut cpu = cpu();
+        server.symbols.insert("Start", 0x0000);
+        server.symbols.insert("Answer", 0x0010);
+        let messages = request(&mut server, &mut cpu, &output, "setFunctionBreakpoints", json!({ "breakpoints": [{ "name": "Answer" }, { "name": "Nope" }] }));
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(messages[0]["body"]["breakpoints"][1]["verified"], false);
+        request(&mut server, &mut cpu, &output, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x0010", "offset": 2 }] }));
+        assert_eq!(server.debugger.breakpoints, BTreeSet::from([0x10, 0x12]));
+
+        request(&mut server, &mut cpu, &output, "configurationDone", json!({}));
+        run_until_stopped(&mut server, &mut cpu);
+        let messages = output.messages();
+        assert_eq!(messages[0]["event"], "stopped");
+        assert_eq!(messages[0]["body"]["reason"], "breakpoint");
+        assert_eq!(cpu.PC, 0x10);
+
+        let messages = request(&mut server, &mut cpu, &output, "stackTrace", json!({ "threadId": 1 }));
+        let frames = &messages[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "Answer");
+        assert_eq!(frames[0]["instructionPointerReference"], "0x0010");
+        assert_eq!(frames[1]["name"], "Start+6");
+        assert_eq!(frames[1]["instructionPointerReference"], "0x0006");
+
+        request(&mut server, &mut cpu, &output, "next", json!({ "threadId": 1 }));
+        run_until_stopped(&mut server, &mut cpu);
+        assert_eq!(output.messages()[0]["body"]["reason"], "step");
+        assert_eq!(cpu.PC, 0x12);
+        request(&mut server, &mut cpu, &output, "stepOut", json!({ "threadId": 1 }));
+        run_until_stopped(&mut server, &mut cpu);
+        assert_eq!(cpu.PC, 0x06);
+        assert!(server.debugger.calls.is_empty());
+    }
+
+    #[test]
+    fn registers_memory_and_disassembly(){
+        let (mut server, output) = server();
+        let mut cpu = cpu();
+        let messages = request(&mut server, &mut cpu, &output, "setVariable", json!({ "variablesReference": REGISTERS, "name": "HL", "value": "0x1234" }));
+        assert_eq!(messages[0]["body"]["value"], "0x1234");
+        // Bare numbers are decimal, hex needs a marker
+        for (value, shown) in [("10", "0x0A"), ("10H", "0x10"), ("$10", "0x10"), ("0x10", "0x10")] {
+            let messages = request(&mut server, &mut cpu, &output, "setVariable", json!({ "variablesReference": REGISTERS, "name": "D", "value": value }));
+            assert_eq!(messages[0]["body"]["value"], shown, "{}", value);
+        }
+        server.handle(&mut cpu, &json!({ "seq": 1, "type": "request", "command": "setVariable",
+                                         "arguments": { "variablesReference": REGISTERS, "name": "D", "value": "1A" } }));
+        assert_eq!(output.messages()[0]["success"], false);
+        assert_eq!(cpu.state().d, 0x10);
+        request(&mut server, &mut cpu, &output, "setVariable", json!({ "variablesReference": FLAGS, "name": "CY", "value": "1" }));
+        let messages = request(&mut server, &mut cpu, &output, "variables", json!({ "variablesReference": REGISTERS }));
+        let registers = &messages[0]["body"]["variables"];
+        assert_eq!(registers[1]["value"], "0x03");
+        assert_eq!(registers[6]["value"], "0x12");
+        assert_eq!(registers[10]["memoryReference"], "0x1234");
+
+        let messages = request(&mut server, &mut cpu, &output, "variables", json!({ "variablesReference": MEMORY_PAGE }));
+        assert_eq!(messages[0]["body"]["variables"][1]["value"], "3E 42 C9 00 00 00 00 00 00 00 00 00 00 00 00 00");
+        let messages = request(&mut server, &mut cpu, &output, "readMemory", json!({ "memoryReference": "0x0010", "count": 3 }));
+        assert_eq!(messages[0]["body"]["data"], "PkLJ");
+
+        let messages = request(&mut server, &mut cpu, &output, "disassemble",
+            json!({ "memoryReference": "0x0006", "instructionOffset": -3, "instructionCount": 4 }));
+        let instructions = &messages[0]["body"]["instructions"];
+        assert_eq!(instructions[0]["presentationHint"], "invalid");
+        assert_eq!(instructions[1]["address"], "0x0000");
+        assert_eq!(instructions[1]["instructionBytes"], "31 00 01");
+        assert_eq!(instructions[3]["address"], "0x0006");
+        assert_eq!(instructions.as_array().unwrap().len(), 4);
+
+        let messages = request(&mut server, &mut cpu, &output, "evaluate", json!({ "expression": "x 10 3", "context": "repl" }));
+        assert_eq!(messages[0]["body"]["result"], "0010: 3E 42 C9                                         >B.");
+    }
+
+    #[test]
+    fn source_breakpoints_need_the_listing(){
+        let path = std::env::temp_dir().join(format!("intel8080-dap-{}.lst", std::process::id()));
+        fs::write(&path, "0000 31 00 01  LXI SP,0100H\n0003 CD 10 00  CALL ANSWER\n\n0010 ANSWER:\n0010 3E 42     MVI A,42H\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let (mut server, output) = server();
+        let mut cpu = cpu();
+        let messages = request(&mut server, &mut cpu, &output, "launch", json!({ "listing": path, "stopOnEntry": true }));
+        assert_eq!(messages[1]["event"], "initialized");
+        let messages = request(&mut server, &mut cpu, &output, "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 3 }, { "line": 9 }] }));
+        let breakpoints = &messages[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["line"], 5);
+        assert_eq!(breakpoints[0]["instructionReference"], "0x0010");
+        assert_eq!(breakpoints[1]["verified"], false);
+        let messages = request(&mut server, &mut cpu, &output, "setBreakpoints", json!({ "source": { "path": "other.asm" }, "breakpoints": [{ "line": 1 }] }));
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["verified"], false);
+
+        let messages = request(&mut server, &mut cpu, &output, "configurationDone", json!({}));
+        assert_eq!(messages[1]["body"]["reason"], "entry");
+        request(&mut server, &mut cpu, &output, "continue", json!({ "threadId": 1 }));
+        run_until_stopped(&mut server, &mut cpu);
+        assert_eq!(output.messages()[0]["body"]["reason"], "breakpoint");
+        assert_eq!(cpu.PC, 0x10);
+        let messages = request(&mut server, &mut cpu, &output, "stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["line"], 5);
+        assert_eq!(messages[0]["body"]["stackFrames"][1]["line"], 0);
+        fs::remove_file(&path).unwrap();
+    }
+}
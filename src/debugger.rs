@@ -0,0 +1,718 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use crate::disassembler::Disassembler;
+use crate::intel8080::{Access, Flag, IOHandler, Intel8080, Op, StepResult, Trap, TrapPolicy, OPCODES};
+use crate::memory::MemoryBus;
+use crate::symbols::parse_address;
+
+const HELP: &str = "\
+c, continue         run until a breakpoint or watchpoint
+s, step [N]         run N instructions (default 1)
+n, next             step, running CALL and RST bodies through
+finish              run until the current subroutine returns
+until ADDR          run to ADDR
+b, break ADDR       stop when PC reaches ADDR
+watch ADDR          stop after a write to ADDR
+rwatch ADDR         stop after a read of ADDR
+awatch ADDR         stop after a read or write of ADDR
+port PORT [in|out]  stop after IN or OUT on PORT (both by default)
+delete [ADDR]       remove breakpoints and watchpoints at ADDR, or everything
+delete port PORT    remove the port breakpoints on PORT
+info                list breakpoints and watchpoints
+r, regs             show registers and flags
+x ADDR [LEN]        dump LEN bytes of memory (default 64)
+l, list [ADDR] [N]  disassemble N instructions at ADDR, or around PC
+q, quit             quit the emulator
+An empty line repeats the last command. Addresses and ports are hex, counts are decimal.
+";
+
+// Why run_cycles stopped early
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stop {
+    // pause() was called, nothing has run
+    Paused,
+    Breakpoint(u16),
+    // An instruction made an access that is being watched. PC is past the instruction.
+    Watchpoint(Access),
+    // A step, step over, step out or run to address finished
+    Stepped,
+    Trap(Trap),
+}
+
+// What the REPL should do after a command
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Stay,
+    Resume,
+    Quit,
+}
+
+// When to stop besides breakpoints and watchpoints
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Target {
+    Free,
+    Pause,
+    Steps(u32),
+    // PC reaches `pc` with SP at or above `sp`, so recursive calls don't stop a step over early
+    Reach { pc: u16, sp: Option<u16> },
+    // A return leaves SP above `sp`
+    Return { sp: u16 },
+}
+
+// A subroutine call or interrupt that hasn't returned yet
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Call {
+    // The subroutine or interrupt vector that was called
+    pub entry: u16,
+    pub return_to: u16,
+    // SP with the return address pushed
+    pub sp: u16,
+}
+
+// Deeper calls than this drop the outermost ones, code that never returns would grow forever
+const MAX_CALL_DEPTH: usize = 256;
+
+// Breakpoints, watchpoints and stepping on top of Intel8080::step(). Commands come in as
+// text through execute(), so any frontend can drive it; repl() reads them from a terminal.
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub read_watches: BTreeSet<u16>,
+    pub write_watches: BTreeSet<u16>,
+    pub in_ports: BTreeSet<u8>,
+    pub out_ports: BTreeSet<u8>,
+    // Set by the quit command, the frontend should shut down
+    pub quit: bool,
+    // Keep `calls` up to date. Every instruction is stepped while this is set.
+    pub track_calls: bool,
+    // Calls in progress, outermost first
+    pub calls: Vec<Call>,
+    target: Target,
+    // Don't stop at a breakpoint on the instruction execution resumes from
+    resume_pc: Option<u16>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            read_watches: BTreeSet::new(),
+            write_watches: BTreeSet::new(),
+            in_ports: BTreeSet::new(),
+            out_ports: BTreeSet::new(),
+            quit: false,
+            track_calls: false,
+            calls: Vec::new(),
+            target: Target::Free,
+            resume_pc: None,
+            last_command: String::new(),
+        }
+    }
+
+    // Stops before the next instruction, e.g. from a hotkey
+    pub fn pause(&mut self) {
+        self.target = Target::Pause;
+    }
+
+    // Continues until a breakpoint or watchpoint, like the continue command
+    pub fn resume<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &Intel8080<M, IO>) {
+        self.target = Target::Free;
+        self.resume_pc = Some(cpu.PC);
+    }
+
+    // Stops again after one instruction, like the step command
+    pub fn single_step<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &Intel8080<M, IO>) {
+        self.target = Target::Steps(1);
+        self.resume_pc = Some(cpu.PC);
+    }
+
+    // Like single_step, but a CALL or RST runs until it returns
+    pub fn step_over<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &Intel8080<M, IO>) {
+        let op = OPCODES[cpu.memory.peek(cpu.PC) as usize];
+        self.target = match op {
+            Op::Call | Op::Ccc(_) | Op::Rst(_) => {
+                Target::Reach { pc: cpu.PC.wrapping_add(op.size()), sp: Some(cpu.state().sp) }
+            }
+            _ => Target::Steps(1),
+        };
+        self.resume_pc = Some(cpu.PC);
+    }
+
+    // Runs until the current subroutine returns
+    pub fn step_out<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &Intel8080<M, IO>) {
+        self.target = Target::Return { sp: cpu.state().sp };
+        self.resume_pc = Some(cpu.PC);
+    }
+
+    pub fn run_to<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &Intel8080<M, IO>, address: u16) {
+        self.target = Target::Reach { pc: address, sp: None };
+        self.resume_pc = Some(cpu.PC);
+    }
+
+    fn watching(&self) -> bool {
+        !(self.read_watches.is_empty() && self.write_watches.is_empty() && self.in_ports.is_empty() && self.out_ports.is_empty())
+    }
+
+    // Nothing can stop execution, so run_cycles can run at full speed
+    fn idle(&self) -> bool {
+        self.target == Target::Free && self.breakpoints.is_empty() && !self.watching() && !self.track_calls
+    }
+
+    fn watched(&self, access: Access) -> bool {
+        match access {
+            Access::Read(address) => self.read_watches.contains(&address),
+            Access::Write(address, _) => self.write_watches.contains(&address),
+            Access::Input(port, _) => self.in_ports.contains(&port),
+            Access::Output(port, _) => self.out_ports.contains(&port),
+        }
+    }
+
+    // Like Intel8080::run_cycles, but returns early with the reason when something stops
+    // execution. Instructions are stepped one at a time unless there is nothing to stop for.
+    pub fn run_cycles<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, cycles: usize) -> (usize, Option<Stop>) {
+        if self.target == Target::Pause {
+            self.target = Target::Free;
+            return (0, Some(Stop::Paused));
+        }
+        let start = cpu.total_ticks;
+        if self.idle() {
+            return match cpu.run_cycles(cycles) {
+                Ok(ran) => (ran, None),
+                Err(trap) => (cpu.total_ticks - start, Some(Stop::Trap(trap))),
+            };
+        }
+        let watching = self.watching();
+        if watching {
+            cpu.accesses = Some(Vec::new());
+        }
+        let mut stop = None;
+        while stop.is_none() && cpu.total_ticks - start < cycles {
+            stop = self.step(cpu, watching);
+        }
+        cpu.accesses = None;
+        if stop.is_some() {
+            self.target = Target::Free;
+        }
+        (cpu.total_ticks - start, stop)
+    }
+
+    fn step<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, watching: bool) -> Option<Stop> {
+        let pc = cpu.PC;
+        if self.breakpoints.contains(&pc) && self.resume_pc.take() != Some(pc) {
+            return Some(Stop::Breakpoint(pc));
+        }
+        self.resume_pc = None;
+        let op = OPCODES[cpu.memory.peek(pc) as usize];
+        let sp = cpu.state().sp;
+        let result = cpu.step();
+        if let StepResult::Trap { trap, .. } = result && cpu.trap_policy == TrapPolicy::Stop {
+            return Some(Stop::Trap(trap));
+        }
+        let new_sp = cpu.state().sp;
+        if self.track_calls {
+            self.track_call(pc, op, sp, new_sp, cpu.PC, result);
+        }
+        if watching {
+            let log = cpu.accesses.as_mut().map(core::mem::take).unwrap_or_default();
+            if let Some(&access) = log.iter().find(|&&access| self.watched(access)) {
+                return Some(Stop::Watchpoint(access));
+            }
+        }
+        let reached = match self.target {
+            Target::Free | Target::Pause => false,
+            Target::Steps(1) => true,
+            Target::Steps(n) => {
+                self.target = Target::Steps(n - 1);
+                false
+            }
+            Target::Reach { pc, sp } => cpu.PC == pc && sp.is_none_or(|sp| new_sp >= sp),
+            Target::Return { sp: frame } => {
+                let returned = matches!(result, StepResult::Executed { .. }) && matches!(op, Op::Ret | Op::Rcc(_)) && new_sp > sp;
+                returned && new_sp > frame
+            }
+        };
+        reached.then_some(Stop::Stepped)
+    }
+
+    // Frames SP has moved above have returned, one way or another. Calls are recognised by
+    // the return address they push.
+    fn track_call(&mut self, pc: u16, op: Op, sp: u16, new_sp: u16, new_pc: u16, result: StepResult) {
+        while self.calls.last().is_some_and(|call| call.sp < new_sp) {
+            self.calls.pop();
+        }
+        let pushed = new_sp == sp.wrapping_sub(2);
+        let return_to = match result {
+            StepResult::InterruptAccepted { .. } if pushed => pc,
+            StepResult::Executed { .. } if pushed && matches!(op, Op::Call | Op::Ccc(_) | Op::Rst(_)) => pc.wrapping_add(op.size()),
+            _ => return,
+        };
+        if self.calls.len() == MAX_CALL_DEPTH {
+            self.calls.remove(0);
+        }
+        self.calls.push(Call { entry: new_pc, return_to, sp: new_sp });
+    }
+
+    // Runs one command line, appending what it prints to `out`
+    pub fn execute<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, line: &str, out: &mut String) -> Action {
+        let line = if line.trim().is_empty() { core::mem::take(&mut self.last_command) } else { String::from(line.trim()) };
+        let action = match self.command(cpu, &line, out) {
+            Ok(action) => action,
+            Err(message) => {
+                let _ = writeln!(out, "{}", message);
+                Action::Stay
+            }
+        };
+        self.last_command = line;
+        if action == Action::Resume {
+            self.resume_pc = Some(cpu.PC);
+        }
+        action
+    }
+
+    fn command<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, line: &str, out: &mut String) -> Result<Action, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Action::Stay);
+        };
+        let args: Vec<&str> = words.collect();
+        let address = |index: usize| args.get(index).ok_or(String::from("missing address")).and_then(|arg| parse_hex(arg));
+        match command {
+            "c" | "continue" => {
+                self.target = Target::Free;
+                return Ok(Action::Resume);
+            }
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(arg) => arg.parse().ok().filter(|&count| count > 0).ok_or(format!("bad count {}", arg))?,
+                    None => 1,
+                };
+                self.target = Target::Steps(count);
+                return Ok(Action::Resume);
+            }
+            "n" | "next" => {
+                self.step_over(cpu);
+                return Ok(Action::Resume);
+            }
+            "finish" => {
+                self.step_out(cpu);
+                return Ok(Action::Resume);
+            }
+            "until" => {
+                self.run_to(cpu, address(0)?);
+                return Ok(Action::Resume);
+            }
+            "b" | "break" => {
+                let address = address(0)?;
+                self.breakpoints.insert(address);
+                let _ = writeln!(out, "Breakpoint at {:04X}", address);
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let address = address(0)?;
+                if command != "rwatch" {
+                    self.write_watches.insert(address);
+                }
+                if command != "watch" {
+                    self.read_watches.insert(address);
+                }
+                let _ = writeln!(out, "Watching {:04X}", address);
+            }
+            "port" => {
+                let port = parse_port(args.first().ok_or("missing port")?)?;
+                let (input, output) = match args.get(1) {
+                    None => (true, true),
+                    Some(&"in") => (true, false),
+                    Some(&"out") => (false, true),
+                    Some(direction) => return Err(format!("expected in or out, got {}", direction)),
+                };
+                if input {
+                    self.in_ports.insert(port);
+                }
+                if output {
+                    self.out_ports.insert(port);
+                }
+                let _ = writeln!(out, "Watching port {:02X}", port);
+            }
+            "delete" => match args.first() {
+                None => {
+                    self.breakpoints.clear();
+                    self.read_watches.clear();
+                    self.write_watches.clear();
+                    self.in_ports.clear();
+                    self.out_ports.clear();
+                }
+                Some(&"port") => {
+                    let port = parse_port(args.get(1).ok_or("missing port")?)?;
+                    self.in_ports.remove(&port);
+                    self.out_ports.remove(&port);
+                }
+                Some(_) => {
+                    let address = address(0)?;
+                    self.breakpoints.remove(&address);
+                    self.read_watches.remove(&address);
+                    self.write_watches.remove(&address);
+                }
+            },
+            "info" => self.list_points(out),
+            "r" | "regs" => registers(cpu, out),
+            "x" => {
+                let start = address(0)?;
+                let length = match args.get(1) {
+                    Some(arg) => arg.parse().map_err(|_| format!("bad length {}", arg))?,
+                    None => 64,
+                };
+                dump_memory(cpu, start, length, out);
+            }
+            "l" | "list" => {
+                let count = match args.get(1) {
+                    Some(arg) => arg.parse().map_err(|_| format!("bad count {}", arg))?,
+                    None => 10,
+                };
+                match args.first() {
+                    Some(_) => disassemble(cpu, address(0)?, 0, count, out),
+                    None => disassemble(cpu, cpu.PC, 4, count.saturating_sub(4), out),
+                }
+            }
+            "q" | "quit" => {
+                self.quit = true;
+                return Ok(Action::Quit);
+            }
+            "h" | "help" => out.push_str(HELP),
+            _ => return Err(format!("unknown command {}, try help", command)),
+        }
+        Ok(Action::Stay)
+    }
+
+    fn list_points(&self, out: &mut String) {
+        for address in &self.breakpoints {
+            let _ = writeln!(out, "break  {:04X}", address);
+        }
+        for address in self.read_watches.union(&self.write_watches) {
+            let kind = match (self.read_watches.contains(address), self.write_watches.contains(address)) {
+                (true, true) => "awatch",
+                (true, false) => "rwatch",
+                _ => "watch ",
+            };
+            let _ = writeln!(out, "{} {:04X}", kind, address);
+        }
+        for port in self.in_ports.union(&self.out_ports) {
+            let direction = match (self.in_ports.contains(port), self.out_ports.contains(port)) {
+                (true, true) => "in/out",
+                (true, false) => "in",
+                _ => "out",
+            };
+            let _ = writeln!(out, "port   {:02X} {}", port, direction);
+        }
+    }
+
+    // Where execution stopped and the instructions around it
+    pub fn describe_stop<M: MemoryBus, IO: IOHandler>(&self, cpu: &Intel8080<M, IO>, stop: Stop, out: &mut String) {
+        let _ = match stop {
+            Stop::Paused => writeln!(out, "Paused at {:04X}", cpu.PC),
+            Stop::Breakpoint(address) => writeln!(out, "Breakpoint at {:04X}", address),
+            Stop::Watchpoint(Access::Read(address)) => writeln!(out, "Read of {:04X}", address),
+            Stop::Watchpoint(Access::Write(address, value)) => writeln!(out, "Write of {:02X} to {:04X}", value, address),
+            Stop::Watchpoint(Access::Input(port, value)) => writeln!(out, "IN {:02X} read {:02X}", port, value),
+            Stop::Watchpoint(Access::Output(port, value)) => writeln!(out, "OUT {:02X} wrote {:02X}", port, value),
+            Stop::Stepped => Ok(()),
+            Stop::Trap(trap) => writeln!(out, "Trapped on {}", trap),
+        };
+        registers(cpu, out);
+        disassemble(cpu, cpu.PC, 0, 1, out);
+    }
+
+    // Reads commands until one resumes execution or quits
+    #[cfg(feature = "std")]
+    pub fn repl<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, input: &mut impl std::io::BufRead,
+                                             output: &mut impl std::io::Write) -> std::io::Result<Action> {
+        loop {
+            write!(output, "(8080) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                self.quit = true;
+                return Ok(Action::Quit);
+            }
+            let mut text = String::new();
+            let action = self.execute(cpu, &line, &mut text);
+            output.write_all(text.as_bytes())?;
+            if action != Action::Stay {
+                return Ok(action);
+            }
+        }
+    }
+
+    // run_cycles for a frontend: every stop is reported and handed to the REPL, and the whole
+    // budget is used unless the user quits. A trap is returned once the REPL resumes.
+    #[cfg(feature = "std")]
+    pub fn run_cycles_interactive<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, cycles: usize,
+                                                               input: &mut impl std::io::BufRead,
+                                                               output: &mut impl std::io::Write) -> Result<usize, Trap> {
+        let start = cpu.total_ticks;
+        while !self.quit && cpu.total_ticks - start < cycles {
+            let (_, stop) = self.run_cycles(cpu, cycles - (cpu.total_ticks - start));
+            let Some(stop) = stop else {
+                continue;
+            };
+            let mut text = String::new();
+            self.describe_stop(cpu, stop, &mut text);
+            let _ = output.write_all(text.as_bytes());
+            if self.repl(cpu, input, output).is_err() {
+                self.quit = true;
+            }
+            if let Stop::Trap(trap) = stop {
+                return Err(trap);
+            }
+        }
+        Ok(cpu.total_ticks - start)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    parse_address(text).ok_or_else(|| format!("bad hex number {}", text))
+}
+
+fn parse_port(text: &str) -> Result<u8, String> {
+    let value = parse_hex(text)?;
+    u8::try_from(value).map_err(|_| format!("port {} is out of range", text))
+}
+
+fn registers<M: MemoryBus, IO: IOHandler>(cpu: &Intel8080<M, IO>, out: &mut String) {
+    let state = cpu.state();
+    let mut flags = String::new();
+    for (flag, letter) in Flag::ALL.into_iter().zip(['S', 'Z', 'A', 'P', 'C']) {
+        flags.push(if state.flag(flag) { letter } else { '.' });
+    }
+    let _ = writeln!(out, "A={:02X} F={:02X} [{}] BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X} PC={:04X}{}{} cycles={}",
+        state.a, state.f, flags, state.b, state.c, state.d, state.e, state.h, state.l, state.sp, state.pc,
+        if state.inte { " EI" } else { " DI" }, if state.halted { " HALTED" } else { "" }, cpu.total_ticks);
+}
+
+fn dump_memory<M: MemoryBus, IO: IOHandler>(cpu: &Intel8080<M, IO>, start: u16, length: usize, out: &mut String) {
+    for row in (0..length).step_by(16) {
+        let address = start.wrapping_add(row as u16);
+        let bytes: Vec<u8> = (0..16.min(length - row)).map(|offset| cpu.memory.peek(address.wrapping_add(offset as u16))).collect();
+        let _ = write!(out, "{:04X}:", address);
+        for byte in &bytes {
+            let _ = write!(out, " {:02X}", byte);
+        }
+        let text: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        let _ = writeln!(out, "{:width$}  {}", "", text, width = (16 - bytes.len()) * 3);
+    }
+}
+
+// Lists `before` instructions leading up to `address` and `after` more from it, marking PC
+fn disassemble<M: MemoryBus, IO: IOHandler>(cpu: &Intel8080<M, IO>, address: u16, before: usize, after: usize, out: &mut String) {
+    for (position, _, text) in instructions_around(cpu, address, before, after) {
+        let marker = if position == cpu.PC { "=>" } else { "  " };
+        let _ = writeln!(out, "{} {:04X}  {}", marker, position, text);
+    }
+}
+
+// Address, length and text of up to `before` instructions leading up to `address` and of
+// `after` (at least one) starting at it. Fewer come before when no run of code decodes
+// straight onto `address`.
+pub(crate) fn instructions_around<M: MemoryBus, IO: IOHandler>(cpu: &Intel8080<M, IO>, address: u16, before: usize,
+                                                               after: usize) -> Vec<(u16, u16, String)> {
+    let mut memory = Vec::with_capacity(0x10000 + 2);
+    memory.extend((0..=0xFFFF).map(|address| cpu.memory.peek(address)));
+    // Operands of an instruction at FFFF wrap around to 0000
+    memory.extend([memory[0], memory[1]]);
+    let mut disassembler = Disassembler::new();
+    disassembler.load(memory);
+
+    // Code can't be decoded backwards, so try starting further back and take the first start
+    // that decodes straight onto `address`
+    let mut lines = Vec::new();
+    let next = |disassembler: &mut Disassembler| {
+        let position = disassembler.position();
+        let text = disassembler.next_instruction();
+        (position, (disassembler.position() - position) as u16, text)
+    };
+    for back in (1..=before * 3).rev() {
+        let Some(start) = (address as usize).checked_sub(back) else {
+            continue;
+        };
+        disassembler.seek(start);
+        let mut candidate = Vec::new();
+        while disassembler.position() < address as usize {
+            candidate.push(next(&mut disassembler));
+        }
+        if disassembler.position() == address as usize {
+            lines = candidate.split_off(candidate.len().saturating_sub(before));
+            break;
+        }
+    }
+    disassembler.seek(address as usize);
+    for _ in 0..after.max(1) {
+        lines.push(next(&mut disassembler));
+    }
+    lines.into_iter().map(|(position, length, text)| ((position & 0xFFFF) as u16, length, text)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn cpu_with(program: &[u8]) -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.load_program(program.to_vec());
+        cpu
+    }
+
+    fn run(debugger: &mut Debugger, cpu: &mut Intel8080, command: &str) -> Option<Stop> {
+        let mut out = String::new();
+        assert_eq!(debugger.execute(cpu, command, &mut out), Action::Resume, "{}", out);
+        debugger.run_cycles(cpu, 10_000).1
+    }
+
+    // 0000 LXI SP,0100  0003 CALL 0010  0006 STA 2000  0009 OUT 03  000B JMP 000B
+    // 0010 MVI A,42     0012 CALL 0020  0015 RET
+    // 0020 LDA 2000     0023 RET
+    fn program() -> Vec<u8> {
+        let mut program = vec![0; 0x24];
+        program[..0x0E].copy_from_slice(&[0x31, 0x00, 0x01, 0xCD, 0x10, 0x00, 0x32, 0x00, 0x20, 0xD3, 0x03, 0xC3, 0x0B, 0x00]);
+        program[0x10..0x16].copy_from_slice(&[0x3E, 0x42, 0xCD, 0x20, 0x00, 0xC9]);
+        program[0x20..0x24].copy_from_slice(&[0x3A, 0x00, 0x20, 0xC9]);
+        program
+    }
+
+    #[test]
+    fn breakpoints_and_stepping(){
+        let mut cpu = cpu_with(&program());
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        debugger.execute(&mut cpu, "break 12", &mut out);
+        assert_eq!(run(&mut debugger, &mut cpu, "continue"), Some(Stop::Breakpoint(0x12)));
+        assert_eq!(cpu.PC, 0x12);
+
+        // Resuming from a breakpoint runs the instruction under it
+        assert_eq!(run(&mut debugger, &mut cpu, "next"), Some(Stop::Stepped));
+        assert_eq!(cpu.PC, 0x15);
+        assert_eq!(run(&mut debugger, &mut cpu, "step 2"), Some(Stop::Stepped));
+        assert_eq!(cpu.PC, 0x09);
+
+        let mut cpu = cpu_with(&program());
+        assert_eq!(run(&mut debugger, &mut cpu, "until 20"), Some(Stop::Breakpoint(0x12)));
+        assert_eq!(run(&mut debugger, &mut cpu, "until 20"), Some(Stop::Stepped));
+        assert_eq!(cpu.PC, 0x20);
+        // Steps out of the inner call into the middle of the outer one
+        assert_eq!(run(&mut debugger, &mut cpu, "finish"), Some(Stop::Stepped));
+        assert_eq!(cpu.PC, 0x15);
+        assert_eq!(run(&mut debugger, &mut cpu, "finish"), Some(Stop::Stepped));
+        assert_eq!(cpu.PC, 0x06);
+    }
+
+    #[test]
+    fn counts_cycles_up_to_a_trap(){
+        // NOP, NOP, then the undocumented 0x08
+        let mut cpu = cpu_with(&[0x00, 0x00, 0x08]);
+        cpu.trap_policy = TrapPolicy::Stop;
+        let mut debugger = Debugger::new();
+        let (ran, stop) = debugger.run_cycles(&mut cpu, 10_000);
+        assert!(matches!(stop, Some(Stop::Trap(_))));
+        assert_eq!(ran, 8);
+        assert_eq!(cpu.PC, 2);
+    }
+
+    #[test]
+    fn tracks_calls(){
+        let mut cpu = cpu_with(&program());
+        let mut debugger = Debugger::new();
+        debugger.track_calls = true;
+        assert_eq!(run(&mut debugger, &mut cpu, "until 20"), Some(Stop::Stepped));
+        assert_eq!(debugger.calls, [
+            Call { entry: 0x10, return_to: 0x06, sp: 0xFE },
+            Call { entry: 0x20, return_to: 0x15, sp: 0xFC },
+        ]);
+        run(&mut debugger, &mut cpu, "finish");
+        assert_eq!(debugger.calls.len(), 1);
+        run(&mut debugger, &mut cpu, "finish");
+        assert!(debugger.calls.is_empty());
+    }
+
+    #[test]
+    fn watchpoints(){
+        let mut cpu = cpu_with(&program());
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        debugger.execute(&mut cpu, "rwatch 0x2000", &mut out);
+        debugger.execute(&mut cpu, "watch $2000", &mut out);
+        debugger.execute(&mut cpu, "port 3 out", &mut out);
+        assert_eq!(run(&mut debugger, &mut cpu, "c"), Some(Stop::Watchpoint(Access::Read(0x2000))));
+        assert_eq!(cpu.PC, 0x23);
+        assert_eq!(run(&mut debugger, &mut cpu, "c"), Some(Stop::Watchpoint(Access::Write(0x2000, 0))));
+        assert_eq!(run(&mut debugger, &mut cpu, "c"), Some(Stop::Watchpoint(Access::Output(3, 0))));
+
+        out.clear();
+        debugger.execute(&mut cpu, "info", &mut out);
+        assert_eq!(out, "awatch 2000\nport   03 out\n");
+        debugger.execute(&mut cpu, "delete", &mut out);
+        assert!(debugger.idle());
+        assert_eq!(debugger.run_cycles(&mut cpu, 100), (100, None));
+    }
+
+    #[test]
+    fn inspecting_state(){
+        let mut cpu = cpu_with(&program());
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        assert_eq!(debugger.run_cycles(&mut cpu, 100), (0, Some(Stop::Paused)));
+
+        let mut out = String::new();
+        debugger.execute(&mut cpu, "x 0 20", &mut out);
+        assert_eq!(out.lines().next(), Some("0000: 31 00 01 CD 10 00 32 00 20 D3 03 C3 0B 00 00 00  1.....2. ......."));
+        assert_eq!(out.lines().nth(1), Some("0010: 3E 42 CD 20                                      >B. "));
+
+        run(&mut debugger, &mut cpu, "until 9");
+        out.clear();
+        debugger.execute(&mut cpu, "list", &mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..3], ["   0000  LXI SP $01 00", "   0003  CALL $00 10", "   0006  STA $20 00"]);
+        assert!(lines[3].starts_with("=> 0009  OUT"));
+
+        out.clear();
+        debugger.execute(&mut cpu, "regs", &mut out);
+        assert!(out.starts_with("A=00 F=02 [....."), "{}", out);
+        assert!(out.contains("SP=0100 PC=0009"));
+
+        // An empty line repeats the last command
+        out.clear();
+        debugger.execute(&mut cpu, "", &mut out);
+        assert!(out.contains("PC=0009"));
+        debugger.execute(&mut cpu, "frobnicate", &mut out);
+        assert!(out.ends_with("unknown command frobnicate, try help\n"));
+        assert_eq!(debugger.execute(&mut cpu, "q", &mut out), Action::Quit);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn interactive_runs_use_the_whole_budget(){
+        let mut cpu = cpu_with(&program());
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.insert(0x20);
+        let mut input = "regs\n\nnext\ncontinue\n".as_bytes();
+        let mut output = Vec::new();
+        // Like run_cycles, the last instruction can overshoot the budget
+        assert!(debugger.run_cycles_interactive(&mut cpu, 1000, &mut input, &mut output).unwrap() >= 1000);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Breakpoint at 0020\n"), "{}", output);
+        assert_eq!(output.matches("PC=0020").count(), 3);
+        assert!(output.contains("PC=0023"));
+
+        // Running out of input quits
+        let mut cpu = cpu_with(&program());
+        debugger.run_cycles_interactive(&mut cpu, 1000, &mut "".as_bytes(), &mut Vec::new()).unwrap();
+        assert!(debugger.quit);
+        assert_eq!(cpu.PC, 0x20);
+    }
+}
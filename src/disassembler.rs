@@ -1,4 +1,7 @@
-use std::io::Write;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
 pub struct Disassembler {
     buffer: Vec<u8>,
@@ -15,6 +18,21 @@ impl Disassembler {
         self.buffer = data;
     }
 
+    // Moves to `index` in the loaded code
+    pub fn seek(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    // Byte `offset` bytes past the current position, zero past the end of the code
+    fn byte(&self, offset: usize) -> u8 {
+        self.buffer.get(self.index + offset).copied().unwrap_or(0)
+    }
+
+    #[cfg(feature = "std")]
     pub fn dump_all(&mut self){
         while self.index < self.buffer.len(){
             self.dump();
@@ -22,7 +40,7 @@ impl Disassembler {
     }
 
     fn create_jump_table(&mut self){
-        let opcode = self.buffer.get(self.index).unwrap();
+        let opcode = self.byte(0);
         self.index = self.index.wrapping_add(1);
         let mut opcode_arr:[u8;8] = [0;8];
         for n in 0..8 {
@@ -30,10 +48,15 @@ impl Disassembler {
         }
     }
 
+    #[cfg(feature = "std")]
     pub fn dump(&mut self) {
-        print!("{:x} ", self.index);
+        print!("{:04x} ", self.index);
+        println!("{}", self.next_instruction());
+    }
 
-        let opcode = self.buffer.get(self.index).unwrap();
+    // Disassembles the instruction at the current position and moves past it
+    pub fn next_instruction(&mut self) -> String {
+        let opcode = self.byte(0);
         self.index = self.index.wrapping_add(1);
         let mut opcode_arr:[u8;8] = [0;8];
         for n in 0..8 {
@@ -47,70 +70,70 @@ impl Disassembler {
         let n = ddd;
 
         let bcde:[&str;2] = ["BC","DE"];
-        let bdhsp:[&str;4] = ["BD","DE","HL","SP"];
-        let bdhpsw:[&str;4] = ["BD","DE","HL","PSW"];
+        let bdhsp:[&str;4] = ["BC","DE","HL","SP"];
+        let bdhpsw:[&str;4] = ["BC","DE","HL","PSW"];
         let bcdehlma:[&str;8] = ["B","C","D","E","H","L","M","A"];
         let aluop1:[&str;8]=["ADD","ADC","SUB","SBB","ANA","XRA","ORA","CMP"];
         let aluop2:[&str;8]=["ADI","ACI","SUI","SBI","ANI","XRI","ORI","CPI"];
-        let condition:[&str;8]=["NZ", "Z", "NC", "C", "PO", "PE", "P", "N"];
+        let condition:[&str;8]=["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
 
         // println!("{:.unwrap()}", opcode_arr);
-        std::io::stdout().flush().unwrap();
-
 
+        let text;
         match opcode_arr {
-            [0,0,_,_,0,0,0,0] => println!("NOP"),
-            [0,0,_,_,1,0,0,0] => println!("NOP"), // alternative
-            [0,0,0,0,0,1,1,1]=>println!("RLC"),
-            [0,0,0,0,1,1,1,1]=>println!("RRC"),
-            [0,0,0,1,0,1,1,1]=>println!("RAL"),
-            [0,0,0,1,1,1,1,1]=>println!("RAR"),
-            [0,0,1,0,0,0,1,0]=>{println!("SHLD ${:02x} {:02x}", self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [0,0,1,0,0,1,1,1]=>println!("DAA"),
-            [0,0,1,0,1,0,1,0]=>{println!("LHLD ${:02x} {:02x}", self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [0,0,1,0,1,1,1,1]=>println!("CMA"),
-            [0,0,1,1,0,0,1,0]=>{println!("STA ${:02x} {:02x}", self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [0,0,1,1,0,1,1,1]=>println!("STC"),
-            [0,0,1,1,1,0,1,0]=>{println!("LDA ${:02x} {:02x}", self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [0,0,1,1,1,1,1,1]=>println!("CMC"),
-            [0,1,1,1,0,1,1,0]=>println!("HLT"),
-            [1,1,0,0,0,0,1,1]=>{println!("JMP ${:02x} {:02x}",  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [1,1,0,0,1,0,1,1]=>{println!("JMP ${:02x} {:02x}",  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);}, // alternative
-            [1,1,0,0,1,0,0,1]=>{println!("RET")},
-            [1,1,0,1,1,0,0,1]=>{println!("RET")}, // alternative
-            [1,1,0,0,1,1,0,1]=>{println!("CALL ${:02x} {:02x}",  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [1,1,0,1,1,1,0,1]=>{println!("CALL ${:02x} {:02x}",  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);}, // alternative
-            [1,1,1,0,1,1,0,1]=>{println!("CALL ${:02x} {:02x}",  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);}, // alternative
-            [1,1,1,1,1,1,0,1]=>{println!("CALL ${:02x} {:02x}",  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},// alternative
-            [1,1,0,1,0,0,1,1]=>{println!("OUT {}",self.buffer.get(self.index).unwrap());self.index = self.index.wrapping_add(1)},
-            [1,1,0,1,1,0,1,1]=>{println!("IN {}",self.buffer.get(self.index).unwrap());self.index = self.index.wrapping_add(1)},
-            [1,1,1,0,0,0,1,1]=>{println!("XTHL")},
-            [1,1,1,0,1,0,0,1]=>{println!("PCHL")},
-            [1,1,1,0,1,0,1,1]=>{println!("XCHG")},
-            [1,1,1,1,0,0,1,1]=>{println!("DI")},
-            [1,1,1,1,1,0,0,1]=>{println!("SPHL")},
-            [1,1,1,1,1,0,1,1]=>{println!("EI")},
-            [0,0,r1,r0,0,0,0,1] => {println!("LXI {} ${:02x} {:02x}", bdhsp[rp], self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [0,0,r1,r0,0,0,1,0]=>{println!("STAX {}",bcde[rp])},
-            [0,0,r1,r0,0,0,1,1]=>{println!("INX {}", bdhsp[rp])},
-            [0,0,d2,d1,d0,1,0,0]=>{println!("INR {}",bcdehlma[ddd])},
-            [0,0,d2,d1,d0,1,0,1]=>{println!("DCR {}",bcdehlma[ddd])},
-            [0,0,d2,d1,d0,1,1,0]=>{println!("MVI {} {}",bcdehlma[ddd],self.buffer.get(self.index+1).unwrap()); self.index = self.index.wrapping_add(1);},
-            [0,0,r1,r0,1,0,0,1]=>{println!("DAD {}", bdhsp[rp])},
-            [0,0,r1,r0,1,0,1,0]=>{println!("LDAX {}", bdhsp[rp])},
-            [0,0,r1,r0,1,0,1,1]=>{println!("DCX {}", bdhsp[rp])},
-            [0,1,d2,d1,d0,s2,s1,s0]=>println!("MOV {},{}",bcdehlma[ddd],bcdehlma[sss]),
-            [1,0,alu2,alu1,alu0,s2,s1,s0]=>{println!("ALUOP1 {} {}", aluop1[alu], bcdehlma[sss])},
-            [1,1,c2,c1,c0,0,0,0]=>{println!("RCC {}", bcdehlma[sss])},
-            [1,1,r1,r0,0,0,0,1]=>{println!("POP {}", bdhpsw[rp])},
-            [1,1,c2,c1,c0,0,1,0]=>{println!("JCC {} ${:02x} {:02x}",condition[cc],  self.buffer.get(self.index+1).unwrap(), self.buffer.get(self.index).unwrap()); self.index = self.index.wrapping_add(2);},
-            [1,1,c2,c1,c0,1,0,0]=>{println!("CCC {}", condition[cc])},
-            [1,1,r1,r0,0,1,0,1]=>{println!("PUSH {}", bdhpsw[rp])},
-            [1,1,alu2,alu1,alu0,1,1,0]=>{println!("ALUOP2 {} {}", aluop2[alu], bcdehlma[sss])},
-            [1,1,n2,n1,n0,1,1,1]=>{println!("RST {}", n)},
+            [0,0,_,_,0,0,0,0] => text = format!("NOP"),
+            [0,0,_,_,1,0,0,0] => text = format!("NOP"), // alternative
+            [0,0,0,0,0,1,1,1]=>text = format!("RLC"),
+            [0,0,0,0,1,1,1,1]=>text = format!("RRC"),
+            [0,0,0,1,0,1,1,1]=>text = format!("RAL"),
+            [0,0,0,1,1,1,1,1]=>text = format!("RAR"),
+            [0,0,1,0,0,0,1,0]=>{text = format!("SHLD ${:02x} {:02x}", self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [0,0,1,0,0,1,1,1]=>text = format!("DAA"),
+            [0,0,1,0,1,0,1,0]=>{text = format!("LHLD ${:02x} {:02x}", self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [0,0,1,0,1,1,1,1]=>text = format!("CMA"),
+            [0,0,1,1,0,0,1,0]=>{text = format!("STA ${:02x} {:02x}", self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [0,0,1,1,0,1,1,1]=>text = format!("STC"),
+            [0,0,1,1,1,0,1,0]=>{text = format!("LDA ${:02x} {:02x}", self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [0,0,1,1,1,1,1,1]=>text = format!("CMC"),
+            [0,1,1,1,0,1,1,0]=>text = format!("HLT"),
+            [1,1,0,0,0,0,1,1]=>{text = format!("JMP ${:02x} {:02x}",  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [1,1,0,0,1,0,1,1]=>{text = format!("JMP ${:02x} {:02x}",  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);}, // alternative
+            [1,1,0,0,1,0,0,1]=>{text = format!("RET")},
+            [1,1,0,1,1,0,0,1]=>{text = format!("RET")}, // alternative
+            [1,1,0,0,1,1,0,1]=>{text = format!("CALL ${:02x} {:02x}",  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [1,1,0,1,1,1,0,1]=>{text = format!("CALL ${:02x} {:02x}",  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);}, // alternative
+            [1,1,1,0,1,1,0,1]=>{text = format!("CALL ${:02x} {:02x}",  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);}, // alternative
+            [1,1,1,1,1,1,0,1]=>{text = format!("CALL ${:02x} {:02x}",  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},// alternative
+            [1,1,0,1,0,0,1,1]=>{text = format!("OUT {}",self.byte(0));self.index = self.index.wrapping_add(1)},
+            [1,1,0,1,1,0,1,1]=>{text = format!("IN {}",self.byte(0));self.index = self.index.wrapping_add(1)},
+            [1,1,1,0,0,0,1,1]=>{text = format!("XTHL")},
+            [1,1,1,0,1,0,0,1]=>{text = format!("PCHL")},
+            [1,1,1,0,1,0,1,1]=>{text = format!("XCHG")},
+            [1,1,1,1,0,0,1,1]=>{text = format!("DI")},
+            [1,1,1,1,1,0,0,1]=>{text = format!("SPHL")},
+            [1,1,1,1,1,0,1,1]=>{text = format!("EI")},
+            [0,0,r1,r0,0,0,0,1] => {text = format!("LXI {} ${:02x} {:02x}", bdhsp[rp], self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [0,0,r1,r0,0,0,1,0]=>{text = format!("STAX {}",bcde[rp])},
+            [0,0,r1,r0,0,0,1,1]=>{text = format!("INX {}", bdhsp[rp])},
+            [0,0,d2,d1,d0,1,0,0]=>{text = format!("INR {}",bcdehlma[ddd])},
+            [0,0,d2,d1,d0,1,0,1]=>{text = format!("DCR {}",bcdehlma[ddd])},
+            [0,0,d2,d1,d0,1,1,0]=>{text = format!("MVI {} {}",bcdehlma[ddd],self.byte(0)); self.index = self.index.wrapping_add(1);},
+            [0,0,r1,r0,1,0,0,1]=>{text = format!("DAD {}", bdhsp[rp])},
+            [0,0,r1,r0,1,0,1,0]=>{text = format!("LDAX {}", bdhsp[rp])},
+            [0,0,r1,r0,1,0,1,1]=>{text = format!("DCX {}", bdhsp[rp])},
+            [0,1,d2,d1,d0,s2,s1,s0]=>text = format!("MOV {},{}",bcdehlma[ddd],bcdehlma[sss]),
+            [1,0,alu2,alu1,alu0,s2,s1,s0]=>{text = format!("ALUOP1 {} {}", aluop1[alu], bcdehlma[sss])},
+            [1,1,c2,c1,c0,0,0,0]=>{text = format!("RCC {}", condition[cc])},
+            [1,1,r1,r0,0,0,0,1]=>{text = format!("POP {}", bdhpsw[rp])},
+            [1,1,c2,c1,c0,0,1,0]=>{text = format!("JCC {} ${:02x} {:02x}",condition[cc],  self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [1,1,c2,c1,c0,1,0,0]=>{text = format!("CCC {} ${:02x} {:02x}", condition[cc], self.byte(1), self.byte(0)); self.index = self.index.wrapping_add(2);},
+            [1,1,r1,r0,0,1,0,1]=>{text = format!("PUSH {}", bdhpsw[rp])},
+            [1,1,alu2,alu1,alu0,1,1,0]=>{text = format!("ALUOP2 {} {}", aluop2[alu], self.byte(0)); self.index = self.index.wrapping_add(1);},
+            [1,1,n2,n1,n0,1,1,1]=>{text = format!("RST {}", n)},
             _ => {
-                println!("invalid opcode: {:#b} {:#x} ", opcode, opcode);
+                text = format!("invalid opcode: {:#b} {:#x} ", opcode, opcode);
             }
         }
+        text
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,125 @@
+// Flag register that remembers the last ALU operation instead of working out its flags
+// straight away. Most results are overwritten by the next ALU op before anything reads
+// them, so S/Z/AC/P/CY are only computed for conditional jumps, PUSH PSW, DAA and the like.
+
+pub(crate) const SIGN: u8 = 0x80;
+pub(crate) const ZERO: u8 = 0x40;
+pub(crate) const AUX_CARRY: u8 = 0x10;
+pub(crate) const PARITY: u8 = 0x04;
+pub(crate) const CARRY: u8 = 0x01;
+pub(crate) const SZAP: u8 = SIGN | ZERO | AUX_CARRY | PARITY;
+pub(crate) const SZAPC: u8 = SZAP | CARRY;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Pending {
+    // i1 + i2 + i3, with AC and CY from the same sum
+    Add(u8, u8, u8),
+    // ANA/XRA/ORA: CY is clear and AC was decided by the instruction
+    Logic { result: u8, aux_carry: bool },
+}
+
+impl Pending {
+    // Works out just the flags in `mask`
+    fn flags(self, mask: u8) -> u8 {
+        let (result, aux_carry, carry) = match self {
+            Pending::Add(i1, i2, i3) => {
+                let sum = i1 as u16 + i2 as u16 + i3 as u16;
+                let nibbles = (i1 & 0x0F) + (i2 & 0x0F) + (i3 & 0x0F);
+                (sum as u8, nibbles & 0x10 != 0, sum > 0xFF)
+            }
+            Pending::Logic { result, aux_carry } => (result, aux_carry, false),
+        };
+        let mut flags = result & SIGN;
+        if result == 0 {
+            flags |= ZERO;
+        }
+        if aux_carry {
+            flags |= AUX_CARRY;
+        }
+        if mask & PARITY != 0 && result.count_ones() % 2 == 0 {
+            flags |= PARITY;
+        }
+        if carry {
+            flags |= CARRY;
+        }
+        flags & mask
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LazyFlags {
+    // Current except for the bits in `mask`
+    bits: u8,
+    pending: Pending,
+    // Flags that still have to come from `pending`
+    mask: u8,
+}
+
+impl LazyFlags {
+    pub fn new(bits: u8) -> LazyFlags {
+        LazyFlags { bits, pending: Pending::Logic { result: 0, aux_carry: false }, mask: 0 }
+    }
+
+    pub fn get(&self) -> u8 {
+        self.bits & !self.mask | self.pending.flags(self.mask)
+    }
+
+    pub fn set(&mut self, bits: u8) {
+        self.bits = bits;
+        self.mask = 0;
+    }
+
+    // True if any flag in `mask` is set
+    pub fn test(&self, mask: u8) -> bool {
+        let pending = self.mask & mask;
+        (self.bits & mask & !pending | self.pending.flags(pending)) != 0
+    }
+
+    pub fn write(&mut self, mask: u8, value: bool) {
+        self.resolve(mask);
+        if value {
+            self.bits |= mask;
+        } else {
+            self.bits &= !mask;
+        }
+    }
+
+    // Replaces the flags in `mask` with ones worked out from `pending` when they are read
+    pub fn record(&mut self, pending: Pending, mask: u8) {
+        self.resolve(self.mask & !mask);
+        self.pending = pending;
+        self.mask = mask;
+    }
+
+    // Settles the pending flags in `mask` into `bits`
+    fn resolve(&mut self, mask: u8) {
+        let stale = self.mask & mask;
+        if stale != 0 {
+            self.bits = self.bits & !stale | self.pending.flags(stale);
+            self.mask &= !stale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_keeps_flags_it_does_not_cover(){
+        let mut flags = LazyFlags::new(0b00000010);
+        // 0xFF + 0x01 carries, then an INR-style update leaves CY alone
+        flags.record(Pending::Add(0xFF, 0x01, 0), SZAPC);
+        flags.record(Pending::Add(0x42, 0x01, 0), SZAP);
+        assert_eq!(flags.get(), 0b00000011);
+        assert!(flags.test(CARRY));
+        assert!(!flags.test(ZERO | SIGN));
+
+        flags.write(CARRY, false);
+        assert_eq!(flags.get(), 0b00000010);
+        flags.record(Pending::Logic { result: 0, aux_carry: true }, SZAPC);
+        assert_eq!(flags.get(), 0b01010110);
+        flags.set(0b10000011);
+        assert_eq!(flags.get(), 0b10000011);
+    }
+}
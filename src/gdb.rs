@@ -0,0 +1,519 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::debugger::{Debugger, Stop};
+use crate::intel8080::{Access, IOHandler, Intel8080, Trap};
+use crate::memory::MemoryBus;
+
+// The register file as GDB sees it: A F B C D E H L, then SP and PC, in that order in g/G
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.intel8080.cpu">
+    <flags id="i8080_flags" size="1">
+      <field name="C" start="0" end="0"/>
+      <field name="P" start="2" end="2"/>
+      <field name="AC" start="4" end="4"/>
+      <field name="Z" start="6" end="6"/>
+      <field name="S" start="7" end="7"/>
+    </flags>
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="i8080_flags"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Checked for a Ctrl-C from the client this often while the CPU runs
+const POLL_CYCLES: usize = 10_000;
+const MAX_PACKET: usize = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// What a packet asks the stub to do next
+#[derive(Clone, PartialEq, Debug)]
+pub enum Reply {
+    Packet(String),
+    // c or s, the reply is sent when the CPU stops
+    Resume,
+    // D or k, the session is over
+    Detach,
+    Kill,
+}
+
+// A GDB remote serial protocol server for one client. The CPU is driven through a Debugger,
+// so Z0/Z1 breakpoints don't patch memory and work on ROM, and Z2-Z4 watchpoints come free.
+// Like the debugger REPL it runs in a frontend's cycle slices: run_cycles serves packets
+// while the target is stopped and watches for Ctrl-C while it runs.
+pub struct GdbStub {
+    pub debugger: Debugger,
+    stream: TcpStream,
+    // Bytes received but not yet handled
+    input: Vec<u8>,
+    no_ack: bool,
+    stopped: bool,
+    // Set when the client detached or the connection dropped, the CPU then runs freely
+    pub closed: bool,
+    // Set by k, the frontend should shut down
+    pub killed: bool,
+}
+
+impl GdbStub {
+    // Waits for a client on localhost. The target starts stopped, as GDB expects.
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for GDB on port {}", port);
+        let (stream, address) = listener.accept()?;
+        eprintln!("GDB connected from {}", address);
+        Ok(GdbStub::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> GdbStub {
+        let _ = stream.set_nodelay(true);
+        GdbStub { debugger: Debugger::new(), stream, input: Vec::new(), no_ack: false, stopped: true, closed: false, killed: false }
+    }
+
+    // Same contract as Intel8080::run_cycles, with the client in control while the target
+    // is stopped. Returns early only when the session ends with k.
+    pub fn run_cycles<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, cycles: usize) -> Result<usize, Trap> {
+        let start = cpu.total_ticks;
+        while !self.killed && cpu.total_ticks - start < cycles {
+            if self.closed {
+                cpu.run_cycles(cycles - (cpu.total_ticks - start))?;
+                break;
+            }
+            if let Err(error) = self.serve(cpu, start + cycles) {
+                eprintln!("GDB connection lost: {}", error);
+                self.close();
+            }
+        }
+        Ok(cpu.total_ticks - start)
+    }
+
+    // Handles packets until the target resumes, then runs it until it stops or total_ticks
+    // reaches `until`
+    fn serve<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, until: usize) -> io::Result<()> {
+        while self.stopped && !self.closed {
+            let packet = self.read_packet()?;
+            match handle_packet(&mut self.debugger, cpu, &packet) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Resume => self.stopped = false,
+                Reply::Detach => {
+                    self.send("OK")?;
+                    self.close();
+                }
+                Reply::Kill => {
+                    self.killed = true;
+                    self.close();
+                }
+            }
+        }
+        while !self.stopped && !self.closed && cpu.total_ticks < until {
+            if self.interrupted()? {
+                self.stopped = true;
+                self.send(&format!("S{:02x}", SIGINT))?;
+                break;
+            }
+            let budget = (until - cpu.total_ticks).min(POLL_CYCLES);
+            if let (_, Some(stop)) = self.debugger.run_cycles(cpu, budget) {
+                self.stopped = true;
+                self.send(&stop_reply(&self.debugger, stop))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.debugger = Debugger::new();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    // Checks for a Ctrl-C without blocking. Anything else that arrives is kept for later.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0; 256];
+        let read = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+        match self.input.iter().position(|&byte| byte == 0x03) {
+            Some(index) => {
+                self.input.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if self.input.is_empty() {
+            let mut buffer = [0; 4096];
+            let count = self.stream.read(&mut buffer)?;
+            if count == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.input.extend_from_slice(&buffer[..count]);
+        }
+        Ok(self.input.remove(0))
+    }
+
+    // Reads the next $packet#xx, acknowledging it. Acks from the client and Ctrl-Cs sent
+    // while the target is already stopped are skipped.
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            if self.read_byte()? != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte if data.len() < MAX_PACKET => data.push(byte),
+                    _ => return Err(io::Error::new(ErrorKind::InvalidData, "packet too long")),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+            let valid = expected == Some(checksum_of(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if !valid {
+                continue;
+            }
+            let packet = String::from_utf8_lossy(&data).into_owned();
+            if packet == "QStartNoAckMode" {
+                self.send("OK")?;
+                self.no_ack = true;
+                continue;
+            }
+            return Ok(packet);
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                // Anything else means the client has moved on without acking
+                byte => {
+                    self.input.insert(0, byte);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn stop_reply(debugger: &Debugger, stop: Stop) -> String {
+    match stop {
+        Stop::Watchpoint(Access::Write(address, _)) => format!("T{:02x}watch:{:04x};", SIGTRAP, address),
+        Stop::Watchpoint(Access::Read(address)) if debugger.write_watches.contains(&address) => {
+            format!("T{:02x}awatch:{:04x};", SIGTRAP, address)
+        }
+        Stop::Watchpoint(Access::Read(address)) => format!("T{:02x}rwatch:{:04x};", SIGTRAP, address),
+        Stop::Trap(_) => format!("S{:02x}", SIGILL),
+        Stop::Paused => format!("S{:02x}", SIGINT),
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+fn number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// "addr,length" as used by m, M and Z
+fn address_and_length(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((number(address)? as u16, number(length)?))
+}
+
+fn registers<M: MemoryBus, IO: IOHandler>(cpu: &Intel8080<M, IO>) -> [u8; 12] {
+    let state = cpu.state();
+    let [sp_lo, sp_hi] = state.sp.to_le_bytes();
+    let [pc_lo, pc_hi] = state.pc.to_le_bytes();
+    [state.a, state.f, state.b, state.c, state.d, state.e, state.h, state.l, sp_lo, sp_hi, pc_lo, pc_hi]
+}
+
+fn set_registers<M: MemoryBus, IO: IOHandler>(cpu: &mut Intel8080<M, IO>, bytes: &[u8; 12]) {
+    let mut state = cpu.state();
+    [state.a, state.f, state.b, state.c, state.d, state.e, state.h, state.l] = bytes[..8].try_into().unwrap();
+    state.sp = u16::from_le_bytes([bytes[8], bytes[9]]);
+    state.pc = u16::from_le_bytes([bytes[10], bytes[11]]);
+    cpu.set_state(&state);
+}
+
+// Register number to its offset and width in the g packet
+fn register_bytes(number: usize) -> Option<(usize, usize)> {
+    match number {
+        0..=7 => Some((number, 1)),
+        8 | 9 => Some((8 + (number - 8) * 2, 2)),
+        _ => None,
+    }
+}
+
+// Answers one packet. An empty reply tells GDB the packet isn't supported, "E01" that it failed.
+pub fn handle_packet<M: MemoryBus, IO: IOHandler>(debugger: &mut Debugger, cpu: &mut Intel8080<M, IO>, packet: &str) -> Reply {
+    let reply = |text: &str| Reply::Packet(String::from(text));
+    let error = || reply("E01");
+    let Some(command) = packet.chars().next() else {
+        return reply("");
+    };
+    let args = &packet[command.len_utf8()..];
+    match command {
+        '?' => Reply::Packet(format!("S{:02x}", SIGTRAP)),
+        'g' => Reply::Packet(hex(&registers(cpu))),
+        'G' => match unhex(args).and_then(|bytes| <[u8; 12]>::try_from(bytes).ok()) {
+            Some(bytes) => {
+                set_registers(cpu, &bytes);
+                reply("OK")
+            }
+            None => error(),
+        },
+        'p' => match number(args).and_then(register_bytes) {
+            Some((offset, width)) => Reply::Packet(hex(&registers(cpu)[offset..offset + width])),
+            None => error(),
+        },
+        'P' => {
+            let parsed = args.split_once('=').and_then(|(register, value)| Some((register_bytes(number(register)?)?, unhex(value)?)));
+            match parsed {
+                Some(((offset, width), value)) if value.len() == width => {
+                    let mut bytes = registers(cpu);
+                    bytes[offset..offset + width].copy_from_slice(&value);
+                    set_registers(cpu, &bytes);
+                    reply("OK")
+                }
+                _ => error(),
+            }
+        }
+        'm' => match address_and_length(args) {
+            Some((address, length)) if length <= MAX_PACKET / 2 => {
+                let bytes: Vec<u8> = (0..length).map(|offset| cpu.memory.peek(address.wrapping_add(offset as u16))).collect();
+                Reply::Packet(hex(&bytes))
+            }
+            _ => error(),
+        },
+        'M' => {
+            let parsed = args.split_once(':').and_then(|(range, data)| Some((address_and_length(range)?, unhex(data)?)));
+            match parsed {
+                Some(((address, length), data)) if data.len() == length => {
+                    for (offset, &byte) in data.iter().enumerate() {
+                        cpu.memory.poke(address.wrapping_add(offset as u16), byte);
+                    }
+                    cpu.flush_block_cache();
+                    reply("OK")
+                }
+                _ => error(),
+            }
+        }
+        // Resuming at another address isn't supported, GDB sets PC with P first anyway
+        'c' if args.is_empty() => {
+            debugger.resume(cpu);
+            Reply::Resume
+        }
+        's' if args.is_empty() => {
+            debugger.single_step(cpu);
+            Reply::Resume
+        }
+        'Z' | 'z' => {
+            let insert = command == 'Z';
+            let mut fields = args.splitn(2, ',');
+            let (Some(kind), Some((address, length))) = (fields.next(), fields.next().and_then(address_and_length)) else {
+                return error();
+            };
+            let addresses = (0..length.max(1)).map(|offset| address.wrapping_add(offset as u16));
+            let sets = match kind {
+                "0" | "1" => vec![&mut debugger.breakpoints],
+                "2" => vec![&mut debugger.write_watches],
+                "3" => vec![&mut debugger.read_watches],
+                "4" => vec![&mut debugger.read_watches, &mut debugger.write_watches],
+                _ => return reply(""),
+            };
+            // A breakpoint only covers its first byte whatever the kind says
+            let addresses: Vec<u16> = if kind == "0" || kind == "1" { vec![address] } else { addresses.collect() };
+            for set in sets {
+                for &address in &addresses {
+                    if insert {
+                        set.insert(address);
+                    } else {
+                        set.remove(&address);
+                    }
+                }
+            }
+            reply("OK")
+        }
+        'D' => Reply::Detach,
+        'k' => Reply::Kill,
+        'H' => reply("OK"),
+        'T' => reply("OK"),
+        'q' | 'Q' => query(packet),
+        _ => reply(""),
+    }
+}
+
+fn query(packet: &str) -> Reply {
+    let text = if packet.starts_with("qSupported") {
+        format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", MAX_PACKET)
+    } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        let Some((offset, length)) = range.split_once(',').and_then(|(offset, length)| Some((number(offset)?, number(length)?))) else {
+            return Reply::Packet(String::from("E01"));
+        };
+        let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+        if rest.len() > length {
+            format!("m{}", &rest[..length])
+        } else {
+            format!("l{}", rest)
+        }
+    } else {
+        let text = match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        };
+        String::from(text)
+    };
+    Reply::Packet(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(debugger: &mut Debugger, cpu: &mut Intel8080, text: &str) -> String {
+        match handle_packet(debugger, cpu, text) {
+            Reply::Packet(reply) => reply,
+            other => panic!("{} got {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn registers_and_memory(){
+        let mut cpu = Intel8080::new();
+        let mut debugger = Debugger::new();
+        assert_eq!(packet(&mut debugger, &mut cpu, "g"), "000200000000000000000000");
+        assert_eq!(packet(&mut debugger, &mut cpu, "G11d7223344556677fffe3412"), "OK");
+        let state = cpu.state();
+        assert_eq!((state.a, state.f, state.l, state.sp, state.pc), (0x11, 0xd7, 0x77, 0xfeff, 0x1234));
+        assert_eq!(packet(&mut debugger, &mut cpu, "P9=0001"), "OK");
+        assert_eq!(cpu.PC, 0x100);
+        assert_eq!(packet(&mut debugger, &mut cpu, "p8"), "fffe");
+        assert_eq!(packet(&mut debugger, &mut cpu, "pa"), "E01");
+
+        assert_eq!(packet(&mut debugger, &mut cpu, "M100,3:3e42ff"), "OK");
+        assert_eq!(packet(&mut debugger, &mut cpu, "mff,4"), "003e42ff");
+        assert_eq!(packet(&mut debugger, &mut cpu, "M100,2:3e"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_queries(){
+        let mut cpu = Intel8080::new();
+        let mut debugger = Debugger::new();
+        assert_eq!(packet(&mut debugger, &mut cpu, "Z0,10,1"), "OK");
+        assert_eq!(packet(&mut debugger, &mut cpu, "Z4,2000,2"), "OK");
+        assert!(debugger.breakpoints.contains(&0x10));
+        assert_eq!(debugger.read_watches.len(), 2);
+        assert_eq!(debugger.write_watches.len(), 2);
+        assert_eq!(packet(&mut debugger, &mut cpu, "z4,2000,2"), "OK");
+        assert!(debugger.read_watches.is_empty());
+        assert_eq!(packet(&mut debugger, &mut cpu, "Z9,0,1"), "");
+
+        assert_eq!(stop_reply(&debugger, Stop::Watchpoint(Access::Write(0x2000, 1))), "T05watch:2000;");
+        assert_eq!(packet(&mut debugger, &mut cpu, "vCont?"), "");
+        assert!(packet(&mut debugger, &mut cpu, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let start = packet(&mut debugger, &mut cpu, "qXfer:features:read:target.xml:0,20");
+        assert_eq!(start, "m<?xml version=\"1.0\"?>\n<!DOCTYPE ");
+        let end = packet(&mut debugger, &mut cpu, &format!("qXfer:features:read:target.xml:{:x},100", TARGET_XML.len() - 10));
+        assert_eq!(end, "l</target>\n");
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) {
+        write!(stream, "${}#{:02x}", data, checksum_of(data.as_bytes())).unwrap();
+    }
+
+    // Reads up to the end of the next packet, returning its data
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut text = Vec::new();
+        let mut byte = [0];
+        while !text.ends_with(b"#") {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] != b'+' || !text.is_empty() {
+                text.push(byte[0]);
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(text[1..text.len() - 1].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn session_over_tcp(){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            send(&mut stream, "?");
+            assert_eq!(receive(&mut stream), "S05");
+            send(&mut stream, "Z0,4,1");
+            assert_eq!(receive(&mut stream), "OK");
+            send(&mut stream, "c");
+            assert_eq!(receive(&mut stream), "S05");
+            send(&mut stream, "s");
+            assert_eq!(receive(&mut stream), "S05");
+            send(&mut stream, "p9");
+            let pc = receive(&mut stream);
+            // Spins on JMP 0005, then gets interrupted
+            send(&mut stream, "c");
+            stream.write_all(&[0x03]).unwrap();
+            assert_eq!(receive(&mut stream), "S02");
+            send(&mut stream, "k");
+            pc
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::new(stream);
+        let mut cpu = Intel8080::new();
+        // 0000 NOP x4  0004 NOP  0005 JMP 0005
+        cpu.load_program(vec![0, 0, 0, 0, 0, 0xC3, 0x05, 0x00]);
+        while !stub.killed {
+            stub.run_cycles(&mut cpu, 1000).unwrap();
+        }
+        assert_eq!(client.join().unwrap(), "0500");
+        assert!(stub.closed);
+    }
+}
@@ -0,0 +1,342 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter::Enumerate;
+use core::str::Lines;
+use crate::intel8080::{CpuState, Flag, IOHandler, Intel8080, StepResult};
+use crate::memory::MemoryBus;
+use crate::trace::{flag_letters, traced_step, write_full};
+
+// Instructions shown before a divergence, and reference lines after it
+const CONTEXT: usize = 8;
+
+// The state a line of a reference trace expects before an instruction runs. Registers the
+// line doesn't give aren't checked.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Expected {
+    pub pc: u16,
+    pub a: Option<u8>,
+    pub f: Option<u8>,
+    pub b: Option<u8>,
+    pub c: Option<u8>,
+    pub d: Option<u8>,
+    pub e: Option<u8>,
+    pub h: Option<u8>,
+    pub l: Option<u8>,
+    pub sp: Option<u16>,
+    pub cycles: Option<u64>,
+}
+
+fn parse_flags(letters: &str) -> Option<u8> {
+    if letters.len() != Flag::ALL.len() {
+        return None;
+    }
+    let mut f = 0b00000010;
+    for ((flag, name), letter) in Flag::ALL.iter().zip("SZAPC".chars()).zip(letters.chars()) {
+        match letter {
+            '-' => {}
+            _ if letter == name => f |= flag.mask(),
+            _ => return None,
+        }
+    }
+    Some(f)
+}
+
+impl Expected {
+    // Reads a line in either of the Tracer's formats, or any other that names its fields as
+    // `PC: 0100` or `PC=0100`. Registers are hex (pairs too, AF and BC etc.), CYC is decimal
+    // and anything after a `;` is ignored. Lines without a PC aren't instructions.
+    pub fn parse(line: &str) -> Option<Expected> {
+        let line = line.split(';').next().unwrap_or("");
+        let words: Vec<&str> = line.split(|c: char| c.is_whitespace() || c == ',').filter(|word| !word.is_empty()).collect();
+        let mut expected = Expected::default();
+        let mut pc = None;
+        let mut index = 0;
+        while index < words.len() {
+            let word = words[index];
+            index += 1;
+            let (key, value) = match word.split_once(['=', ':']) {
+                Some((key, "")) if index < words.len() => {
+                    index += 1;
+                    (key, words[index - 1])
+                }
+                Some(field) => field,
+                None => continue,
+            };
+            let byte = u8::from_str_radix(value, 16).ok();
+            let word = u16::from_str_radix(value, 16).ok();
+            let [high, low] = word.map_or([None; 2], |word| word.to_be_bytes().map(Some));
+            match key.to_ascii_uppercase().as_str() {
+                "PC" => pc = word,
+                "SP" => expected.sp = word,
+                "A" => expected.a = byte,
+                "F" => expected.f = byte.or_else(|| parse_flags(value)),
+                "B" => expected.b = byte,
+                "C" => expected.c = byte,
+                "D" => expected.d = byte,
+                "E" => expected.e = byte,
+                "H" => expected.h = byte,
+                "L" => expected.l = byte,
+                "AF" => (expected.a, expected.f) = (high, low),
+                "BC" => (expected.b, expected.c) = (high, low),
+                "DE" => (expected.d, expected.e) = (high, low),
+                "HL" => (expected.h, expected.l) = (high, low),
+                "CYC" | "CYCLES" => expected.cycles = value.parse().ok(),
+                _ => {}
+            }
+        }
+        // The Tracer's full format starts with the cycle count and PC instead
+        if pc.is_none() && let [cycles, address, ..] = words[..] && address.len() == 4
+            && let Ok(cycles) = cycles.parse::<u64>() {
+            pc = u16::from_str_radix(address, 16).ok();
+            expected.cycles = Some(cycles);
+        }
+        expected.pc = pc?;
+        Some(expected)
+    }
+
+    pub fn differences(&self, state: &CpuState, compare_cycles: bool) -> Vec<Difference> {
+        // Emulators disagree about the bits of F that aren't flags
+        let flags = Flag::ALL.iter().fold(0, |mask, flag| mask | flag.mask());
+        let mut differences = Vec::new();
+        let mut check = |field, expected: Option<u64>, actual: u64| {
+            if let Some(expected) = expected && expected != actual {
+                differences.push(Difference { field, expected, actual });
+            }
+        };
+        check("PC", Some(self.pc.into()), state.pc.into());
+        check("A", self.a.map(u64::from), state.a.into());
+        check("F", self.f.map(|f| (f & flags).into()), (state.f & flags).into());
+        check("B", self.b.map(u64::from), state.b.into());
+        check("C", self.c.map(u64::from), state.c.into());
+        check("D", self.d.map(u64::from), state.d.into());
+        check("E", self.e.map(u64::from), state.e.into());
+        check("H", self.h.map(u64::from), state.h.into());
+        check("L", self.l.map(u64::from), state.l.into());
+        check("SP", self.sp.map(u64::from), state.sp.into());
+        if compare_cycles {
+            check("CYC", self.cycles, state.total_cycles);
+        }
+        differences
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Difference {
+    pub field: &'static str,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (expected, actual) = (self.expected, self.actual);
+        match self.field {
+            "PC" | "SP" => write!(f, "{}: expected {:04X}, got {:04X}", self.field, expected, actual),
+            "F" => write!(f, "F: expected {:02X} ({}), got {:02X} ({})", expected, flag_letters(expected as u8), actual,
+                          flag_letters(actual as u8)),
+            "CYC" => write!(f, "CYC: expected {}, got {}", expected, actual),
+            _ => write!(f, "{}: expected {:02X}, got {:02X}", self.field, expected, actual),
+        }
+    }
+}
+
+// Where a run stopped matching the reference, with the instructions leading up to it
+#[derive(Clone, PartialEq, Debug)]
+pub struct Divergence {
+    // Line number in the reference, from 1
+    pub line: usize,
+    // Instructions that matched before it
+    pub matched: u64,
+    pub differences: Vec<Difference>,
+    // Full format trace lines for the instructions before, and the one that diverged
+    pub before: Vec<String>,
+    pub diverged: String,
+    // The reference line that didn't match and the ones after it
+    pub expected: String,
+    pub following: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Diverged from line {} of the reference after {} matching instructions:", self.line, self.matched)?;
+        for line in &self.before {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "> {}", self.diverged)?;
+        writeln!(f, "Expected:")?;
+        writeln!(f, "> {}", self.expected)?;
+        for line in &self.following {
+            writeln!(f, "  {}", line)?;
+        }
+        for difference in &self.differences {
+            writeln!(f, "{}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs a CPU in lockstep with a reference trace, one line per instruction as the Tracer writes
+// them (unfiltered), until the state before an instruction doesn't match its line
+pub struct Comparison<'a> {
+    reference: Enumerate<Lines<'a>>,
+    next: Option<(usize, &'a str, Expected)>,
+    recent: VecDeque<String>,
+    pub compare_cycles: bool,
+    pub matched: u64,
+    pub divergence: Option<Divergence>,
+}
+
+impl<'a> Comparison<'a> {
+    pub fn new(reference: &'a str) -> Comparison<'a> {
+        let mut comparison = Comparison {
+            reference: reference.lines().enumerate(),
+            next: None,
+            recent: VecDeque::with_capacity(CONTEXT),
+            compare_cycles: true,
+            matched: 0,
+            divergence: None,
+        };
+        comparison.advance();
+        comparison
+    }
+
+    fn advance(&mut self) {
+        self.next = self.reference.find_map(|(index, line)| Some((index + 1, line, Expected::parse(line)?)));
+    }
+
+    // The reference line to match next, None once they all have
+    pub fn next_line(&self) -> Option<usize> {
+        self.next.map(|(line, ..)| line)
+    }
+
+    // Whether there is more to compare: the reference has lines left and nothing has diverged
+    pub fn running(&self) -> bool {
+        self.next.is_some() && self.divergence.is_none()
+    }
+
+    // Runs one instruction and checks the state from before it against the reference.
+    // Returns running().
+    pub fn step<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>) -> bool {
+        if !self.running() {
+            return false;
+        }
+        let (before, bytes, result) = traced_step(cpu);
+        if before.halted && matches!(result, StepResult::Halted { .. }) {
+            return true;
+        }
+        let Some((line, text, expected)) = self.next else {
+            return false;
+        };
+        let mut traced = String::new();
+        write_full(&mut traced, &before, &bytes);
+        let differences = expected.differences(&before, self.compare_cycles);
+        if differences.is_empty() {
+            if self.recent.len() == CONTEXT {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(traced);
+            self.matched += 1;
+            self.advance();
+            return self.running();
+        }
+        let following = self.reference.by_ref()
+            .filter(|(_, line)| Expected::parse(line).is_some())
+            .take(CONTEXT)
+            .map(|(_, line)| line.to_string())
+            .collect();
+        self.divergence = Some(Divergence {
+            line,
+            matched: self.matched,
+            differences,
+            before: self.recent.drain(..).collect(),
+            diverged: traced,
+            expected: text.to_string(),
+            following,
+        });
+        false
+    }
+
+    // Like Intel8080::run_cycles, but stops early when the comparison does
+    pub fn run_cycles<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, cycles: usize) -> usize {
+        let start = cpu.total_ticks;
+        while cpu.total_ticks - start < cycles && self.step(cpu) {}
+        cpu.total_ticks - start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{TraceFormat, Tracer};
+
+    // MVI A,20h; MVI B,5; ADD B; DCR B; JNZ 4; HLT
+    const PROGRAM: [u8; 10] = [0x3E, 0x20, 0x06, 0x05, 0x80, 0x05, 0xC2, 0x04, 0x00, 0x76];
+
+    fn program() -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.load_program(PROGRAM.to_vec());
+        cpu
+    }
+
+    fn reference(format: TraceFormat) -> String {
+        let mut cpu = program();
+        let mut out = String::from("a header line\n");
+        Tracer::new(format).run_cycles(&mut cpu, 200, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn parses_trace_lines() {
+        let compact = Expected::parse("PC: 0100, AF: 0002, BC: 1234, DE: 0000, HL: 0000, SP: FFFE, CYC: 17\t(C3 AB 01 00)").unwrap();
+        assert_eq!(compact.pc, 0x0100);
+        assert_eq!((compact.a, compact.f, compact.b, compact.c), (Some(0), Some(2), Some(0x12), Some(0x34)));
+        assert_eq!((compact.sp, compact.cycles), (Some(0xFFFE), Some(17)));
+
+        let full = Expected::parse("        28  1A5F  CF        RST 1           A=24 F=S-APC BC=0000 DE=1C00 HL=24B1 SP=23FC ; interrupt").unwrap();
+        assert_eq!((full.pc, full.cycles, full.a, full.f), (0x1A5F, Some(28), Some(0x24), Some(0x97)));
+        assert_eq!((full.d, full.e, full.sp), (Some(0x1C), Some(0x00), Some(0x23FC)));
+
+        let other = Expected::parse("pc=0005 a=3f sp:ff00").unwrap();
+        assert_eq!((other.pc, other.a, other.b, other.sp), (5, Some(0x3F), None, Some(0xFF00)));
+        assert_eq!(Expected::parse("Diverged at 0100"), None);
+        // Prose that happens to have four hex digits second isn't a trace line
+        assert_eq!(Expected::parse("Step 1234 done"), None);
+    }
+
+    #[test]
+    fn matches_its_own_traces() {
+        for format in [TraceFormat::Full, TraceFormat::Compact] {
+            let reference = reference(format);
+            let mut comparison = Comparison::new(&reference);
+            let mut cpu = program();
+            comparison.run_cycles(&mut cpu, 1000);
+            assert_eq!(comparison.divergence, None);
+            assert_eq!(comparison.next_line(), None);
+            assert_eq!(comparison.matched, 18);
+        }
+    }
+
+    #[test]
+    fn reports_the_first_difference() {
+        let reference = reference(TraceFormat::Compact);
+        let mut comparison = Comparison::new(&reference);
+        let mut cpu = program();
+        // ADD B becomes SUB B
+        cpu.memory.poke(4, 0x90);
+        comparison.run_cycles(&mut cpu, 1000);
+        let divergence = comparison.divergence.clone().unwrap();
+        assert_eq!(divergence.line, 5);
+        assert_eq!(divergence.matched, 3);
+        assert_eq!(divergence.before.len(), 3);
+        assert!(divergence.diverged.contains("0005  05        DCR B"));
+        assert!(divergence.expected.starts_with("PC: 0005, AF: 2502"));
+        assert_eq!(divergence.following.len(), 8);
+        assert_eq!(divergence.differences, [
+            Difference { field: "A", expected: 0x25, actual: 0x1B },
+            Difference { field: "F", expected: 0x00, actual: 0x04 },
+        ]);
+        assert!(!comparison.step(&mut cpu));
+    }
+}
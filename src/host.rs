@@ -0,0 +1,43 @@
+use core::time::Duration;
+
+// Time as the host sees it. The core never reads a clock itself, anything that has to keep
+// pace with real time (FrameScheduler) goes through one of these.
+pub trait Clock {
+    // Time since some fixed point, which must never go backwards
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+#[cfg(feature = "std")]
+pub struct StdClock {
+    start: std::time::Instant,
+    sleeper: spin_sleep::SpinSleeper,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> StdClock {
+        StdClock {
+            start: std::time::Instant::now(),
+            sleeper: spin_sleep::SpinSleeper::default().with_spin_strategy(spin_sleep::SpinStrategy::SpinLoopHint),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> StdClock {
+        StdClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.sleeper.sleep(duration);
+    }
+}
@@ -1,23 +1,339 @@
-use std::io::{stdout, Write};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use alloc::vec::Vec;
+use core::fmt;
 use log::warn;
-use spin_sleep::SpinSleeper;
+use crate::block_cache::{BlockCache, BDOS_ENTRY};
+use crate::flags::{LazyFlags, Pending, AUX_CARRY, CARRY, PARITY, SIGN, SZAP, SZAPC, ZERO};
+use crate::interrupts::{InterruptController, InterruptVector};
+#[cfg(feature = "jit")]
+use crate::jit::{self, JitCache, JitFrame};
+use crate::memory::{FlatMemory, MemoryBus};
+use crate::save_state::{SaveStateError, Snapshot, StateReader, StateWriter};
 
 const PROGRAM_START_ADDRESS: usize = 0x0;
+// States charged for each step spent idling in the halt state
+const HALT_IDLE_STATES: usize = 4;
 
-// const CLOCK_SPEED:usize = 2000000;
-// const PERIOD:f64 = 1.0 / (CLOCK_SPEED as f64);
-const PERIOD_NS:usize = 350; // should be 500, but 350 works to get 2MHz
-
+// Called synchronously by IN and OUT, so devices see port accesses in program order
 pub trait IOHandler {
     fn input(&mut self, port: u8) -> u8;
     fn output(&mut self, port: u8, value: u8);
+
+    // Characters a CP/M program prints through the BDOS hook at 0x0005. They go to stdout
+    // when std is available and are dropped otherwise.
+    fn console_output(&mut self, byte: u8) {
+        #[cfg(feature = "std")]
+        print!("{}", byte as char);
+        #[cfg(not(feature = "std"))]
+        let _ = byte;
+    }
+}
+
+// Used when no devices are attached, reads return 0 and writes are dropped
+#[derive(Clone)]
+pub struct NullIO;
+
+impl IOHandler for NullIO {
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
+
+// A data access made by an instruction: memory reads and writes (not instruction fetches)
+// and port I/O with the value transferred
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read(u16),
+    Write(u16, u8),
+    Input(u8, u8),
+    Output(u8, u8),
+}
+
+// What a call to step() did. cycles is the number of states it took.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StepResult {
+    Executed { cycles: usize },
+    // Ran HLT, or idled because the CPU is still halted
+    Halted { cycles: usize },
+    // Acknowledged an interrupt and ran the jammed instruction
+    InterruptAccepted { vector: InterruptVector, cycles: usize },
+    // cycles is 0 when the trap policy stopped the instruction from running
+    Trap { trap: Trap, cycles: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TrapKind {
+    // One of the duplicate encodings (0x08, 0xCB, 0xD9, 0xDD, ...) the datasheet leaves undefined
+    UndocumentedOpcode,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            TrapKind::UndocumentedOpcode => "undocumented",
+        };
+        write!(f, "{} opcode {:#04x} at {:#06x}", kind, self.opcode, self.pc)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TrapPolicy {
+    // Report the trap and carry on, undocumented opcodes behave like the instruction they alias
+    Continue,
+    // Report the trap without running the instruction, PC is left pointing at it
+    Stop,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExecutionMode {
+    // Fetch and decode every instruction as it runs
+    Interpreter,
+    // run_cycles runs straight-line code from decoded blocks cached by address. Results are
+    // the same as the interpreter's as long as code is only changed by the CPU itself or
+    // flush_block_cache is called after changing it, and code fetches have no side effects.
+    // step() always interprets.
+    BlockCache,
+    // Like BlockCache, but blocks that have run often enough are compiled to x86-64 code
+    #[cfg(feature = "jit")]
+    Jit,
+    // Runs every compiled block, then runs it again on the interpreter and panics if the
+    // registers, states, instruction count or memory writes differ. Slow, for testing the JIT.
+    #[cfg(feature = "jit")]
+    JitDifferential,
+}
+
+pub(crate) fn is_undocumented(opcode: u8) -> bool {
+    matches!(opcode, 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB | 0xD9 | 0xDD | 0xED | 0xFD)
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Flag {
+    Sign,
+    Zero,
+    AuxCarry,
+    Parity,
+    Carry,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 5] = [Flag::Sign, Flag::Zero, Flag::AuxCarry, Flag::Parity, Flag::Carry];
+
+    // Bit in the flags register (the low byte of PSW)
+    pub fn mask(self) -> u8 {
+        match self {
+            Flag::Sign => 0x80,
+            Flag::Zero => 0x40,
+            Flag::AuxCarry => 0x10,
+            Flag::Parity => 0x04,
+            Flag::Carry => 0x01,
+        }
+    }
+}
+
+// Programmer-visible CPU state, for debuggers, save states and tests outside this module.
+// Pending interrupt requests live in the InterruptController, not here.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub inte: bool,
+    // EI ran last, so interrupts are held off for one more instruction
+    pub ei_delay: bool,
+    pub halted: bool,
+    pub total_cycles: u64,
+}
+
+impl CpuState {
+    pub const SIZE: usize = 21;
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.f & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        if value {
+            self.f |= flag.mask();
+        } else {
+            self.f &= !flag.mask();
+        }
+    }
+
+    // Fixed little-endian layout: A F B C D E H L, SP, PC, INTE/EI delay/halted bits, cycles
+    pub fn to_bytes(&self) -> [u8; CpuState::SIZE] {
+        let mut bytes = [0; CpuState::SIZE];
+        bytes[..8].copy_from_slice(&[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l]);
+        bytes[8..10].copy_from_slice(&self.sp.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[12] = self.inte as u8 | (self.ei_delay as u8) << 1 | (self.halted as u8) << 2;
+        bytes[13..21].copy_from_slice(&self.total_cycles.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<CpuState> {
+        if bytes.len() != CpuState::SIZE || bytes[12] & !0b111 != 0 {
+            return None;
+        }
+        Some(CpuState {
+            a: bytes[0],
+            f: bytes[1],
+            b: bytes[2],
+            c: bytes[3],
+            d: bytes[4],
+            e: bytes[5],
+            h: bytes[6],
+            l: bytes[7],
+            sp: u16::from_le_bytes([bytes[8], bytes[9]]),
+            pc: u16::from_le_bytes([bytes[10], bytes[11]]),
+            inte: bytes[12] & 0b001 != 0,
+            ei_delay: bytes[12] & 0b010 != 0,
+            halted: bytes[12] & 0b100 != 0,
+            total_cycles: u64::from_le_bytes(bytes[13..21].try_into().unwrap()),
+        })
+    }
+}
+
+impl Snapshot for NullIO {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
+
+// An opcode with its operand fields already pulled out. rp is a register pair (BC, DE, HL,
+// SP or PSW), ddd/sss a register number (6 is M), cc a condition, alu an ALU operation and
+// n an RST vector.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Op {
+    Nop,
+    Lxi(u8),
+    Stax(u8),
+    Inx(u8),
+    Inr(u8),
+    Dcr(u8),
+    Mvi(u8),
+    Dad(u8),
+    Ldax(u8),
+    Dcx(u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Shld,
+    Daa,
+    Lhld,
+    Cma,
+    Sta,
+    Stc,
+    Lda,
+    Cmc,
+    Mov(u8, u8),
+    Hlt,
+    Alu(u8, u8),
+    Rcc(u8),
+    Pop(u8),
+    Jcc(u8),
+    Jmp,
+    Ccc(u8),
+    Push(u8),
+    AluImmediate(u8),
+    Rst(u8),
+    Ret,
+    Call,
+    Out,
+    In,
+    Xthl,
+    Pchl,
+    Xchg,
+    Di,
+    Sphl,
+    Ei,
+}
+
+const fn decode(opcode: u8) -> Op {
+    let rp = (opcode & 0x30) >> 4;
+    let ddd = (opcode & 0x38) >> 3;
+    let sss = opcode & 0x07;
+    match opcode {
+        0x07 => Op::Rlc,
+        0x0F => Op::Rrc,
+        0x17 => Op::Ral,
+        0x1F => Op::Rar,
+        0x22 => Op::Shld,
+        0x27 => Op::Daa,
+        0x2A => Op::Lhld,
+        0x2F => Op::Cma,
+        0x32 => Op::Sta,
+        0x37 => Op::Stc,
+        0x3A => Op::Lda,
+        0x3F => Op::Cmc,
+        0x76 => Op::Hlt,
+        0xC3 | 0xCB => Op::Jmp,
+        0xC9 | 0xD9 => Op::Ret,
+        0xCD | 0xDD | 0xED | 0xFD => Op::Call,
+        0xD3 => Op::Out,
+        0xDB => Op::In,
+        0xE3 => Op::Xthl,
+        0xE9 => Op::Pchl,
+        0xEB => Op::Xchg,
+        0xF3 => Op::Di,
+        0xF9 => Op::Sphl,
+        0xFB => Op::Ei,
+
+        _ if opcode & 0xC7 == 0x00 => Op::Nop,
+        _ if opcode & 0xCF == 0x01 => Op::Lxi(rp),
+        _ if opcode & 0xCF == 0x02 => Op::Stax(rp),
+        _ if opcode & 0xCF == 0x03 => Op::Inx(rp),
+        _ if opcode & 0xC7 == 0x04 => Op::Inr(ddd),
+        _ if opcode & 0xC7 == 0x05 => Op::Dcr(ddd),
+        _ if opcode & 0xC7 == 0x06 => Op::Mvi(ddd),
+        _ if opcode & 0xCF == 0x09 => Op::Dad(rp),
+        _ if opcode & 0xCF == 0x0A => Op::Ldax(rp),
+        _ if opcode & 0xCF == 0x0B => Op::Dcx(rp),
+        _ if opcode & 0xC0 == 0x40 => Op::Mov(ddd, sss),
+        _ if opcode & 0xC0 == 0x80 => Op::Alu(ddd, sss),
+        _ if opcode & 0xC7 == 0xC0 => Op::Rcc(ddd),
+        _ if opcode & 0xCF == 0xC1 => Op::Pop(rp),
+        _ if opcode & 0xC7 == 0xC2 => Op::Jcc(ddd),
+        _ if opcode & 0xC7 == 0xC4 => Op::Ccc(ddd),
+        _ if opcode & 0xCF == 0xC5 => Op::Push(rp),
+        _ if opcode & 0xC7 == 0xC6 => Op::AluImmediate(ddd),
+        // opcode & 0xC7 == 0xC7, the last encodings left
+        _ => Op::Rst(ddd),
+    }
+}
+
+// Every opcode decoded once, at compile time
+pub(crate) static OPCODES: [Op; 256] = {
+    let mut table = [Op::Nop; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = decode(opcode as u8);
+        opcode += 1;
+    }
+    table
+};
+
+#[derive(Clone)]
 pub struct Registers {
     A:u8,
-    Flags: u8,
+    Flags: LazyFlags,
     B:u8,
     C:u8,
     D:u8,
@@ -30,7 +346,7 @@ impl Registers {
     pub fn new() -> Registers {
         Self {
             A:0,
-            Flags:0b00000010,
+            Flags:LazyFlags::new(0b00000010),
             B:0,
             C:0,
             D:0,
@@ -41,262 +357,219 @@ impl Registers {
     }
 }
 
-pub struct Intel8080 {
-    pub memory:[u8;65536],
+#[derive(Clone)]
+pub struct Intel8080<M: MemoryBus = FlatMemory, IO: IOHandler = NullIO> {
+    pub memory: M,
     pub PC:u16,
     registers: Registers,
     ticks: usize,
     pub total_ticks: usize,
-    pub last_cycle_time: Instant,
+    // Instructions run, including ones jammed by interrupts
+    pub total_instructions: u64,
     SP:u16,
-    rp:u8,
-    ddd:u8,
-    sss:u8,
-    cc:u8,
-    alu:u8,
-    n:u8,
-    pub oport: Vec<(u8,u8)>,
-    pub iport:[u8;256],
+    pub io: IO,
     pub interrupt_enabled: bool,
-    // interrupt_requested: bool,
-    // interrupt_acknowledge: bool,
-    pub interrupt_data: Vec<u8>,
-    sleeper: SpinSleeper,
+    pub interrupts: InterruptController,
+    // Set by EI so the next instruction runs before an interrupt can be accepted
+    ei_delay: bool,
+    pub halted: bool,
+    pub trap_policy: TrapPolicy,
+    // Instruction bytes that don't come from memory at PC (INTA cycles, or a cached block),
+    // with the index of the next one to read
+    fed: Option<([u8; 3], usize)>,
+    pub execution_mode: ExecutionMode,
+    block_cache: BlockCache,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: JitCache,
+    // Data accesses made while this is Some, for the debugger's watchpoints
+    pub(crate) accesses: Option<Vec<Access>>,
 }
 
 impl Intel8080 {
     pub fn new() -> Intel8080 {
+        Intel8080::with_io(NullIO)
+    }
+}
+
+impl<IO: IOHandler> Intel8080<FlatMemory, IO> {
+    pub fn with_io(io: IO) -> Intel8080<FlatMemory, IO> {
+        Intel8080::with_bus(FlatMemory::new(), io)
+    }
+}
+
+impl<M: MemoryBus, IO: IOHandler> Intel8080<M, IO> {
+    pub fn with_bus(memory: M, io: IO) -> Intel8080<M, IO> {
         Self {
-            memory: [0;65536],
+            memory,
             PC: PROGRAM_START_ADDRESS as u16,
             registers: Registers::new(),
             ticks: 0,
             total_ticks: 0,
-            last_cycle_time: Instant::now(),
+            total_instructions: 0,
             SP:0,
-            rp:0,
-            ddd:0,
-            sss:0,
-            cc:0,
-            alu:0,
-            n:0,
-            oport:Vec::new(),
-            iport:[0;256],
+            io,
             interrupt_enabled: true,
-            // interrupt_requested: false,
-            // interrupt_acknowledge: false,
-            interrupt_data: Vec::new(),
-            sleeper: SpinSleeper::new(50000000).with_spin_strategy(spin_sleep::SpinStrategy::SpinLoopHint),
+            interrupts: InterruptController::new(),
+            ei_delay: false,
+            halted: false,
+            trap_policy: TrapPolicy::Continue,
+            fed: None,
+            execution_mode: ExecutionMode::Interpreter,
+            block_cache: BlockCache::new(),
+            #[cfg(feature = "jit")]
+            jit: JitCache::new(),
+            accesses: None,
         }
     }
 
-    pub fn load_program(&mut self, data: Vec<u8>) {
-        // let mut data = Vec::new();
-        // data.push(0x01);
-        // data.push(0x02);
-        // data.push(0x03);
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.registers.A,
+            f: self.registers.Flags.get(),
+            b: self.registers.B,
+            c: self.registers.C,
+            d: self.registers.D,
+            e: self.registers.E,
+            h: self.registers.H,
+            l: self.registers.L,
+            sp: self.SP,
+            pc: self.PC,
+            inte: self.interrupt_enabled,
+            ei_delay: self.ei_delay,
+            halted: self.halted,
+            total_cycles: self.total_ticks as u64,
+        }
+    }
+
+    // The always-one and always-zero flag bits are forced the same way POP PSW does
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.registers.A = state.a;
+        self.registers.Flags.set(state.f & 0b11010111 | 0b00000010);
+        self.registers.B = state.b;
+        self.registers.C = state.c;
+        self.registers.D = state.d;
+        self.registers.E = state.e;
+        self.registers.H = state.h;
+        self.registers.L = state.l;
+        self.SP = state.sp;
+        self.PC = state.pc;
+        self.interrupt_enabled = state.inte;
+        self.ei_delay = state.ei_delay;
+        self.halted = state.halted;
+        self.total_ticks = state.total_cycles as usize;
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.registers.Flags.test(flag.mask())
+    }
 
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        self.registers.Flags.write(flag.mask(), value);
+    }
+
+    pub fn load_program(&mut self, data: Vec<u8>) {
         for n in 0..data.len(){
-            self.memory[n] = data[n];
+            self.memory.poke((PROGRAM_START_ADDRESS + n) as u16, data[n]);
         }
-
-        // for n in 0..256 {
-        //     self.memory[n] = n as u8;
-        // }
+        self.flush_block_cache();
     }
 
-    fn get_m(&self) -> u8{
-        let address:usize = ((self.registers.H as usize) << 8) + (self.registers.L as usize);
-        return self.memory[address];
+    fn get_m(&mut self) -> u8{
+        let address = self.get_hl();
+        return self.read_byte(address);
     }
 
     fn write_m(&mut self, value: u8){
-        let address:usize = ((self.registers.H as usize) << 8) + (self.registers.L as usize);
-        self.memory[address] = value;
+        let address = self.get_hl();
+        self.write_byte(address, value);
     }
-    
-    fn add_3_szapc(&mut self, i1: u8, i2:u8, i3:u8) -> u8 {
-        let result = i1.wrapping_add(i2).wrapping_add(i3);
-        self.set_szp(result);
 
-        // a
-        let ac_result = (i1 & 0x0F) + (i2 & 0x0F) + (i3 & 0x0F);
-        if (ac_result & 0x10) >> 4 > 0 {
-            self.registers.Flags |= 0b00010000;
-        } else {
-            self.registers.Flags &= !(0b00010000);
+    // Data reads go through here so they can be watched. Opcode and operand fetches don't.
+    fn read_byte(&mut self, address: u16) -> u8 {
+        if let Some(log) = &mut self.accesses {
+            log.push(Access::Read(address));
         }
-        
-        // c
-        if (i1 as u16) + (i2 as u16) + (i3 as u16) > 0xFF {
-            self.registers.Flags |= 0b00000001;
-        } else {
-            self.registers.Flags &= !(0b00000001);
+        self.memory.read(address)
+    }
+
+    // Every write an instruction makes goes through here so cached blocks stay current
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
+        if let Some(log) = &mut self.accesses {
+            log.push(Access::Write(address, value));
         }
-        result
+        #[cfg(feature = "jit")]
+        if let Some(log) = &mut self.jit.write_log {
+            log.push((address, self.memory.peek(address)));
+        }
+        self.memory.write(address, value);
+        // Also in the interpreter, the mode can be switched back with blocks still cached
+        self.block_cache.invalidate(address);
+        #[cfg(feature = "jit")]
+        self.jit.invalidate(address);
     }
 
-    fn add_szap(&mut self, i1:u8,i2:u8) -> u8{
-        let result = i1.wrapping_add(i2);
-        self.set_szp(result);
+    fn read_word(&mut self, address: u16) -> u16 {
+        let lo = self.read_byte(address);
+        let hi = self.read_byte(address.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
 
-        // a
-        let ac_result = (i1 & 0x0F) + (i2 & 0x0F);
-        if (ac_result & 0x10) >> 4 == 1 {
-            self.registers.Flags |= 0b00010000;
-        } else {
-            self.registers.Flags &= !(0b00010000);
-        }
+    fn write_word(&mut self, address: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write_byte(address, lo);
+        self.write_byte(address.wrapping_add(1), hi);
+    }
+    
+    fn add_3_szapc(&mut self, i1: u8, i2:u8, i3:u8) -> u8 {
+        self.registers.Flags.record(Pending::Add(i1, i2, i3), SZAPC);
+        i1.wrapping_add(i2).wrapping_add(i3)
+    }
 
-        return result;
+    fn add_szap(&mut self, i1:u8,i2:u8) -> u8{
+        self.registers.Flags.record(Pending::Add(i1, i2, 0), SZAP);
+        i1.wrapping_add(i2)
     }
 
     fn add_szapc(&mut self, i1:u8,i2:u8) -> u8{
-        let result = self.add_szap(i1, i2);
-        self.set_c_add(i1,i2);
-        return result;
+        self.add_3_szapc(i1, i2, 0)
     }
 
+    // AC comes from adding the two's complement of i2, which only matches SUB's for DCR
     fn sub_szap(&mut self, i1:u8,i2:u8) -> u8{
-        let complement = (!i2).wrapping_add(1);
-        let result = i1.wrapping_sub(i2);
-        self.set_szp(result);
-
-        // a
-        if ((complement & 0x0F) + (i1 & 0x0F) > 0x0F ) {
-            self.registers.Flags |= 0b00010000;
-        } else {
-            self.registers.Flags &= !(0b00010000);
-        }
-
-        return result;
+        self.add_szap(i1, (!i2).wrapping_add(1))
     }
 
-    fn sub_szapc(&mut self, i1:u8,i2:u8) -> u8{
-        let result = self.sub_szap(i1, i2);
-        self.set_c_sub(i1,i2);
-        return result;
+    // ANA/XRA/ORA, which clear CY
+    fn set_logic(&mut self, result: u8, aux_carry: bool) -> u8 {
+        self.registers.Flags.record(Pending::Logic { result, aux_carry }, SZAPC);
+        result
     }
 
-    // fn set_szapc(&mut self, i1:u8, i2:u8, result:u8){
-    //     log::warn!("Wrong method");
-    //     self.set_szp(i1, i2, result);
-    //     self.set_c_add(i1,i2);
-    // }
-
     fn get_s(&self)->bool{
-        if (self.registers.Flags & 0x80)>>7==1 {
-            return true;
-        } else {
-            return false;
-        }
+        self.registers.Flags.test(SIGN)
     }
 
     fn get_z(&self)->bool{
-        if (self.registers.Flags & 0x40)>>6==1 {
-            return true;
-        } else {
-            return false;
-        }
+        self.registers.Flags.test(ZERO)
     }
 
     fn get_a(&self)->bool{
-        if (self.registers.Flags & 0x10)>>4==1 {
-            return true;
-        } else {
-            return false;
-        }
+        self.registers.Flags.test(AUX_CARRY)
     }
 
     fn get_p(&self)->bool{
-        if (self.registers.Flags & 0x04)>>2==1 {
-            return true;
-        } else {
-            return false;
-        }
+        self.registers.Flags.test(PARITY)
     }
 
     fn get_c(&self)->bool{
-        if (self.registers.Flags & 0x01)==1 {
-            return true;
-        } else {
-            return false;
-        }
-    }
-    
-    fn write_a(&mut self, val:bool){
-        if val {
-            self.registers.Flags |= 0b00010000;
-        } else {
-            self.registers.Flags &= !(0b00010000);
-        }
-    }
-    
-    fn write_c(&mut self, val:bool){
-        if val {
-            self.registers.Flags |= 0x01;
-        } else {
-            self.registers.Flags &= !(0x01);
-        }
+        self.registers.Flags.test(CARRY)
     }
 
-    fn set_szp(&mut self, result: u8) {
-        // s
-        if (result & 0x80) >> 7 == 1 {
-            self.registers.Flags |= 0b10000000;
-        } else {
-            self.registers.Flags &= !(0b10000000);
-        }
-
-        // z
-        if result == 0 {
-            self.registers.Flags |= 0b01000000;
-        } else {
-            self.registers.Flags &= !(0b01000000);
-        }
-
-        // a
-        // let ac_result = (i1 & 0x0F) + (i2 & 0x0F);
-        // if (ac_result & 0x10) >> 4 == 1 {
-        //     self.registers.Flags |= 0b00010000;
-        // } else {
-        //     self.registers.Flags &= !(0b00010000);
-        // }
-
-        // p
-        let mut parity: bool = true;
-        for n in 0..8 {
-            if ((result >> n) & 0x01) == 1 {
-                parity = !parity;
-            }
-        }
-        if parity {
-            self.registers.Flags |= 0b00000100;
-        } else {
-            self.registers.Flags &= !(0b00000100);
-        }
-    }
-
-    fn set_c_add(&mut self, i1: u8, i2: u8){
-        // c
-        if (i1 as u16) + (i2 as u16) > 0xFF {
-            self.registers.Flags |= 0b00000001;
-        } else {
-            self.registers.Flags &= !(0b00000001);
-        }
-    }
-    fn set_c_sub(&mut self, i1: u8, i2: u8){
-        // c
-        let complement = (!i2).wrapping_add(1);
-        if (i1 as u16) + complement as u16 > 0xFF {
-            self.registers.Flags |= 0b00000001;
-        } else {
-            self.registers.Flags &= !(0b00000001);
-        }
+    fn write_c(&mut self, val:bool){
+        self.registers.Flags.write(CARRY, val);
     }
 
-
-    fn get_register(&self, num:u8)->u8{
+    fn get_register(&mut self, num:u8)->u8{
         match num {
             0=>self.registers.B,
             1=>self.registers.C,
@@ -325,14 +598,14 @@ impl Intel8080 {
     }
 
     fn get_bc(&self)->u16{
-        ((self.get_register(0) as u16) << 8) + (self.get_register(1) as u16)
+        ((self.registers.B as u16) << 8) + (self.registers.C as u16)
     }
-    fn get_de(&self)->u16{ ((self.get_register(2) as u16) << 8) + (self.get_register(3) as u16) }
+    fn get_de(&self)->u16{ ((self.registers.D as u16) << 8) + (self.registers.E as u16) }
     fn get_hl(&self)->u16{
-        ((self.get_register(4) as u16) << 8) + (self.get_register(5) as u16)
+        ((self.registers.H as u16) << 8) + (self.registers.L as u16)
     }
     fn get_psw(&self)->u16{
-        ((self.get_register(7) as u16) << 8) + (self.registers.Flags as u16)
+        ((self.registers.A as u16) << 8) + (self.registers.Flags.get() as u16)
     }
     fn get_sp(&self)->u16{
         return self.SP;
@@ -355,159 +628,315 @@ impl Intel8080 {
     }
 
     fn read_next_byte(&mut self) -> u8 {
-        let val =  self.memory[self.PC as usize];
-        self.PC += 1;
+        if let Some((bytes, index)) = &mut self.fed {
+            // Operands of a jammed or cached instruction, PC is already where it should be
+            let val = bytes.get(*index).copied().unwrap_or(0xFF);
+            *index += 1;
+            return val;
+        }
+        let val =  self.memory.read(self.PC);
+        self.PC = self.PC.wrapping_add(1);
         val
     }
 
     pub fn cycle(&mut self) {
-        // print!("{:x}\n",self.PC);
-        if (self.PC==5 && !cfg!(test)){
+        self.step();
+    }
+
+    // Runs one instruction, accepts one interrupt or spends one idle period in the halt state
+    pub fn step(&mut self) -> StepResult {
+        // Requests are only acknowledged while INTE is set, until then they stay latched
+        let ei_delay = core::mem::take(&mut self.ei_delay);
+        if self.interrupt_enabled && !ei_delay && self.interrupts.pending() {
+            self.halted = false;
+            let vector = self.interrupts.acknowledge().unwrap();
+            self.interrupt_enabled = false;
+            // A vector shorter than its instruction leaves the bus floating for the rest
+            let mut bytes = [0xFF; 3];
+            bytes[..vector.bytes().len()].copy_from_slice(vector.bytes());
+            self.fed = Some((bytes, 1));
+            self.decode_execute(bytes[0]);
+            self.fed = None;
+            return StepResult::InterruptAccepted { vector, cycles: self.end_step() };
+        }
+
+        if self.halted {
+            // Only an accepted interrupt (or reset) leaves the halt state
+            self.ticks += HALT_IDLE_STATES;
+            return StepResult::Halted { cycles: self.end_step() };
+        }
+
+        let trap = self.execute_next();
+        let cycles = self.end_step();
+        match trap {
+            Some(trap) => StepResult::Trap { trap, cycles },
+            None if self.halted => StepResult::Halted { cycles },
+            None => StepResult::Executed { cycles },
+        }
+    }
+
+    fn end_step(&mut self) -> usize {
+        let cycles = self.ticks;
+        self.total_ticks += cycles;
+        self.ticks = 0;
+        cycles
+    }
+
+    fn execute_next(&mut self) -> Option<Trap> {
+        if (self.PC==BDOS_ENTRY && !cfg!(test)){
             if self.registers.C == 9 {
                 let mut addr = self.get_de();
-                while (self.memory[addr as usize]!=0b00100100){
-                    print!("{}", self.memory[addr as usize] as char);
-                    // stdout().flush();
+                while (self.memory.peek(addr)!=0b00100100){
+                    self.io.console_output(self.memory.peek(addr));
                     addr += 1;
                 }
             } else if self.registers.C==2 {
-                print!("{}", self.registers.E as char);
-                // stdout().flush();
+                self.io.console_output(self.registers.E);
             }
             self.decode_execute(0b11001001); //return
         }
 
+        let pc = self.PC;
+        let opcode = self.memory.peek(pc);
+        let mut trap = None;
+        if is_undocumented(opcode) {
+            trap = Some(Trap { kind: TrapKind::UndocumentedOpcode, pc, opcode });
+            if self.trap_policy == TrapPolicy::Stop {
+                return trap;
+            }
+        }
+
         let opcode = self.fetch();
         self.decode_execute(opcode);
-        
-        self.total_ticks += self.ticks;
-        let cycle_time:u128 = ((self.ticks) * PERIOD_NS) as u128;
-        let duration = self.last_cycle_time.elapsed().as_nanos();
-        if cycle_time> duration {
-            self.sleeper.sleep(Duration::from_nanos((cycle_time - duration) as u64));
+        trap
+    }
+
+    // Runs whole instructions until at least `cycles` states have passed and returns how many
+    // actually did, which can overshoot by up to one instruction. Stops early with the trap
+    // if the trap policy is Stop.
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<usize, Trap> {
+        let start = self.total_ticks;
+        while self.total_ticks - start < cycles {
+            let until = start + cycles;
+            let ran = match self.execution_mode {
+                ExecutionMode::Interpreter => false,
+                ExecutionMode::BlockCache => self.run_block(until),
+                #[cfg(feature = "jit")]
+                ExecutionMode::Jit | ExecutionMode::JitDifferential => self.run_jit(until) || self.run_block(until),
+            };
+            if ran {
+                continue;
+            }
+            if let StepResult::Trap { trap, .. } = self.step() {
+                if self.trap_policy == TrapPolicy::Stop {
+                    return Err(trap);
+                }
+            }
         }
-        self.ticks = 0;
-        
-        if (self.interrupt_enabled && self.interrupt_data.len() > 0){
-            let interrupt_begin_time = Instant::now();
-        
-            // self.interrupt_acknowledge = true;
-            // sleep(Duration::from_secs_f64(2.0*PERIOD));
-        
-            let opcode = self.interrupt_data.pop().unwrap();
-            self.interrupt_enabled = false;
-            // self.interrupt_acknowledge = false;
-        
-            self.decode_execute(opcode);
-            // self.interrupt_enabled = true;
-        
-            let duration = interrupt_begin_time.elapsed().as_nanos();
-            self.total_ticks += self.ticks;
-            let cycle_time:u128 = ((self.ticks) * PERIOD_NS) as u128;
-            if cycle_time> duration as u128 {
-                self.sleeper.sleep(Duration::from_nanos((cycle_time - duration) as u64))
+        Ok(self.total_ticks - start)
+    }
+
+    // Runs the cached block at PC, one instruction at a time until the block ends, a write
+    // invalidates it or total_ticks reaches `until`. Returns false without doing anything
+    // when step() has to handle the next instruction instead.
+    fn run_block(&mut self, until: usize) -> bool {
+        if !self.can_run_block() {
+            return false;
+        }
+        let Some(ops) = self.block_cache.get(&self.memory, self.PC) else {
+            return false;
+        };
+        let generation = self.block_cache.generation;
+        for decoded in ops.iter() {
+            self.PC = decoded.next_pc;
+            self.fed = Some((decoded.bytes, 1));
+            self.execute(decoded.op);
+            self.fed = None;
+            self.end_step();
+            if self.total_ticks >= until || self.block_cache.generation != generation {
+                break;
             }
-            self.ticks = 0;
         }
-        
-        // self.last_cycle_time = Instant::now();
+        true
+    }
+
+    // Whether the next instruction can come from a block instead of going through step()
+    fn can_run_block(&self) -> bool {
+        let interrupt_due = self.interrupt_enabled && self.interrupts.pending();
+        !(self.halted || self.ei_delay || interrupt_due || self.PC == BDOS_ENTRY)
+    }
+
+    // Runs the compiled block at PC if there is one and it can't cross `until` before its last
+    // instruction, which is where run_block would have stopped. Returns false otherwise.
+    #[cfg(feature = "jit")]
+    fn run_jit(&mut self, until: usize) -> bool {
+        if !self.can_run_block() {
+            return false;
+        }
+        let helpers = jit::Helpers { read: jit::read::<M, IO> as *const () as usize, write: jit::write::<M, IO> as *const () as usize };
+        let Some(block) = self.jit.lookup(&self.memory, self.PC, helpers) else {
+            return false;
+        };
+        if self.total_ticks + block.prefix_ticks >= until {
+            return false;
+        }
+        let differential = self.execution_mode == ExecutionMode::JitDifferential;
+        let before = (self.state(), self.total_instructions);
+        if differential {
+            self.jit.write_log = Some(Vec::new());
+        }
+
+        let mut frame = JitFrame {
+            bc: self.get_bc() as u32,
+            de: self.get_de() as u32,
+            hl: self.get_hl() as u32,
+            a: self.registers.A as u32,
+            f: self.registers.Flags.get() as u32,
+            sp: self.SP as u32,
+            pc: 0,
+            ticks: 0,
+            instructions: 0,
+            invalidated: 0,
+            cpu: self as *mut Self as *mut u8,
+        };
+        // The block only reaches this CPU through frame.cpu, and nothing else holds it
+        unsafe { block.run(&mut frame) };
+        self.write_bc(frame.bc as u16);
+        self.write_de(frame.de as u16);
+        self.write_hl(frame.hl as u16);
+        self.registers.A = frame.a as u8;
+        self.registers.Flags.set(frame.f as u8);
+        self.SP = frame.sp as u16;
+        self.PC = frame.pc as u16;
+        self.total_ticks += frame.ticks as usize;
+        self.total_instructions += frame.instructions as u64;
+
+        if differential {
+            self.check_jit_block(before.0, before.1, frame.instructions);
+        }
+        true
+    }
+
+    // Undoes the block that just ran, runs the same instructions on the interpreter and panics
+    // if anything came out differently
+    #[cfg(feature = "jit")]
+    fn check_jit_block(&mut self, start: CpuState, instructions: u64, count: u32) {
+        let compiled = self.state();
+        let compiled_instructions = self.total_instructions;
+        let compiled_writes = self.jit.write_log.replace(Vec::new()).unwrap();
+        let written: Vec<(u16, u8)> = compiled_writes.iter()
+            .map(|(address, _)| (*address, self.memory.peek(*address)))
+            .collect();
+        for (address, old) in compiled_writes.iter().rev() {
+            self.memory.poke(*address, *old);
+        }
+        self.set_state(&start);
+        self.total_instructions = instructions;
+        for _ in 0..count {
+            self.step();
+        }
+
+        let interpreted_writes = self.jit.write_log.take().unwrap();
+        assert_eq!(compiled, self.state(), "JIT block at {:#06x} left different registers than the interpreter", start.pc);
+        assert_eq!(compiled_instructions, self.total_instructions, "JIT block at {:#06x} counted different instructions", start.pc);
+        let addresses = compiled_writes.iter().chain(interpreted_writes.iter()).map(|(address, _)| *address);
+        for address in addresses {
+            // Memory the block didn't write still holds what the interpreter first overwrote
+            let value = written.iter().rev().find(|(other, _)| *other == address)
+                .or(interpreted_writes.iter().find(|(other, _)| *other == address))
+                .map(|(_, value)| *value)
+                .unwrap();
+            assert_eq!(value, self.memory.peek(address), "JIT block at {:#06x} wrote {:#06x} differently", start.pc, address);
+        }
+    }
+
+    // Call after changing code in memory from outside the CPU while using the block cache
+    // or the JIT
+    pub fn flush_block_cache(&mut self) {
+        self.block_cache.clear();
+        #[cfg(feature = "jit")]
+        self.jit.clear();
+    }
 
-        // self.check_flags();
+    // How many times a block has to be entered before the JIT compiles it
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, executions: u32) {
+        self.jit.threshold = executions;
     }
 
     fn check_flags(&mut self){
-        let flags = self.registers.Flags;
+        let flags = self.registers.Flags.get();
         let mut bit_arr:[u8;8] = [0;8];
         for n in 0..8 {
             bit_arr[n] = (flags & (0b01 << (7-n)))>>(7-n) ;
         }
         if bit_arr[2] != 0 {
-            println!("flag bit 2 mismatch")
+            warn!("flag bit 2 mismatch")
         }
         if bit_arr[4] != 0 {
-            println!("flag bit 4 mismatch")
+            warn!("flag bit 4 mismatch")
         }
         if bit_arr[6] != 1 {
-            println!("flag bit 6 mismatch")
+            warn!("flag bit 6 mismatch")
         }
     }
 
     fn fetch(&mut self)->u8{
-        let opcode = self.read_next_byte();
+        let opcode = self.memory.fetch_opcode(self.PC);
+        self.PC = self.PC.wrapping_add(1);
         opcode
     }
 
     fn decode_execute(&mut self, opcode:u8) {
-        // let opcode = self.memory[self.PC as usize];
-        // self.PC = self.PC.wrapping_add(1);
-        let mut opcode_arr:[u8;8] = [0;8];
-        for n in 0..8 {
-            opcode_arr[n] = (opcode & (0b01 << (7-n)))>>(7-n) ;
-        }
-        self.rp = (opcode & 0x30) >> 4;
-        self.ddd = (opcode & 0x38)>> 3;
-        self.sss = opcode & 0x07;
-        self.cc = self.ddd;
-        self.alu = self.ddd;
-        self.n = self.ddd;
-
-        // println!("{:?}", opcode_arr);
-        // std::io::stdout().flush().unwrap();
-
-        match opcode_arr {
-            [0,0,0,0,0,1,1,1]=>self.rlc(),
-            [0,0,0,0,1,1,1,1]=>self.rrc(),
-            [0,0,0,1,0,1,1,1]=>self.ral(),
-            [0,0,0,1,1,1,1,1]=>self.rar(),
-            [0,0,1,0,0,0,1,0]=>self.shld(),
-            [0,0,1,0,0,1,1,1]=>self.daa(),
-            [0,0,1,0,1,0,1,0]=>self.lhld(),
-            [0,0,1,0,1,1,1,1]=>self.cma(),
-            [0,0,1,1,0,0,1,0]=>self.sta(),
-            [0,0,1,1,0,1,1,1]=>self.stc(),
-            [0,0,1,1,1,0,1,0]=>self.lda(),
-            [0,0,1,1,1,1,1,1]=>self.cmc(),
-            [0,1,1,1,0,1,1,0]=>self.hlt(),
-            [1,1,0,0,0,0,1,1]=>self.jmp(),
-            [1,1,0,0,1,0,1,1]=>self.jmp(), // alternative
-            [1,1,0,0,1,0,0,1]=>self.ret(),
-            [1,1,0,1,1,0,0,1]=>self.ret(), // alternative
-            [1,1,0,0,1,1,0,1]=>self.call(),
-            [1,1,0,1,1,1,0,1]=>self.call(), // alternative
-            [1,1,1,0,1,1,0,1]=>self.call(), // alternative
-            [1,1,1,1,1,1,0,1]=>self.call(), // alternative
-            [1,1,0,1,0,0,1,1]=>self.out_port(),
-            [1,1,0,1,1,0,1,1]=>self.in_port(),
-            [1,1,1,0,0,0,1,1]=>self.xthl(),
-            [1,1,1,0,1,0,0,1]=>self.pchl(),
-            [1,1,1,0,1,0,1,1]=>self.xchg(),
-            [1,1,1,1,0,0,1,1]=>self.di(),
-            [1,1,1,1,1,0,0,1]=>self.sphl(),
-            [1,1,1,1,1,0,1,1]=>self.ei(),
-
-            [0,0,_,_,0,0,0,0] => self.nop(),
-            [0,0,_,_,1,0,0,0] => self.nop(), // alternative
-            [0,0,r1,r0,0,0,0,1] => self.lxi(r1,r0),
-            [0,0,r1,r0,0,0,1,0]=>self.stax(r1,r0),
-            [0,0,r1,r0,0,0,1,1]=>self.inx(r1,r0),
-            [0,0,d2,d1,d0,1,0,0]=>self.inr(d2,d1,d0),
-            [0,0,d2,d1,d0,1,0,1]=>self.dcr(d2,d1,d0),
-            [0,0,d2,d1,d0,1,1,0]=>self.mvi(d2,d1,d0),
-            [0,0,r1,r0,1,0,0,1]=>self.dad(r1,r0),
-            [0,0,r1,r0,1,0,1,0]=>self.ldax(r1,r0),
-            [0,0,r1,r0,1,0,1,1]=>self.dcx(r1,r0),
-            [0,1,d2,d1,d0,s2,s1,s0]=>self.mov(d2,d1,d0,s2,s1,s0),
-            [1,0,alu2,alu1,alu0,s2,s1,s0]=>self.aluop1(alu2,alu1,alu0,s2,s1,s0),
-            [1,1,c2,c1,c0,0,0,0]=>self.rcc(c2,c1,c0),
-            [1,1,r1,r0,0,0,0,1]=>self.pop(r1,r0),
-            [1,1,c2,c1,c0,0,1,0]=>self.jcc(c2,c1,c0),
-            [1,1,c2,c1,c0,1,0,0]=>self.ccc(c2,c1,c0),
-            [1,1,r1,r0,0,1,0,1]=>self.push(r1,r0),
-            [1,1,alu2,alu1,alu0,1,1,0]=>self.aluop2(alu2,alu1,alu0),
-            [1,1,n2,n1,n0,1,1,1]=>self.rst(n2,n1,n0),
-            _ => {
-                println!("invalid opcode: {:#b} {:#x} ", opcode, opcode);
-            }
+        self.execute(OPCODES[opcode as usize])
+    }
+
+    fn execute(&mut self, op: Op) {
+        self.total_instructions += 1;
+        match op {
+            Op::Nop => self.nop(),
+            Op::Lxi(rp) => self.lxi(rp),
+            Op::Stax(rp) => self.stax(rp),
+            Op::Inx(rp) => self.inx(rp),
+            Op::Inr(ddd) => self.inr(ddd),
+            Op::Dcr(ddd) => self.dcr(ddd),
+            Op::Mvi(ddd) => self.mvi(ddd),
+            Op::Dad(rp) => self.dad(rp),
+            Op::Ldax(rp) => self.ldax(rp),
+            Op::Dcx(rp) => self.dcx(rp),
+            Op::Rlc => self.rlc(),
+            Op::Rrc => self.rrc(),
+            Op::Ral => self.ral(),
+            Op::Rar => self.rar(),
+            Op::Shld => self.shld(),
+            Op::Daa => self.daa(),
+            Op::Lhld => self.lhld(),
+            Op::Cma => self.cma(),
+            Op::Sta => self.sta(),
+            Op::Stc => self.stc(),
+            Op::Lda => self.lda(),
+            Op::Cmc => self.cmc(),
+            Op::Mov(ddd, sss) => self.mov(ddd, sss),
+            Op::Hlt => self.hlt(),
+            Op::Alu(alu, sss) => self.aluop1(alu, sss),
+            Op::Rcc(cc) => self.rcc(cc),
+            Op::Pop(rp) => self.pop(rp),
+            Op::Jcc(cc) => self.jcc(cc),
+            Op::Jmp => self.jmp(),
+            Op::Ccc(cc) => self.ccc(cc),
+            Op::Push(rp) => self.push(rp),
+            Op::AluImmediate(alu) => self.aluop2(alu),
+            Op::Rst(n) => self.rst(n),
+            Op::Ret => self.ret(),
+            Op::Call => self.call(),
+            Op::Out => self.out_port(),
+            Op::In => self.in_port(),
+            Op::Xthl => self.xthl(),
+            Op::Pchl => self.pchl(),
+            Op::Xchg => self.xchg(),
+            Op::Di => self.di(),
+            Op::Sphl => self.sphl(),
+            Op::Ei => self.ei(),
         }
     }
 
@@ -515,12 +944,12 @@ impl Intel8080 {
         self.ticks += 4;
     }
 
-    fn lxi(&mut self, r1:u8, r0:u8) {
+    fn lxi(&mut self, rp:u8) {
         self.ticks +=10;
         let datalo = self.read_next_byte();
         let datahi = self.read_next_byte();
         let data = u16::from_le_bytes([datalo,datahi]);
-        match self.rp {
+        match rp {
             0=>{
                 self.write_bc(data);
             }
@@ -539,18 +968,18 @@ impl Intel8080 {
         }
     }
 
-    fn stax(&mut self,r1:u8, r0:u8) {
+    fn stax(&mut self, rp:u8) {
         self.ticks += 7;
-        match self.rp {
-            0=>self.memory[self.get_bc() as usize] = self.registers.A,
-            1=>self.memory[self.get_de() as usize] = self.registers.A,
+        match rp {
+            0=>self.write_byte(self.get_bc(), self.registers.A),
+            1=>self.write_byte(self.get_de(), self.registers.A),
             _=>{}
         }
     }
 
-    fn inx(&mut self, r1:u8, r0:u8) {
+    fn inx(&mut self, rp:u8) {
         self.ticks += 5;
-        match self.rp {
+        match rp {
             0=>{
                 let result = self.get_bc().wrapping_add(1);
                 self.write_bc(result);
@@ -572,9 +1001,9 @@ impl Intel8080 {
         }
     }
 
-    fn inr(&mut self,d2:u8, d1:u8,d0:u8) {
+    fn inr(&mut self, ddd:u8) {
         self.ticks += 5;
-        match self.ddd {
+        match ddd {
             0=>self.registers.B = self.add_szap(self.registers.B,1),
             1=>self.registers.C = self.add_szap(self.registers.C,1),
             2=>self.registers.D = self.add_szap(self.registers.D,1),
@@ -583,16 +1012,17 @@ impl Intel8080 {
             5=>self.registers.L = self.add_szap(self.registers.L,1),
             6=>{
                 self.ticks += 5;
-                let sum:u8 = self.add_szap(self.get_m(),1);
+                let m = self.get_m();
+                let sum:u8 = self.add_szap(m,1);
                 self.write_m(sum);
             },
             7=>self.registers.A = self.add_szap(self.registers.A,1),
-            _ => {println!("invalid inr operation:"); }
+            _ => {warn!("invalid inr operation:"); }
         }
     }
-    fn dcr(&mut self,d2:u8, d1:u8,d0:u8) {
+    fn dcr(&mut self, ddd:u8) {
         self.ticks += 5;
-        match self.ddd {
+        match ddd {
             0=>self.registers.B = self.sub_szap(self.registers.B,1),
             1=>self.registers.C = self.sub_szap(self.registers.C,1),
             2=>self.registers.D = self.sub_szap(self.registers.D,1),
@@ -601,18 +1031,19 @@ impl Intel8080 {
             5=>self.registers.L = self.sub_szap(self.registers.L,1),
             6=>{
                 self.ticks += 5;
-                let diff:u8 = self.sub_szap(self.get_m(),1);
+                let m = self.get_m();
+                let diff:u8 = self.sub_szap(m,1);
                 self.write_m(diff);
             },
             7=>self.registers.A = self.sub_szap(self.registers.A,1),
             _ => {}
         }
     }
-    fn mvi(&mut self,d2:u8,d1:u8,d0:u8) {
+    fn mvi(&mut self, ddd:u8) {
         self.ticks += 7;
         let data:u8 = self.read_next_byte();
 
-        match self.ddd {
+        match ddd {
             0=>self.registers.B = data,
             1=>self.registers.C = data,
             2=>self.registers.D = data,
@@ -627,42 +1058,38 @@ impl Intel8080 {
             _ => {}
         }
     }
-    fn dad(&mut self,r1:u8,r0:u8) {
+    fn dad(&mut self, rp:u8) {
         self.ticks += 10;
 
         let hl:u16 = self.get_hl();
         let result: u16;
-        match self.rp {
+        match rp {
             0=>result = hl.wrapping_add(self.get_bc()),
             1=>result = hl.wrapping_add(self.get_de()),
             2=>result = hl.wrapping_add(self.get_hl()),
             3=>result = hl.wrapping_add(self.SP),
             _ => {result=0}
         }
-        if (result < hl) {
-            self.registers.Flags |= 1;
-        } else {
-            self.registers.Flags &= !1;
-        }
+        self.write_c(result < hl);
         self.write_hl(result);
     }
-    fn ldax(&mut self,r1:u8,r0:u8) {
+    fn ldax(&mut self, rp:u8) {
         self.ticks += 7;
-        match self.rp {
+        match rp {
             0=>{
                 let bc = self.get_bc();
-                self.registers.A = self.memory[bc as usize];
+                self.registers.A = self.read_byte(bc);
             }
             1=>{
                 let de = self.get_de();
-                self.registers.A = self.memory[de as usize];
+                self.registers.A = self.read_byte(de);
             }
             _ => {}
         }
     }
-    fn dcx(&mut self,r1:u8,r0:u8) {
+    fn dcx(&mut self, rp:u8) {
         self.ticks += 5;
-        match self.rp {
+        match rp {
             0=>{
                 let result = self.get_bc().wrapping_sub(1);
                 self.write_bc(result);
@@ -689,11 +1116,7 @@ impl Intel8080 {
         let first_bit:u8 = (self.registers.A & 0x80) >> 7;
         self.registers.A = self.registers.A << 1;
         self.registers.A |= first_bit;
-        if first_bit == 0x01 {
-            self.registers.Flags |= 0x01;
-        }else {
-            self.registers.Flags &= !0x01;
-        }
+        self.write_c(first_bit == 0x01);
     }
     
     fn rrc(&mut self) {
@@ -701,11 +1124,7 @@ impl Intel8080 {
         let first_bit:u8 = self.registers.A & 0x01;
         self.registers.A = self.registers.A >> 1;
         self.registers.A |= first_bit<<7;
-        if first_bit == 0x01 {
-            self.registers.Flags |= 0x01;
-        }else {
-            self.registers.Flags &= !0x01;
-        }
+        self.write_c(first_bit == 0x01);
     }
     
     fn ral(&mut self) {
@@ -713,14 +1132,10 @@ impl Intel8080 {
         let first_bit:u8 = (self.registers.A & 0x80) >> 7;
         self.registers.A = self.registers.A << 1;
 
-        let carry:u8 = self.registers.Flags & 0x01;
+        let carry:u8 = self.get_c() as u8;
         self.registers.A |= carry;
 
-        if first_bit == 0x01 {
-            self.registers.Flags |= 0x01;
-        }else {
-            self.registers.Flags &= !0x01;
-        }
+        self.write_c(first_bit == 0x01);
     }
     
     fn rar(&mut self) {
@@ -728,28 +1143,23 @@ impl Intel8080 {
         let first_bit:u8 = self.registers.A & 0x01;
         self.registers.A = self.registers.A >> 1;
 
-        let carry:u8 = self.registers.Flags & 0x01;
+        let carry:u8 = self.get_c() as u8;
         self.registers.A |= carry<<7;
 
-        if first_bit == 0x01 {
-            self.registers.Flags |= 0x01;
-        }else {
-            self.registers.Flags &= !0x01;
-        }
+        self.write_c(first_bit == 0x01);
     }
     fn shld(&mut self) {
         self.ticks += 16;
         let addlo = self.read_next_byte();
         let addhi = self.read_next_byte();
-        self.memory[((addhi as usize) << 8) + (addlo as usize)] = self.registers.L;
-        self.memory[((addhi as usize) << 8) + (addlo as usize) + 1] = self.registers.H;
+        self.write_word(u16::from_le_bytes([addlo, addhi]), self.get_hl());
     }
 
     // TODO need fix
     fn daa(&mut self) {
         self.ticks += 4;
         let A = self.registers.A;
-        let AC = (self.registers.Flags & 0b00010000)>>4;
+        let AC = self.get_a() as u8;
         let mut add = 0;
         let msb = (self.registers.A)>>4;
         let lsb = A & 0b00001111;
@@ -757,10 +1167,10 @@ impl Intel8080 {
             add = 6;
         }
         
-        let C = self.registers.Flags & 0x01;
+        let C = self.get_c() as u8;
         if msb > 9 || C == 1 || (msb >= 9 && lsb>9) {
             add += 0x60;
-            self.registers.Flags |= 0x01;
+            self.write_c(true);
         }
         self.registers.A = self.add_szap(self.registers.A, add);
     }
@@ -768,8 +1178,8 @@ impl Intel8080 {
         self.ticks += 16;
         let addlo = self.read_next_byte();
         let addhi = self.read_next_byte();
-        self.registers.L = self.memory[((addhi as usize) << 8) + (addlo as usize)] ;
-        self.registers.H = self.memory[((addhi as usize) << 8) + (addlo as usize) + 1];
+        let data = self.read_word(u16::from_le_bytes([addlo, addhi]));
+        self.write_hl(data);
     }
 
     // TODO need fix
@@ -781,144 +1191,124 @@ impl Intel8080 {
         self.ticks += 13;
         let addlo = self.read_next_byte();
         let addhi = self.read_next_byte();
-        self.memory[((addhi as usize) << 8) + (addlo as usize)] = self.registers.A;
+        self.write_byte(u16::from_le_bytes([addlo, addhi]), self.registers.A);
     }
 
     // TODO need fix
     fn stc(&mut self) {
         self.ticks += 4;
-        self.registers.Flags |= 0x01;
+        self.write_c(true);
     }
     fn lda(&mut self) {
         self.ticks += 13;
         let addlo = self.read_next_byte();
         let addhi = self.read_next_byte();
-        self.registers.A = self.memory[((addhi as usize) << 8) + (addlo as usize)] ;
+        self.registers.A = self.read_byte(u16::from_le_bytes([addlo, addhi]));
     }
 
     fn cmc(&mut self) {
         self.ticks += 4;
-        if self.registers.Flags & 0x01 == 1 {
-            self.registers.Flags &= !0x01;
-        } else {
-            self.registers.Flags |= 0x01;
-        }
+        let carry = self.get_c();
+        self.write_c(!carry);
     }
-    fn mov(&mut self,d2:u8,d1:u8,d0:u8,s2:u8,s1:u8,s0:u8) {
+    fn mov(&mut self, ddd:u8, sss:u8) {
         self.ticks += 5;
         let reg_val:u8;
-        match (s2,s1,s0) {
-            (0,0,0)=>reg_val=self.registers.B,
-            (0,0,1)=>reg_val=self.registers.C,
-            (0,1,0)=>reg_val=self.registers.D,
-            (0,1,1)=>reg_val=self.registers.E,
-            (1,0,0)=>reg_val=self.registers.H,
-            (1,0,1)=>reg_val=self.registers.L,
-            (1,1,0)=>{reg_val=self.get_m();self.ticks += 2;},
-            (1,1,1)=>reg_val=self.registers.A,
+        match sss {
+            0=>reg_val=self.registers.B,
+            1=>reg_val=self.registers.C,
+            2=>reg_val=self.registers.D,
+            3=>reg_val=self.registers.E,
+            4=>reg_val=self.registers.H,
+            5=>reg_val=self.registers.L,
+            6=>{reg_val=self.get_m();self.ticks += 2;},
+            7=>reg_val=self.registers.A,
             _ => {reg_val=0}
         }
-        match (d2,d1,d0) {
-            (0,0,0)=>self.registers.B=reg_val,
-            (0,0,1)=>self.registers.C=reg_val,
-            (0,1,0)=>self.registers.D=reg_val,
-            (0,1,1)=>self.registers.E=reg_val,
-            (1,0,0)=>self.registers.H=reg_val,
-            (1,0,1)=>self.registers.L=reg_val,
-            (1,1,0)=>{self.write_m(reg_val);self.ticks +=2;},
-            (1,1,1)=>self.registers.A=reg_val,
+        match ddd {
+            0=>self.registers.B=reg_val,
+            1=>self.registers.C=reg_val,
+            2=>self.registers.D=reg_val,
+            3=>self.registers.E=reg_val,
+            4=>self.registers.H=reg_val,
+            5=>self.registers.L=reg_val,
+            6=>{self.write_m(reg_val);self.ticks +=2;},
+            7=>self.registers.A=reg_val,
             _ => {}
         }
     }
     fn hlt(&mut self) {
-        print!("Halt");
         self.ticks += 7;
-        self.PC = self.PC.wrapping_sub(1);
+        self.halted = true;
     }
 
     // TODO need fix
-    fn aluop1(&mut self,alu2:u8,alu1:u8,alu0:u8,s2:u8,s1:u8,s0:u8) {
+    fn aluop1(&mut self, alu:u8, sss:u8) {
         self.ticks += 4;
         let a_val:u8 = self.registers.A;
         let reg_val:u8;
-        match (s2,s1,s0) {
-            (0,0,0)=>reg_val=self.registers.B,
-            (0,0,1)=>reg_val=self.registers.C,
-            (0,1,0)=>reg_val=self.registers.D,
-            (0,1,1)=>reg_val=self.registers.E,
-            (1,0,0)=>reg_val=self.registers.H,
-            (1,0,1)=>reg_val=self.registers.L,
-            (1,1,0)=>{reg_val=self.get_m();self.ticks += 3;},
-            (1,1,1)=>reg_val=self.registers.A,
+        match sss {
+            0=>reg_val=self.registers.B,
+            1=>reg_val=self.registers.C,
+            2=>reg_val=self.registers.D,
+            3=>reg_val=self.registers.E,
+            4=>reg_val=self.registers.H,
+            5=>reg_val=self.registers.L,
+            6=>{reg_val=self.get_m();self.ticks += 3;},
+            7=>reg_val=self.registers.A,
             _ => {reg_val=0}
         }
-        match (alu2,alu1,alu0) {
-            (0,0,0)=>self.registers.A=self.add_szapc(a_val,reg_val),
-            (0,0,1)=>{
-                self.registers.A=self.add_3_szapc(a_val,reg_val,self.registers.Flags & 0x01);
+        match alu {
+            0=>self.registers.A=self.add_szapc(a_val,reg_val),
+            1=>{
+                self.registers.A=self.add_3_szapc(a_val,reg_val,self.get_c() as u8);
             },
-            (0,1,0)=>{
+            2=>{
                 self.registers.A=self.add_3_szapc(a_val,!reg_val,1);
                 self.write_c(reg_val as u16 > a_val as u16) // overwrite c,
             },
-            (0,1,1)=>{
+            3=>{
                 let complement = (!reg_val); // 2s complement
-                let carry = self.registers.Flags & 0x01;
+                let carry = self.get_c() as u8;
                 self.registers.A=self.add_3_szapc(a_val,complement,if carry == 1 { 0 } else { 1 });
                 self.write_c(reg_val as u16 + (carry as u16) > a_val as u16);
             },
-            (1,0,0)=>{
-                self.set_szp(a_val&reg_val);
-                self.registers.Flags&=0b11101110;
-                if (((self.registers.A | reg_val) & 0x08) != 0){
-                    self.registers.Flags |= 0b00010000;
-                } else {
-                    self.registers.Flags &= !0b00010000;
-                }
-                self.registers.A=a_val&reg_val;
-            },
-            (1,0,1)=>{
-                self.registers.A=a_val^reg_val;
-                self.set_szp(a_val^reg_val);
-                self.registers.Flags&=0b11101110
+            4=>{
+                self.registers.A=self.set_logic(a_val&reg_val, (a_val | reg_val) & 0x08 != 0);
             },
-            (1,1,0)=>{
-                self.registers.A=a_val|reg_val;
-                self.set_szp(a_val|reg_val);
-                self.registers.Flags&=0b11101110
-            },
-            (1,1,1)=>{
+            5=>self.registers.A=self.set_logic(a_val^reg_val, false),
+            6=>self.registers.A=self.set_logic(a_val|reg_val, false),
+            7=>{
                 self.add_3_szapc(a_val,!reg_val,1);
                 self.write_c(reg_val as u16 > a_val as u16); // overwrite c
             },
             _ => {}
         }
     }
-    fn rcc(&mut self,c2:u8,c1:u8,c0:u8) {
+    fn rcc(&mut self, cc:u8) {
         self.ticks += 5;
         let condition:bool;
-        match (c2,c1,c0) {
-            (0,0,0)=>condition = !self.get_z(),
-            (0,0,1)=>condition = self.get_z(),
-            (0,1,0)=>condition = !self.get_c(),
-            (0,1,1)=>condition = self.get_c(),
-            (1,0,0)=>condition = self.get_p()==false,
-            (1,0,1)=>condition = self.get_p()==true,
-            (1,1,0)=>condition = self.get_p(),
-            (1,1,1)=>condition = self.get_s()==true,
+        match cc {
+            0=>condition = !self.get_z(),
+            1=>condition = self.get_z(),
+            2=>condition = !self.get_c(),
+            3=>condition = self.get_c(),
+            4=>condition = self.get_p()==false,
+            5=>condition = self.get_p()==true,
+            6=>condition = self.get_s()==false,
+            7=>condition = self.get_s()==true,
             _ => {condition=false;}
         }
         if condition {
             self.ticks += 6;
-            self.PC=(self.memory[self.SP as usize] as u16) + ((self.memory[(self.SP+1) as usize]as u16)<<8 );
+            self.PC=self.read_word(self.SP);
             self.SP = self.SP.wrapping_add(2);
         }
     }
-    fn pop(&mut self,r1:u8,r0:u8) {
+    fn pop(&mut self, rp:u8) {
         self.ticks += 10;
-        let cur_sp_hi = self.memory[(self.SP+1) as usize];
-        let cur_sp_lo = self.memory[self.SP as usize];
-        match self.rp {
+        let [cur_sp_lo, cur_sp_hi] = self.read_word(self.SP).to_le_bytes();
+        match rp {
             0=>{
                 self.registers.B = cur_sp_hi;
                 self.registers.C = cur_sp_lo;
@@ -933,7 +1323,7 @@ impl Intel8080 {
             }
             3=>{
                 self.registers.A = cur_sp_hi;
-                self.registers.Flags = cur_sp_lo & 0b11010111 | 0b00000010;
+                self.registers.Flags.set(cur_sp_lo & 0b11010111 | 0b00000010);
             }
             _ => {
                 return;
@@ -941,21 +1331,21 @@ impl Intel8080 {
         }
         self.SP = self.SP.wrapping_add(2);
     }
-    fn jcc(&mut self,c2:u8,c1:u8,c0:u8) {
+    fn jcc(&mut self, cc:u8) {
         self.ticks += 10;
         let addlo = self.read_next_byte();
         let addhi = self.read_next_byte();
 
         let condition:bool;
-        match (c2,c1,c0) {
-            (0,0,0)=>condition = !self.get_z(),
-            (0,0,1)=>condition = self.get_z(),
-            (0,1,0)=>condition = !self.get_c(),
-            (0,1,1)=>condition = self.get_c(),
-            (1,0,0)=>condition = self.get_p()==false,
-            (1,0,1)=>condition = self.get_p()==true,
-            (1,1,0)=>condition = self.get_p(),
-            (1,1,1)=>condition = self.get_s()==true,
+        match cc {
+            0=>condition = !self.get_z(),
+            1=>condition = self.get_z(),
+            2=>condition = !self.get_c(),
+            3=>condition = self.get_c(),
+            4=>condition = self.get_p()==false,
+            5=>condition = self.get_p()==true,
+            6=>condition = self.get_s()==false,
+            7=>condition = self.get_s()==true,
             _ => {condition=false;}
         }
         if condition {
@@ -968,51 +1358,38 @@ impl Intel8080 {
         let addhi = self.read_next_byte();
         self.PC = (((addhi as usize) << 8) + (addlo as usize)) as u16;
     }
-    fn ccc(&mut self,c2:u8,c1:u8,c0:u8) {
+    fn ccc(&mut self, cc:u8) {
         self.ticks += 11;
         let addlo = self.read_next_byte();
         let addhi = self.read_next_byte();
 
         let condition:bool;
-        match (c2,c1,c0) {
-            (0,0,0)=>condition = !self.get_z(),
-            (0,0,1)=>condition = self.get_z(),
-            (0,1,0)=>condition = !self.get_c(),
-            (0,1,1)=>condition = self.get_c(),
-            (1,0,0)=>condition = self.get_p()==false,
-            (1,0,1)=>condition = self.get_p()==true,
-            (1,1,0)=>condition = self.get_p(),
-            (1,1,1)=>condition = self.get_s()==true,
+        match cc {
+            0=>condition = !self.get_z(),
+            1=>condition = self.get_z(),
+            2=>condition = !self.get_c(),
+            3=>condition = self.get_c(),
+            4=>condition = self.get_p()==false,
+            5=>condition = self.get_p()==true,
+            6=>condition = self.get_s()==false,
+            7=>condition = self.get_s()==true,
             _ => {condition=false}
         }
         if condition {
             self.ticks += 6;
-            self.SP-=2;
-            self.memory[(self.SP) as usize]= (self.PC & 0x00FF) as u8;
-            self.memory[(self.SP+1) as usize]= ((self.PC & 0xFF00) >> 8) as u8;
+            self.SP = self.SP.wrapping_sub(2);
+            self.write_word(self.SP, self.PC);
             self.PC = ((addhi as u16)<<8) + addlo as u16;
         }
     }
-    fn push(&mut self,r1:u8,r0:u8) {
+    fn push(&mut self, rp:u8) {
         self.ticks += 11;
-        self.SP-=2;
-        match self.rp {
-            0=>{
-                self.memory[self.SP as usize] = self.registers.C;
-                self.memory[(self.SP+1) as usize] = self.registers.B;
-            }
-            1=>{
-                self.memory[self.SP as usize] = self.registers.E;
-                self.memory[(self.SP+1) as usize] = self.registers.D;
-            }
-            2=>{
-                self.memory[self.SP as usize] = self.registers.L;
-                self.memory[(self.SP+1) as usize] = self.registers.H;
-            }
-            3=>{
-                self.memory[self.SP as usize] = self.registers.Flags;
-                self.memory[(self.SP+1) as usize] = self.registers.A;
-            }
+        self.SP = self.SP.wrapping_sub(2);
+        match rp {
+            0=>self.write_word(self.SP, self.get_bc()),
+            1=>self.write_word(self.SP, self.get_de()),
+            2=>self.write_word(self.SP, self.get_hl()),
+            3=>self.write_word(self.SP, self.get_psw()),
             _ => {
                 return;
             }
@@ -1020,79 +1397,46 @@ impl Intel8080 {
     }
 
     // TODO need fix
-    fn aluop2(&mut self,alu2:u8,alu1:u8,alu0:u8) {
+    fn aluop2(&mut self, alu:u8) {
         self.ticks += 7;
         let a_val = self.registers.A;
         let data_val = self.read_next_byte();
-        match (alu2,alu1,alu0) {
-            (0,0,0)=>self.registers.A=self.add_szapc(a_val,data_val),
-            (0,0,1)=>{
-                self.registers.A=self.add_3_szapc(a_val,data_val,self.registers.Flags & 0x01);
+        match alu {
+            0=>self.registers.A=self.add_szapc(a_val,data_val),
+            1=>{
+                self.registers.A=self.add_3_szapc(a_val,data_val,self.get_c() as u8);
             },
-            (0,1,0)=>{
+            2=>{
                 self.registers.A=self.add_3_szapc(a_val,!data_val,1);
                 self.write_c(data_val as u16 > a_val as u16); // overwrite c
             },
-            (0,1,1)=>{
-                // let sum = data_val.wrapping_add((self.registers.Flags & 0x01) as u8);
+            3=>{
                 let complement = (!data_val); // 2s complement
-                let carry = self.registers.Flags & 0x01;
-                // let flags = self.registers.Flags;
+                let carry = self.get_c() as u8;
                 self.registers.A=self.add_3_szapc(a_val,complement,if carry == 1 { 0 } else { 1 });
-                // let mysum = self.add_3_szapc(a_val,complement,if carry == 1 { 0 } else { 1 });
                 self.write_c(data_val as u16 + (carry as u16) > a_val as u16);
-                // let myflags = self.registers.Flags;
-                // self.registers.Flags = flags;
-                
-                
-                // let c = self.registers.Flags & 0x01;
-                // let r = a_val.wrapping_sub(data_val).wrapping_sub(c);
-                // 
-                // 
-                // self.set_szp(r);
-                // self.write_a((a_val as i8 & 0x0f) - (data_val as i8 & 0x0f) - (c as i8) >= 0x00);
-                // self.write_c(u16::from(a_val) < u16::from(data_val) + u16::from(c));
-                // self.registers.A = r;
-                // 
-                // if (mysum!= r || myflags != self.registers.Flags) {
-                //     println!("mysum {mysum} result {r} at a_val {a_val} and data_val {data_val} myflags {myflags} flags {}", self.registers.Flags);
-                // }
             },
-            (1,0,0)=>{
-                // self.registers.A=a_val&data_val;self.set_szp(a_val&data_val);self.registers.Flags&=0b11101110; // use same behaviour as ANA
-
-                self.set_szp(a_val&data_val);
-                self.registers.Flags&=0b11101110;
-                if (((self.registers.A | data_val) & 0x08) != 0){
-                    self.registers.Flags |= 0b00010000;
-                } else {
-                    self.registers.Flags &= !0b00010000;
-                }
-                self.registers.A=a_val&data_val;
+            4=>{
+                self.registers.A=self.set_logic(a_val&data_val, (a_val | data_val) & 0x08 != 0);
             },
-            (1,0,1)=>{self.registers.A=a_val^data_val;self.set_szp(a_val^data_val);self.registers.Flags&=0b11101110},
-            (1,1,0)=>{self.registers.A=a_val|data_val;self.set_szp(a_val|data_val);self.registers.Flags&=0b11101110},
-            (1,1,1)=>{
+            5=>self.registers.A=self.set_logic(a_val^data_val, false),
+            6=>self.registers.A=self.set_logic(a_val|data_val, false),
+            7=>{
                 self.add_3_szapc(a_val,!data_val,1);
                 self.write_c(data_val as u16 > a_val as u16); // overwrite c
-                // let r = a_val.wrapping_sub(data_val);
-                // self.set_szp(r);
-                // self.write_a((a_val as i8 & 0x0f) - (data_val as i8 & 0x0f) >= 0x00);
-                // self.write_c(u16::from(a_val) < u16::from(data_val));
             },
             _ => {}
         }
     }
-    fn rst(&mut self,n2:u8,n1:u8,n0:u8) {
+    fn rst(&mut self, n:u8) {
         self.ticks += 11;
         self.SP = self.SP.wrapping_sub(2);
-        self.memory[self.SP as usize] = (self.PC & 0x00FF) as u8;
-        self.memory[(self.SP+1) as usize] = ((self.PC & 0xFF00)>>8) as u8;
-        self.PC=(((n2<<2)+(n1<<1)+n0) * 8)as u16;
+        self.write_word(self.SP, self.PC);
+        self.PC=(n as u16) * 8;
     }
     fn ret(&mut self) {
         self.ticks += 10;
-        self.PC=(self.memory[self.SP as usize] as u16) + ((self.memory[(self.SP+1) as usize]as u16)<<8 );
+        self.PC=self.read_word(self.SP);
         self.SP=self.SP.wrapping_add(2);
     }
     fn call(&mut self) {
@@ -1101,8 +1445,7 @@ impl Intel8080 {
         let addhi = self.read_next_byte();
 
         self.SP= self.SP.wrapping_sub(2);
-        self.memory[self.SP as usize]= (self.PC & 0x00FF) as u8;
-        self.memory[(self.SP+1) as usize]= ((self.PC & 0xFF00) >> 8) as u8;
+        self.write_word(self.SP, self.PC);
 
         self.PC=((addhi as u16) << 8) + addlo as u16;
 
@@ -1110,22 +1453,26 @@ impl Intel8080 {
     fn out_port(&mut self) {
         self.ticks += 10;
         let port = self.read_next_byte();
-        self.oport.push((port,self.registers.A));
+        if let Some(log) = &mut self.accesses {
+            log.push(Access::Output(port, self.registers.A));
+        }
+        self.io.output(port, self.registers.A);
     }
     fn in_port(&mut self) {
         self.ticks += 10;
         let port = self.read_next_byte();
-        // println!("Port: {} data: {} pc: {}",port,self.iport[port as usize],self.PC);
-        self.registers.A = self.iport[port as usize];
+        self.registers.A = self.io.input(port);
+        if let Some(log) = &mut self.accesses {
+            log.push(Access::Input(port, self.registers.A));
+        }
     }
     fn xthl(&mut self) {
         self.ticks += 18;
         let temph = self.registers.H;
         let templ = self.registers.L;
-        self.registers.L=self.memory[self.SP as usize];
-        self.registers.H=self.memory[(self.SP+1) as usize];
-        self.memory[self.SP as usize] = templ;
-        self.memory[(self.SP+1) as usize] = temph;
+        let data = self.read_word(self.SP);
+        self.write_hl(data);
+        self.write_word(self.SP, u16::from_le_bytes([templ, temph]));
     }
     fn pchl(&mut self) {
         self.ticks += 5;
@@ -1151,6 +1498,26 @@ impl Intel8080 {
     fn ei(&mut self) {
         self.ticks += 4;
         self.interrupt_enabled = true;
+        self.ei_delay = true;
+    }
+}
+
+impl<M: MemoryBus + Snapshot, IO: IOHandler + Snapshot> Snapshot for Intel8080<M, IO> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.state().to_bytes());
+        self.interrupts.save_state(writer);
+        self.memory.save_state(writer);
+        self.io.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let state = CpuState::from_bytes(reader.read_bytes(CpuState::SIZE)?)
+            .ok_or(SaveStateError::Corrupt("CPU state"))?;
+        self.set_state(&state);
+        self.interrupts.load_state(reader)?;
+        self.memory.load_state(reader)?;
+        self.flush_block_cache();
+        self.io.load_state(reader)
     }
 }
 
@@ -1160,14 +1527,14 @@ mod tests {
 
     const Init_Flag:u8 = 0b00000010;
 
-    fn load_program(intel8080: & mut Intel8080, prog: Vec<u8>){
+    fn load_program<M: MemoryBus, IO: IOHandler>(intel8080: & mut Intel8080<M, IO>, prog: Vec<u8>){
         intel8080.load_program(prog);
     }
 
     #[track_caller]
-    fn compare_registers(intel8080: & Intel8080,a:u8,f:u8,b:u8,c:u8,d:u8,e:u8,h:u8,l:u8){
+    fn compare_registers<M: MemoryBus, IO: IOHandler>(intel8080: & Intel8080<M, IO>,a:u8,f:u8,b:u8,c:u8,d:u8,e:u8,h:u8,l:u8){
         assert_eq!(intel8080.registers.A, a, "The expected result in register A is {} but got {}",a,intel8080.registers.A);
-        assert_eq!(intel8080.registers.Flags, f,"The expected result in register Flags is {} but got {}",f,intel8080.registers.Flags);
+        assert_eq!(intel8080.registers.Flags.get(), f,"The expected result in register Flags is {} but got {}",f,intel8080.registers.Flags.get());
         assert_eq!(intel8080.registers.C, c,"The expected result in register C is {} but got {}",c,intel8080.registers.C);
         assert_eq!(intel8080.registers.D, d,"The expected result in register D is {} but got {}",d,intel8080.registers.D);
         assert_eq!(intel8080.registers.E, e,"The expected result in register E is {} but got {}",e,intel8080.registers.E);
@@ -1185,6 +1552,93 @@ mod tests {
         let intel8080 = Intel8080::new();
         assert!(intel8080.SP==0);
     }
+    #[test]
+    fn run_cycles(){
+        let mut i0 = Intel8080::new();
+        // NOPs take 4 states each, so asking for 10 runs three of them
+        assert_eq!(i0.run_cycles(10), Ok(12));
+        assert_eq!(i0.total_ticks, 12);
+        assert_eq!(i0.PC, 3);
+        assert_eq!(i0.run_cycles(0), Ok(0));
+    }
+
+    // States per opcode from the 8080 datasheet. Conditional returns and calls list the
+    // not-taken count here, see taken_states for the other outcome.
+    const STATES:[usize;256] = [
+    //  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+         4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0x
+         4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 1x
+         4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4, // 2x
+         4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4, // 3x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 4x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 5x
+         5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 6x
+         7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5, // 7x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9x
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Ax
+         4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Bx
+         5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // Cx
+         5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // Dx
+         5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // Ex
+         5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // Fx
+    ];
+
+    fn taken_states(opcode:u8) -> usize {
+        match opcode & 0b11000111 {
+            0b11000000 => 11, // Rcc
+            0b11000100 => 17, // Ccc
+            _ => STATES[opcode as usize],
+        }
+    }
+
+    #[test]
+    fn instruction_states(){
+        // Every condition code is met under one of these flag values and not the other
+        for flags in [Init_Flag, 0b11000111] {
+            for opcode in 0..=255u8 {
+                let mut i0 = Intel8080::new();
+                load_program(&mut i0, vec![opcode]);
+                i0.registers.Flags.set(flags);
+                i0.registers.H = 0x80;
+                i0.SP = 0x8000;
+                i0.cycle();
+
+                let cc = (opcode >> 3) & 0x07;
+                let taken = match cc {
+                    0 => !i0.get_z(),
+                    1 => i0.get_z(),
+                    2 => !i0.get_c(),
+                    3 => i0.get_c(),
+                    4 => !i0.get_p(),
+                    5 => i0.get_p(),
+                    6 => !i0.get_s(),
+                    _ => i0.get_s(),
+                };
+                let expected = if taken { taken_states(opcode) } else { STATES[opcode as usize] };
+                assert_eq!(i0.total_ticks, expected, "opcode {:#04x} with flags {:#010b}", opcode, flags);
+            }
+        }
+    }
+
+    #[test]
+    fn conditional_jumps_follow_sign(){
+        // JP and JM test the sign flag, not parity
+        let mut i0 = Intel8080::new();
+        //                          JP 0x1234
+        load_program(&mut i0, vec![0b11110010, 0x34, 0x12]);
+        i0.registers.Flags.set(Init_Flag | 0b00000100);
+        i0.cycle();
+        assert_eq!(i0.PC, 0x1234);
+
+        let mut i0 = Intel8080::new();
+        //                          JM 0x1234
+        load_program(&mut i0, vec![0b11111010, 0x34, 0x12]);
+        i0.registers.Flags.set(Init_Flag | 0b00000100);
+        i0.cycle();
+        assert_eq!(i0.PC, 0x0003);
+    }
+
     #[test]
     fn nop(){
         let mut intel8080 = Intel8080::new();
@@ -1207,8 +1661,7 @@ mod tests {
         i1.cycle();
         compare_registers(&i1, 0, Init_Flag, 0, 0, 1, 0xFF, 0, 0);
 
-        // register pair hl        i0.cycle();
-
+        // register pair hl
         let mut i2 = Intel8080::new();
         load_program(&mut i2,vec![0b00100001,0xFF,1]);
         i2.cycle();
@@ -1937,29 +2390,51 @@ mod tests {
         compare_registers(&i0, 0, Init_Flag, 0, 0, 0, 0, 0, 0);
     }
 
-    // #[test]
-    // // [1,1,0,1,0,0,1,1] port
-    // fn out_port() {
-    //     let mut i0 = Intel8080::new();
-    //     //                              MVI A 0xde,      OUT 0xba
-    //     load_program(&mut i0, vec![0b00111110,0xde, 0b11010011, 0xba]);
-    //     i0.cycle();
-    //     i0.cycle();
-    //     assert_eq!(i0.oport[0xba],0xde);
-    //     compare_registers(&i0, 0xde, Init_Flag, 0, 0, 0, 0, 0, 0);
-    // }
-    // 
-    // #[test]
-    // // [1,1,0,1,1,0,1,1] port
-    // fn in_port() {
-    //     let mut i0 = Intel8080::new();
-    //     //                         OUT 0xba
-    //     load_program(&mut i0, vec![0b11011011, 0xba]);
-    //     i0.iport[0xba] = 0xde;
-    //     i0.cycle();
-    //     i0.cycle();
-    //     compare_registers(&i0, 0xde, Init_Flag, 0, 0, 0, 0, 0, 0);
-    // }
+    // Records every OUT in order and answers IN from a fixed table
+    struct TestIO {
+        input: [u8; 256],
+        writes: Vec<(u8, u8)>,
+    }
+
+    impl IOHandler for TestIO {
+        fn input(&mut self, port: u8) -> u8 {
+            self.input[port as usize]
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.writes.push((port, value));
+        }
+    }
+
+    fn test_io() -> TestIO {
+        TestIO { input: [0; 256], writes: Vec::new() }
+    }
+
+    #[test]
+    // [1,1,0,1,0,0,1,1] port
+    fn out_port() {
+        let mut i0 = Intel8080::with_io(test_io());
+        //                              MVI A 0xde,      OUT 0xba,      MVI A 0xad,       OUT 0xbb
+        load_program(&mut i0, vec![0b00111110,0xde, 0b11010011, 0xba, 0b00111110, 0xad, 0b11010011, 0xbb]);
+        i0.cycle();
+        i0.cycle();
+        assert_eq!(i0.io.writes, vec![(0xba, 0xde)]);
+        i0.cycle();
+        i0.cycle();
+        assert_eq!(i0.io.writes, vec![(0xba, 0xde), (0xbb, 0xad)]);
+        compare_registers(&i0, 0xad, Init_Flag, 0, 0, 0, 0, 0, 0);
+    }
+
+    #[test]
+    // [1,1,0,1,1,0,1,1] port
+    fn in_port() {
+        let mut i0 = Intel8080::with_io(test_io());
+        //                         IN 0xba
+        load_program(&mut i0, vec![0b11011011, 0xba]);
+        i0.io.input[0xba] = 0xde;
+        i0.cycle();
+        compare_registers(&i0, 0xde, Init_Flag, 0, 0, 0, 0, 0, 0);
+    }
 
     #[test]
     // [1,1,1,0,0,0,1,1]
@@ -2037,4 +2512,471 @@ mod tests {
         assert_eq!(i0.interrupt_enabled, true);
         compare_registers(&i0, 0, Init_Flag, 0, 0, 0, 0, 0, 0);
     }
+
+    #[test]
+    fn interrupt_waits_for_inte() {
+        let mut i0 = Intel8080::new();
+        //                              LXI SP 0xabcd,          DI,         NOP,        EI,         NOP
+        load_program(&mut i0, vec![0b00110001, 0xcd, 0xab, 0b11110011, 0b00000000, 0b11111011, 0b00000000]);
+        i0.cycle();
+        i0.cycle();
+        i0.interrupts.raise(1);
+        i0.interrupts.raise(1);
+        i0.cycle();
+        assert_eq!(i0.PC, 0x0005);
+        assert!(i0.interrupts.is_pending(1));
+        i0.cycle();
+        i0.cycle();
+        // EI, NOP, then RST 1 pushes the address after the NOP
+        assert_eq!(i0.step(), StepResult::InterruptAccepted { vector: InterruptVector::rst(1), cycles: 11 });
+        assert_eq!(i0.PC, 0x0008);
+        assert_eq!(i0.SP, 0xabcd-2);
+        compare_memory(&i0, 0xabcd-2, 0x07);
+        assert!(!i0.interrupts.pending());
+        assert_eq!(i0.interrupt_enabled, false);
+    }
+
+    #[test]
+    fn interrupts_serviced_by_priority() {
+        let mut i0 = Intel8080::new();
+        i0.memory[0x0008] = 0b11111011; // EI at RST 1
+        i0.interrupts.raise(2);
+        i0.interrupts.raise(1);
+        i0.cycle();
+        assert_eq!(i0.PC, 0x0008);
+        i0.cycle();
+        i0.cycle();
+        i0.cycle();
+        // EI, NOP, then RST 2
+        assert_eq!(i0.PC, 0x0010);
+        assert_eq!(i0.total_ticks, 11 + 4 + 4 + 11);
+    }
+
+    #[test]
+    fn interrupt_jams_call() {
+        let mut i0 = Intel8080::new();
+        //                              LXI SP 0xabcd
+        load_program(&mut i0, vec![0b00110001, 0xcd, 0xab]);
+        i0.cycle();
+        i0.interrupts.set_vector(5, InterruptVector::call(0xbeef));
+        i0.interrupts.raise(5);
+        assert_eq!(i0.step(), StepResult::InterruptAccepted { vector: InterruptVector::call(0xbeef), cycles: 17 });
+        // The CALL operands come from the bus, so the return address is the interrupted PC
+        assert_eq!(i0.PC, 0xbeef);
+        assert_eq!(i0.SP, 0xabcd-2);
+        compare_memory(&i0, 0xabcd-2, 0x03);
+        compare_memory(&i0, 0xabcd-1, 0x00);
+    }
+
+    #[test]
+    fn ei_delays_one_instruction() {
+        let mut i0 = Intel8080::new();
+        //                              LXI SP 0xabcd,          DI,         EI,         RET
+        load_program(&mut i0, vec![0b00110001, 0xcd, 0xab, 0b11110011, 0b11111011, 0b11001001]);
+        i0.memory[0xabcd] = 0x34;
+        i0.memory[0xabcd+1] = 0x12;
+        i0.cycle();
+        i0.cycle();
+        i0.interrupts.raise(1);
+        i0.cycle();
+        // The interrupt is held off until RET has run
+        assert_eq!(i0.step(), StepResult::Executed { cycles: 10 });
+        assert_eq!(i0.PC, 0x1234);
+        assert!(i0.interrupts.pending());
+        assert!(matches!(i0.step(), StepResult::InterruptAccepted { .. }));
+        assert_eq!(i0.PC, 0x0008);
+        assert_eq!(i0.SP, 0xabcd);
+        compare_memory(&i0, 0xabcd, 0x34);
+        compare_memory(&i0, 0xabcd+1, 0x12);
+    }
+
+    #[test]
+    fn hlt_idles_until_interrupt() {
+        let mut i0 = Intel8080::new();
+        //                              LXI SP 0xabcd,          HLT
+        load_program(&mut i0, vec![0b00110001, 0xcd, 0xab, 0b01110110]);
+        i0.cycle();
+        assert_eq!(i0.step(), StepResult::Halted { cycles: 7 });
+        assert!(i0.halted);
+        assert_eq!(i0.PC, 0x0004);
+        assert_eq!(i0.step(), StepResult::Halted { cycles: HALT_IDLE_STATES });
+        assert_eq!(i0.step(), StepResult::Halted { cycles: HALT_IDLE_STATES });
+        assert_eq!(i0.PC, 0x0004);
+        assert_eq!(i0.total_ticks, 10 + 7 + 2 * HALT_IDLE_STATES);
+
+        i0.interrupts.raise(7);
+        assert_eq!(i0.step(), StepResult::InterruptAccepted { vector: InterruptVector::rst(7), cycles: 11 });
+        // Woken into RST 7 with the return address past HLT
+        assert!(!i0.halted);
+        assert_eq!(i0.PC, 0x0038);
+        compare_memory(&i0, 0xabcd-2, 0x04);
+    }
+
+    #[test]
+    fn hlt_with_interrupts_disabled_stays_halted() {
+        let mut i0 = Intel8080::new();
+        //                          DI,         HLT
+        load_program(&mut i0, vec![0b11110011, 0b01110110]);
+        i0.cycle();
+        i0.cycle();
+        i0.interrupts.raise(1);
+        assert_eq!(i0.step(), StepResult::Halted { cycles: HALT_IDLE_STATES });
+        assert!(i0.halted);
+        assert_eq!(i0.PC, 0x0002);
+        assert!(i0.interrupts.pending());
+    }
+
+    #[test]
+    fn undocumented_opcode_traps() {
+        // Carries on as the aliased instruction by default
+        let mut i0 = Intel8080::new();
+        //                          JMP (0xCB) 0x1234
+        load_program(&mut i0, vec![0xcb, 0x34, 0x12]);
+        let trap = Trap { kind: TrapKind::UndocumentedOpcode, pc: 0x0000, opcode: 0xcb };
+        assert_eq!(i0.step(), StepResult::Trap { trap, cycles: 10 });
+        assert_eq!(i0.PC, 0x1234);
+
+        let mut i0 = Intel8080::new();
+        i0.trap_policy = TrapPolicy::Stop;
+        //                          NOP,        NOP (0x08)
+        load_program(&mut i0, vec![0x00, 0x08]);
+        let trap = Trap { kind: TrapKind::UndocumentedOpcode, pc: 0x0001, opcode: 0x08 };
+        assert_eq!(i0.run_cycles(100), Err(trap));
+        assert_eq!(i0.step(), StepResult::Trap { trap, cycles: 0 });
+        assert_eq!(i0.PC, 0x0001);
+        assert_eq!(i0.total_ticks, 4);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut i0 = Intel8080::new();
+        //                              LXI SP 0xabcd,          LXI HL 0xbeef,          MVI A 0x80,        ORA A
+        load_program(&mut i0, vec![0b00110001, 0xcd, 0xab, 0b00100001, 0xef, 0xbe, 0b00111110, 0x80, 0b10110111]);
+        i0.run_cycles(10 + 10 + 7 + 4).unwrap();
+        let state = i0.state();
+        assert_eq!((state.a, state.h, state.l, state.sp, state.pc), (0x80, 0xbe, 0xef, 0xabcd, 0x0009));
+        assert_eq!(state.total_cycles, 31);
+        assert!(state.flag(Flag::Sign));
+        assert!(!state.flag(Flag::Zero));
+        assert!(!i0.flag(Flag::Parity));
+
+        let mut i1 = Intel8080::new();
+        i1.set_state(&CpuState::from_bytes(&state.to_bytes()).unwrap());
+        assert_eq!(i1.state(), state);
+        i1.set_flag(Flag::Carry, true);
+        assert!(i1.state().flag(Flag::Carry));
+
+        // Bits 1, 3 and 5 of the flags register can't be changed
+        let mut forced = state;
+        forced.f = 0xFF;
+        i1.set_state(&forced);
+        assert_eq!(i1.state().f, 0b11010111);
+    }
+
+    #[test]
+    fn block_cache_sees_self_modifying_code() {
+        //     MVI A 0x3c (INR A), STA 0x0106,      NOP,  NOP (becomes INR A), HLT
+        let prog = [0x3e, 0x3c, 0x32, 0x06, 0x01, 0x00, 0x00, 0x76];
+        let modes = [
+            ExecutionMode::Interpreter,
+            ExecutionMode::BlockCache,
+            #[cfg(feature = "jit")]
+            ExecutionMode::JitDifferential,
+        ];
+        let mut results = Vec::new();
+        for mode in modes {
+            let mut i0 = Intel8080::new();
+            i0.execution_mode = mode;
+            #[cfg(feature = "jit")]
+            i0.set_jit_threshold(0);
+            for (n, byte) in prog.iter().enumerate() {
+                i0.memory[0x0100 + n] = *byte;
+            }
+            i0.PC = 0x0100;
+            i0.run_cycles(100).unwrap();
+            assert!(i0.halted);
+            assert_eq!(i0.registers.A, 0x3d);
+            results.push(i0.state());
+        }
+        assert!(results.iter().all(|state| *state == results[0]));
+    }
+
+    #[test]
+    fn code_written_in_the_interpreter_replaces_cached_blocks() {
+        let modes = [
+            ExecutionMode::BlockCache,
+            #[cfg(feature = "jit")]
+            ExecutionMode::JitDifferential,
+        ];
+        for mode in modes {
+            let mut i0 = Intel8080::new();
+            #[cfg(feature = "jit")]
+            i0.set_jit_threshold(0);
+            //   0100 INR B, HLT            0200 MVI A 0x05 (DCR B), STA 0x0100, HLT
+            for (address, byte) in [(0x0100, 0x04), (0x0101, 0x76), (0x0200, 0x3e), (0x0201, 0x05),
+                                    (0x0202, 0x32), (0x0203, 0x00), (0x0204, 0x01), (0x0205, 0x76)] {
+                i0.memory[address] = byte;
+            }
+            i0.execution_mode = mode;
+            i0.PC = 0x0100;
+            i0.run_cycles(20).unwrap();
+            assert_eq!(i0.registers.B, 1);
+
+            i0.execution_mode = ExecutionMode::Interpreter;
+            (i0.PC, i0.halted) = (0x0200, false);
+            i0.run_cycles(30).unwrap();
+            assert_eq!(i0.memory[0x0100], 0x05);
+
+            i0.execution_mode = mode;
+            (i0.PC, i0.halted) = (0x0100, false);
+            i0.run_cycles(20).unwrap();
+            assert_eq!(i0.registers.B, 0, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn block_cache_stops_on_cycle_budget() {
+        let mut i0 = Intel8080::new();
+        i0.execution_mode = ExecutionMode::BlockCache;
+        // Same as run_cycles: a block of NOPs is left part way through
+        assert_eq!(i0.run_cycles(10), Ok(12));
+        assert_eq!(i0.PC, 3);
+        assert_eq!(i0.run_cycles(4), Ok(4));
+        assert_eq!(i0.PC, 4);
+    }
+
+    // Every opcode that sets flags: the ALU ops on B, on A and on an immediate, INR/DCR, DAA,
+    // DAD, the rotates, STC and CMC
+    fn flag_opcodes() -> Vec<u8> {
+        let mut opcodes = Vec::new();
+        for alu in 0..8 {
+            opcodes.extend([0x80 | alu << 3, 0x87 | alu << 3, 0xC6 | alu << 3]);
+        }
+        opcodes.extend([0x04, 0x05, 0x3C, 0x3D, 0x27, 0x09, 0x07, 0x0F, 0x17, 0x1F, 0x37, 0x3F]);
+        opcodes
+    }
+
+    // The interpreter's flag code from before flags were evaluated lazily, with F as a plain
+    // byte, kept as the reference for LazyFlags
+    struct EagerFlags {
+        a: u8,
+        f: u8,
+        b: u8,
+        c: u8,
+        h: u8,
+        l: u8,
+    }
+
+    impl EagerFlags {
+        fn set_szp(&mut self, result: u8) {
+            if (result & 0x80) >> 7 == 1 {
+                self.f |= 0b10000000;
+            } else {
+                self.f &= !(0b10000000);
+            }
+            if result == 0 {
+                self.f |= 0b01000000;
+            } else {
+                self.f &= !(0b01000000);
+            }
+            let mut parity: bool = true;
+            for n in 0..8 {
+                if ((result >> n) & 0x01) == 1 {
+                    parity = !parity;
+                }
+            }
+            if parity {
+                self.f |= 0b00000100;
+            } else {
+                self.f &= !(0b00000100);
+            }
+        }
+
+        fn write_c(&mut self, val: bool) {
+            if val {
+                self.f |= 0x01;
+            } else {
+                self.f &= !(0x01);
+            }
+        }
+
+        fn add_3_szapc(&mut self, i1: u8, i2: u8, i3: u8) -> u8 {
+            let result = i1.wrapping_add(i2).wrapping_add(i3);
+            self.set_szp(result);
+            let ac_result = (i1 & 0x0F) + (i2 & 0x0F) + (i3 & 0x0F);
+            if (ac_result & 0x10) >> 4 > 0 {
+                self.f |= 0b00010000;
+            } else {
+                self.f &= !(0b00010000);
+            }
+            self.write_c((i1 as u16) + (i2 as u16) + (i3 as u16) > 0xFF);
+            result
+        }
+
+        fn add_szap(&mut self, i1: u8, i2: u8) -> u8 {
+            let result = i1.wrapping_add(i2);
+            self.set_szp(result);
+            let ac_result = (i1 & 0x0F) + (i2 & 0x0F);
+            if (ac_result & 0x10) >> 4 == 1 {
+                self.f |= 0b00010000;
+            } else {
+                self.f &= !(0b00010000);
+            }
+            result
+        }
+
+        fn sub_szap(&mut self, i1: u8, i2: u8) -> u8 {
+            let complement = (!i2).wrapping_add(1);
+            let result = i1.wrapping_sub(i2);
+            self.set_szp(result);
+            if (complement & 0x0F) + (i1 & 0x0F) > 0x0F {
+                self.f |= 0b00010000;
+            } else {
+                self.f &= !(0b00010000);
+            }
+            result
+        }
+
+        fn alu(&mut self, alu: u8, value: u8) {
+            let a_val = self.a;
+            match alu {
+                0 => {
+                    self.a = self.add_szap(a_val, value);
+                    self.write_c((a_val as u16) + (value as u16) > 0xFF);
+                }
+                1 => self.a = self.add_3_szapc(a_val, value, self.f & 0x01),
+                2 => {
+                    self.a = self.add_3_szapc(a_val, !value, 1);
+                    self.write_c(value as u16 > a_val as u16);
+                }
+                3 => {
+                    let carry = self.f & 0x01;
+                    self.a = self.add_3_szapc(a_val, !value, if carry == 1 { 0 } else { 1 });
+                    self.write_c(value as u16 + (carry as u16) > a_val as u16);
+                }
+                4 => {
+                    self.set_szp(a_val & value);
+                    self.f &= 0b11101110;
+                    if ((a_val | value) & 0x08) != 0 {
+                        self.f |= 0b00010000;
+                    }
+                    self.a = a_val & value;
+                }
+                5 => {
+                    self.a = a_val ^ value;
+                    self.set_szp(self.a);
+                    self.f &= 0b11101110;
+                }
+                6 => {
+                    self.a = a_val | value;
+                    self.set_szp(self.a);
+                    self.f &= 0b11101110;
+                }
+                _ => {
+                    self.add_3_szapc(a_val, !value, 1);
+                    self.write_c(value as u16 > a_val as u16);
+                }
+            }
+        }
+
+        fn daa(&mut self) {
+            let mut add = 0;
+            let msb = self.a >> 4;
+            let lsb = self.a & 0x0F;
+            if lsb > 9 || self.f & 0x10 != 0 {
+                add = 6;
+            }
+            if msb > 9 || self.f & 0x01 == 1 || (msb >= 9 && lsb > 9) {
+                add += 0x60;
+                self.f |= 0x01;
+            }
+            self.a = self.add_szap(self.a, add);
+        }
+
+        fn execute(&mut self, opcode: u8, data: u8) {
+            match opcode {
+                0x80..=0xBF => {
+                    let value = if opcode & 7 == 7 { self.a } else { self.b };
+                    self.alu(opcode >> 3 & 7, value);
+                }
+                0xC6..=0xFE => self.alu(opcode >> 3 & 7, data),
+                0x04 => self.b = self.add_szap(self.b, 1),
+                0x05 => self.b = self.sub_szap(self.b, 1),
+                0x3C => self.a = self.add_szap(self.a, 1),
+                0x3D => self.a = self.sub_szap(self.a, 1),
+                0x27 => self.daa(),
+                0x09 => {
+                    let hl = u16::from_be_bytes([self.h, self.l]);
+                    let result = hl.wrapping_add(u16::from_be_bytes([self.b, self.c]));
+                    self.write_c(result < hl);
+                    [self.h, self.l] = result.to_be_bytes();
+                }
+                0x07 => {
+                    self.write_c(self.a & 0x80 != 0);
+                    self.a = self.a.rotate_left(1);
+                }
+                0x0F => {
+                    self.write_c(self.a & 0x01 != 0);
+                    self.a = self.a.rotate_right(1);
+                }
+                0x17 => {
+                    let carry = self.f & 0x01;
+                    self.write_c(self.a & 0x80 != 0);
+                    self.a = self.a << 1 | carry;
+                }
+                0x1F => {
+                    let carry = self.f & 0x01;
+                    self.write_c(self.a & 0x01 != 0);
+                    self.a = self.a >> 1 | carry << 7;
+                }
+                0x37 => self.f |= 0x01,
+                0x3F => self.f ^= 0x01,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_flags_push_psw_matches_eager() {
+        let mut i0 = Intel8080::new();
+        let opcodes = flag_opcodes();
+        // Pairs of ops, so a pending op gets folded into the flags the next one leaves alone
+        for first in opcodes.iter().copied().chain([0x00]) {
+            for second in opcodes.iter().copied() {
+                let mut prog = Vec::new();
+                for opcode in [first, second] {
+                    prog.push(opcode);
+                    if OPCODES[opcode as usize].size() == 2 {
+                        prog.push(0);
+                    }
+                }
+                prog.push(0xF5); // PUSH PSW
+                for a in (0..=255u8).step_by(5) {
+                    for b in [0x00, 0x01, 0x0F, 0x80, 0x99, 0xFF] {
+                        for f in [0b00000010, 0b11010111] {
+                            for (n, byte) in prog.iter().enumerate() {
+                                // Immediates take the same value as B
+                                i0.memory[n] = if n > 0 && OPCODES[prog[n - 1] as usize].size() == 2 { b } else { *byte };
+                            }
+                            let mut state = i0.state();
+                            (state.a, state.f, state.b, state.c, state.h, state.l) = (a, f, b, a, a, b);
+                            (state.sp, state.pc) = (0x8000, 0);
+                            i0.set_state(&state);
+                            for _ in 0..3 {
+                                i0.step();
+                            }
+
+                            let mut eager = EagerFlags { a, f, b, c: a, h: a, l: b };
+                            if first != 0x00 {
+                                eager.execute(first, b);
+                            }
+                            eager.execute(second, b);
+                            assert_eq!([i0.memory[0x7FFE], i0.memory[0x7FFF]], [eager.f, eager.a],
+                                "{:02x} then {:02x} with A={:02x} B={:02x} F={:02x}", first, second, a, b, f);
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
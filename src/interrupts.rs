@@ -0,0 +1,136 @@
+use crate::save_state::{SaveStateError, Snapshot, StateReader, StateWriter};
+
+// The instruction placed on the data bus during interrupt acknowledge. Multi-byte
+// instructions (e.g. CALL from an 8228 bus controller) are read over successive INTA
+// cycles, so their operands never come from memory and PC is left untouched.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InterruptVector {
+    bytes: [u8; 3],
+    len: u8,
+}
+
+impl InterruptVector {
+    pub fn new(bytes: &[u8]) -> InterruptVector {
+        assert!(!bytes.is_empty() && bytes.len() <= 3, "interrupt vector must be 1 to 3 bytes");
+        let mut vector = InterruptVector { bytes: [0; 3], len: bytes.len() as u8 };
+        vector.bytes[..bytes.len()].copy_from_slice(bytes);
+        vector
+    }
+
+    pub fn rst(n: u8) -> InterruptVector {
+        assert!(n < 8, "RST {} out of range", n);
+        InterruptVector::new(&[0b11000111 | (n << 3)])
+    }
+
+    pub fn call(address: u16) -> InterruptVector {
+        let [lo, hi] = address.to_le_bytes();
+        InterruptVector::new(&[0b11001101, lo, hi])
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+// Priority interrupt controller feeding the CPU's INT line. Each of the eight levels
+// has one request latch: raising an already pending level does nothing, and the latch is
+// only cleared when the CPU acknowledges it or the device lowers it again. When several
+// levels are pending the lowest numbered one is acknowledged first. Level n jams RST n
+// unless set_vector gives it another instruction.
+#[derive(Clone)]
+pub struct InterruptController {
+    requests: u8,
+    vectors: [InterruptVector; 8],
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController { requests: 0, vectors: core::array::from_fn(|n| InterruptVector::rst(n as u8)) }
+    }
+
+    pub fn set_vector(&mut self, level: u8, vector: InterruptVector) {
+        assert!(level < 8, "interrupt level {} out of range", level);
+        self.vectors[level as usize] = vector;
+    }
+
+    pub fn raise(&mut self, level: u8) {
+        assert!(level < 8, "interrupt level {} out of range", level);
+        self.requests |= 1 << level;
+    }
+
+    pub fn lower(&mut self, level: u8) {
+        assert!(level < 8, "interrupt level {} out of range", level);
+        self.requests &= !(1 << level);
+    }
+
+    // State of the INT line
+    pub fn pending(&self) -> bool {
+        self.requests != 0
+    }
+
+    pub fn is_pending(&self, level: u8) -> bool {
+        self.requests & (1 << level) != 0
+    }
+
+    // INTA cycle: picks the highest priority request, clears its latch and returns the
+    // instruction to jam onto the data bus
+    pub fn acknowledge(&mut self) -> Option<InterruptVector> {
+        if self.requests == 0 {
+            return None;
+        }
+        let level = self.requests.trailing_zeros() as u8;
+        self.requests &= !(1 << level);
+        Some(self.vectors[level as usize])
+    }
+}
+
+impl Default for InterruptController {
+    fn default() -> InterruptController {
+        InterruptController::new()
+    }
+}
+
+// Only the request latches are saved, vectors are part of how the machine is wired
+impl Snapshot for InterruptController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.requests);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.requests = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_and_deduplication(){
+        let mut controller = InterruptController::new();
+        controller.raise(2);
+        controller.raise(1);
+        controller.raise(2);
+        assert_eq!(controller.acknowledge(), Some(InterruptVector::new(&[0b11001111]))); // RST 1
+        assert_eq!(controller.acknowledge(), Some(InterruptVector::new(&[0b11010111]))); // RST 2
+        assert_eq!(controller.acknowledge(), None);
+    }
+
+    #[test]
+    fn lower_withdraws_request(){
+        let mut controller = InterruptController::new();
+        controller.raise(7);
+        controller.lower(7);
+        assert!(!controller.pending());
+        assert_eq!(controller.acknowledge(), None);
+    }
+
+    #[test]
+    fn custom_vector(){
+        let mut controller = InterruptController::new();
+        controller.set_vector(3, InterruptVector::call(0x1234));
+        controller.raise(3);
+        assert_eq!(controller.acknowledge().unwrap().bytes(), &[0b11001101, 0x34, 0x12]);
+    }
+}
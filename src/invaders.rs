@@ -0,0 +1,336 @@
+use alloc::vec::Vec;
+use crate::hash::fnv1a64;
+use crate::intel8080::{IOHandler, Intel8080, Trap};
+use crate::memory::MappedMemory;
+use crate::save_state::{SaveStateError, Snapshot, StateReader, StateWriter};
+use crate::shift_register::ShiftRegister;
+
+pub const CLOCK_SPEED: usize = 2_000_000;
+pub const REFRESH_RATE: u32 = 60;
+pub const CYCLES_PER_FRAME: usize = CLOCK_SPEED / REFRESH_RATE as usize;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Inputs {
+    pub p2start: bool,
+    pub p1start: bool,
+    pub p1shoot: bool,
+    pub p1left: bool,
+    pub p1right: bool,
+    pub p2shoot: bool,
+    pub p2left: bool,
+    pub p2right: bool,
+    pub coin: bool,
+}
+
+impl Inputs {
+    pub fn new() -> Inputs {
+        Inputs::default()
+    }
+
+    // One bit per button, in field order. Used by input movies.
+    pub fn to_bits(&self) -> u16 {
+        [self.p2start, self.p1start, self.p1shoot, self.p1left, self.p1right,
+         self.p2shoot, self.p2left, self.p2right, self.coin]
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (n, pressed)| bits | (*pressed as u16) << n)
+    }
+
+    pub fn from_bits(bits: u16) -> Inputs {
+        let pressed = |n: u16| bits & (1 << n) != 0;
+        Inputs{
+            p2start: pressed(0),
+            p1start: pressed(1),
+            p1shoot: pressed(2),
+            p1left: pressed(3),
+            p1right: pressed(4),
+            p2shoot: pressed(5),
+            p2left: pressed(6),
+            p2right: pressed(7),
+            coin: pressed(8),
+        }
+    }
+}
+
+pub fn input0(inputs: &Inputs) ->u8{
+    let mut result = 0b00001110;
+    if inputs.p1shoot {result |= 1<<4} else {result &= !(1<<4)};
+    if inputs.p1left {result |= 1<<5} else {result &= !(1<<5)};
+    if inputs.p1right {result |= 1<<6} else {result &= !(1<<6)};
+    result
+}
+
+
+pub fn input1(inputs: &Inputs)->u8{
+    let mut result = 0b00001000;
+    if inputs.coin{result|=1} else  {result &= !(1)};
+    if inputs.p2start {result |= 1<<1} else {result &= !(1<<1)};
+    if inputs.p1start {result |= 1<<2} else {result &= !(1<<2)};
+    if inputs.p1shoot {result |= 1<<4} else {result &= !(1<<4)};
+    if inputs.p1left {result |= 1<<5} else {result &= !(1<<5)};
+    if inputs.p1right {result |= 1<<6} else {result &= !(1<<6)};
+    result
+}
+pub fn input2(inputs: &Inputs)->u8 {
+    let mut result = 0b00000011;
+    if inputs.p2shoot {result |= 1<<4} else {result &= !(1<<4)};
+    if inputs.p2left {result |= 1<<5} else {result &= !(1<<5)};
+    if inputs.p2right {result |= 1<<6} else {result &= !(1<<6)};
+    result
+}
+
+// A write to one of the sound latches, with the value the latch held before it
+#[derive(Clone)]
+pub struct SoundWrite {
+    pub port: u8,
+    pub data: u8,
+    pub prev_data: u8,
+}
+
+// The Space Invaders board: input ports, the shift register and the two sound latches
+#[derive(Clone)]
+pub struct InvadersIO {
+    pub inputs: Inputs,
+    shift_register: ShiftRegister,
+    prev_port3: u8,
+    prev_port5: u8,
+    pub sound_writes: Vec<SoundWrite>,
+}
+
+impl InvadersIO {
+    pub fn new() -> InvadersIO {
+        InvadersIO {
+            inputs: Inputs::new(),
+            shift_register: ShiftRegister::new(),
+            prev_port3: 0,
+            prev_port5: 0,
+            sound_writes: Vec::new(),
+        }
+    }
+}
+
+impl Default for InvadersIO {
+    fn default() -> InvadersIO {
+        InvadersIO::new()
+    }
+}
+
+impl IOHandler for InvadersIO {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0 => input0(&self.inputs),
+            1 => input1(&self.inputs),
+            2 => input2(&self.inputs),
+            3 => self.shift_register.result(),
+            _ => 0,
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            2 => self.shift_register.write_offset(value),
+            3 => {
+                self.sound_writes.push(SoundWrite { port, data: value, prev_data: self.prev_port3 });
+                self.prev_port3 = value;
+            }
+            4 => self.shift_register.insert(value),
+            5 => {
+                self.sound_writes.push(SoundWrite { port, data: value, prev_data: self.prev_port5 });
+                self.prev_port5 = value;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Inputs come from the frontend every frame and pending sound writes are drained right
+// after the frame that made them, so neither is part of the saved state
+impl Snapshot for InvadersIO {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.shift_register.save_state(writer);
+        writer.write_u8(self.prev_port3);
+        writer.write_u8(self.prev_port5);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift_register.load_state(reader)?;
+        self.prev_port3 = reader.read_u8()?;
+        self.prev_port5 = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// The whole arcade board, driven one video frame at a time
+#[derive(Clone)]
+pub struct Invaders {
+    pub cpu: Intel8080<MappedMemory, InvadersIO>,
+    pub frame: usize,
+}
+
+impl Invaders {
+    pub fn new(rom: &[u8]) -> Invaders {
+        let mut cpu = Intel8080::with_bus(MappedMemory::space_invaders(), InvadersIO::new());
+        cpu.load_program(rom.to_vec());
+        Invaders { cpu, frame: 0 }
+    }
+
+    // Runs to the next frame boundary, raising RST 1 mid screen and RST 2 at vblank.
+    // Frame boundaries are fixed points on total_ticks, so overshooting one half frame
+    // shortens the next rather than drifting.
+    pub fn run_frame(&mut self) -> Result<(), Trap> {
+        self.run_frame_with(|cpu, cycles| cpu.run_cycles(cycles))
+    }
+
+    // run_frame with the CPU driven by `run` instead of run_cycles, e.g. under a debugger.
+    // `run` gets the same cycle budgets run_cycles would.
+    pub fn run_frame_with<F>(&mut self, mut run: F) -> Result<(), Trap>
+    where
+        F: FnMut(&mut Intel8080<MappedMemory, InvadersIO>, usize) -> Result<usize, Trap>,
+    {
+        let frame_start = self.frame * CYCLES_PER_FRAME;
+        let cycles = (frame_start + CYCLES_PER_FRAME / 2).saturating_sub(self.cpu.total_ticks);
+        run(&mut self.cpu, cycles)?;
+        self.cpu.interrupts.raise(1);
+
+        let cycles = (frame_start + CYCLES_PER_FRAME).saturating_sub(self.cpu.total_ticks);
+        run(&mut self.cpu, cycles)?;
+        self.cpu.interrupts.raise(2);
+
+        self.frame += 1;
+        Ok(())
+    }
+
+    pub fn video_ram(&self) -> &[u8] {
+        self.cpu.memory.slice(0x2400, 0x3FFF)
+    }
+
+    // Hash of work and video RAM, for checking that two runs ended up in the same place
+    pub fn ram_hash(&self) -> u64 {
+        fnv1a64(self.cpu.memory.slice(0x2000, 0x3FFF))
+    }
+}
+
+impl Snapshot for Invaders {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu.save_state(writer);
+        writer.write_u64(self.frame as u64);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu.load_state(reader)?;
+        self.frame = reader.read_u64()? as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intel8080::ExecutionMode;
+    use crate::save_state;
+
+    const ROM: &[u8] = include_bytes!("../cpu_tests/invaders.concatenated");
+
+    #[test]
+    fn save_state_resumes_identically(){
+        let rom_hash = fnv1a64(ROM);
+        let mut machine = Invaders::new(ROM);
+        for _ in 0..120 {
+            machine.run_frame().unwrap();
+        }
+        let saved = save_state::encode(rom_hash, &machine);
+        for _ in 0..60 {
+            machine.run_frame().unwrap();
+        }
+
+        let mut restored = Invaders::new(ROM);
+        save_state::decode(&saved, rom_hash, &mut restored).unwrap();
+        assert_eq!(restored.frame, 120);
+        for _ in 0..60 {
+            restored.run_frame().unwrap();
+        }
+        assert_eq!(restored.cpu.state(), machine.cpu.state());
+        assert_eq!(restored.cpu.memory.slice(0x2000, 0x3FFF), machine.cpu.memory.slice(0x2000, 0x3FFF));
+    }
+
+    #[test]
+    fn block_cache_matches_interpreter(){
+        let mut interpreter = Invaders::new(ROM);
+        let mut cached = Invaders::new(ROM);
+        cached.cpu.execution_mode = ExecutionMode::BlockCache;
+        // Attract mode, then a coin and a one player game
+        for frame in 0..900 {
+            let mut inputs = Inputs::new();
+            inputs.coin = (600..610).contains(&frame);
+            inputs.p1start = (660..670).contains(&frame);
+            inputs.p1shoot = frame % 30 < 3;
+            interpreter.cpu.io.inputs = inputs;
+            cached.cpu.io.inputs = inputs;
+            interpreter.run_frame().unwrap();
+            cached.run_frame().unwrap();
+            assert_eq!(cached.cpu.state(), interpreter.cpu.state(), "frame {}", frame);
+        }
+        assert_eq!(cached.ram_hash(), interpreter.ram_hash());
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit_matches_interpreter(){
+        let mut interpreter = Invaders::new(ROM);
+        let mut compiled = Invaders::new(ROM);
+        // Every compiled block is checked against the interpreter as it runs
+        compiled.cpu.execution_mode = ExecutionMode::JitDifferential;
+        for frame in 0..900 {
+            let mut inputs = Inputs::new();
+            inputs.coin = (600..610).contains(&frame);
+            inputs.p1start = (660..670).contains(&frame);
+            inputs.p1shoot = frame % 30 < 3;
+            interpreter.cpu.io.inputs = inputs;
+            compiled.cpu.io.inputs = inputs;
+            interpreter.run_frame().unwrap();
+            compiled.run_frame().unwrap();
+            assert_eq!(compiled.cpu.state(), interpreter.cpu.state(), "frame {}", frame);
+        }
+        assert_eq!(compiled.ram_hash(), interpreter.ram_hash());
+    }
+
+    #[test]
+    fn clones_run_independently(){
+        fn send_and_clone<T: Send + Clone>() {}
+        send_and_clone::<Invaders>();
+
+        let mut machine = Invaders::new(ROM);
+        machine.cpu.execution_mode = ExecutionMode::BlockCache;
+        for _ in 0..600 {
+            machine.run_frame().unwrap();
+        }
+        let mut same = machine.clone();
+        let mut other = machine.clone();
+        let mut inputs = Inputs::new();
+        inputs.coin = true;
+        other.cpu.io.inputs = inputs;
+        let other = std::thread::spawn(move || {
+            for _ in 0..60 {
+                other.run_frame().unwrap();
+            }
+            other
+        }).join().unwrap();
+        for _ in 0..60 {
+            machine.run_frame().unwrap();
+            same.run_frame().unwrap();
+        }
+        assert_eq!(same.cpu.state(), machine.cpu.state());
+        assert_eq!(same.ram_hash(), machine.ram_hash());
+        assert_ne!(other.ram_hash(), machine.ram_hash());
+    }
+
+    #[test]
+    fn inputs_bits_round_trip(){
+        for bits in 0..1 << 9 {
+            assert_eq!(Inputs::from_bits(bits).to_bits(), bits);
+        }
+        let mut inputs = Inputs::new();
+        inputs.coin = true;
+        assert_eq!(inputs.to_bits(), 1 << 8);
+    }
+}
@@ -0,0 +1,898 @@
+use std::collections::HashMap;
+use std::ptr;
+use crate::block_cache::{decode_block, DecodedOp};
+use crate::intel8080::{IOHandler, Intel8080, Op};
+use crate::memory::MemoryBus;
+use crate::x64::{Alu, Assembler, Cond, Label, Reg};
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature needs an x86-64 Unix host");
+
+// Executable memory for compiled blocks. When it fills up everything is thrown away and
+// blocks are compiled again as they get hot.
+const ARENA_SIZE: usize = 4 << 20;
+// Times a block is entered before it gets compiled
+pub(crate) const DEFAULT_THRESHOLD: u32 = 16;
+const PAGE_COUNT: usize = 256;
+
+// The 8080 register file lives in callee-saved host registers while a block runs, so
+// memory helper calls don't disturb it. Register pairs are kept as 16-bit values.
+const BC: Reg = Reg::Rbx;
+const DE: Reg = Reg::Rbp;
+const HL: Reg = Reg::R12;
+const A: Reg = Reg::R13;
+const F: Reg = Reg::R14;
+const SP: Reg = Reg::R15;
+
+// Stack slots below the saved registers: the frame pointer and two scratch words
+const FRAME_SLOT: i8 = 0;
+const SCRATCH_SLOT: i8 = 8;
+const STACK_SLOTS: i8 = 24;
+
+// Passed to a compiled block in rdi. Registers go in and come out; pc, ticks and
+// instructions say where the block stopped and what it cost.
+#[repr(C)]
+pub(crate) struct JitFrame {
+    pub bc: u32,
+    pub de: u32,
+    pub hl: u32,
+    pub a: u32,
+    pub f: u32,
+    pub sp: u32,
+    pub pc: u32,
+    pub ticks: u32,
+    pub instructions: u32,
+    // Set by the write helper when a write drops a compiled block
+    pub invalidated: u32,
+    pub cpu: *mut u8,
+}
+
+const FRAME_BC: i8 = 0;
+const FRAME_DE: i8 = 4;
+const FRAME_HL: i8 = 8;
+const FRAME_A: i8 = 12;
+const FRAME_F: i8 = 16;
+const FRAME_SP: i8 = 20;
+const FRAME_PC: i8 = 24;
+const FRAME_TICKS: i8 = 28;
+const FRAME_INSTRUCTIONS: i8 = 32;
+const FRAME_INVALIDATED: i8 = 36;
+
+type BlockFn = unsafe extern "sysv64" fn(*mut JitFrame);
+
+// Called from compiled code for every memory access, monomorphised for the CPU's bus and
+// I/O types. The frame's cpu pointer is the Intel8080 running the block.
+pub(crate) extern "sysv64" fn read<M: MemoryBus, IO: IOHandler>(frame: *mut JitFrame, address: u32) -> u32 {
+    let cpu = unsafe { &mut *((*frame).cpu as *mut Intel8080<M, IO>) };
+    cpu.memory.read(address as u16) as u32
+}
+
+pub(crate) extern "sysv64" fn write<M: MemoryBus, IO: IOHandler>(frame: *mut JitFrame, address: u32, value: u32) {
+    let frame = unsafe { &mut *frame };
+    let cpu = unsafe { &mut *(frame.cpu as *mut Intel8080<M, IO>) };
+    let generation = cpu.jit.generation;
+    cpu.write_byte(address as u16, value as u8);
+    if cpu.jit.generation != generation {
+        frame.invalidated = 1;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Helpers {
+    pub read: usize,
+    pub write: usize,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct CompiledBlock {
+    entry: BlockFn,
+    // States taken by every instruction but the last, which is the only one whose cost
+    // depends on a condition. The block only runs if it won't cross the cycle budget
+    // before its last instruction, the same point the interpreter would stop at.
+    pub prefix_ticks: usize,
+}
+
+impl CompiledBlock {
+    // Safety: the frame's cpu pointer must be the Intel8080<M, IO> the block was compiled
+    // with helpers for, and nothing else may touch that CPU until this returns
+    pub unsafe fn run(&self, frame: &mut JitFrame) {
+        unsafe { (self.entry)(frame) }
+    }
+}
+
+enum Entry {
+    Cold(u32),
+    Compiled { block: CompiledBlock, len: u16 },
+    // The first instruction has to go through the interpreter
+    Uncompilable,
+}
+
+pub(crate) struct JitCache {
+    arena: *mut u8,
+    used: usize,
+    entries: HashMap<u16, Entry>,
+    // Start addresses of the compiled (and uncompilable) entries with code in each page
+    pages: Vec<Vec<u16>>,
+    // Bumped whenever a compiled block is dropped
+    pub generation: u64,
+    pub threshold: u32,
+    // Address and previous value of every write the CPU makes while this is Some, kept for
+    // differential runs
+    pub write_log: Option<Vec<(u16, u8)>>,
+}
+
+// The arena is only ever touched through the JitCache that owns it
+unsafe impl Send for JitCache {}
+
+// Compiled code can't be shared with the copy, so it starts cold and compiles its own
+impl Clone for JitCache {
+    fn clone(&self) -> JitCache {
+        let mut cache = JitCache::new();
+        cache.threshold = self.threshold;
+        cache
+    }
+}
+
+impl JitCache {
+    pub fn new() -> JitCache {
+        JitCache {
+            arena: ptr::null_mut(),
+            used: 0,
+            entries: HashMap::new(),
+            pages: vec![Vec::new(); PAGE_COUNT],
+            generation: 0,
+            threshold: DEFAULT_THRESHOLD,
+            write_log: None,
+        }
+    }
+
+    // The compiled block starting at `pc`, compiling it if it has just got hot
+    pub fn lookup<M: MemoryBus>(&mut self, memory: &M, pc: u16, helpers: Helpers) -> Option<CompiledBlock> {
+        let entry = self.entries.entry(pc).or_insert(Entry::Cold(0));
+        match entry {
+            Entry::Compiled { block, .. } => return Some(*block),
+            Entry::Uncompilable => return None,
+            Entry::Cold(count) => {
+                *count += 1;
+                if *count < self.threshold {
+                    return None;
+                }
+            }
+        }
+
+        let ops = decode_block(memory, pc, supported);
+        if ops.is_empty() {
+            self.entries.insert(pc, Entry::Uncompilable);
+            self.pages[pc as usize >> 8].push(pc);
+            return None;
+        }
+        let len: u16 = ops.iter().map(|decoded| decoded.op.size()).sum();
+        let code = compile(&ops, helpers);
+        let block = CompiledBlock {
+            entry: self.place(&code),
+            prefix_ticks: ops[..ops.len() - 1].iter().map(|decoded| decoded.op.states().0).sum(),
+        };
+        for page in pages(pc, len) {
+            self.pages[page].push(pc);
+        }
+        self.entries.insert(pc, Entry::Compiled { block, len });
+        Some(block)
+    }
+
+    fn place(&mut self, code: &[u8]) -> BlockFn {
+        if self.arena.is_null() {
+            let arena = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    ARENA_SIZE,
+                    libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            assert!(arena != libc::MAP_FAILED, "could not map executable memory for the JIT");
+            self.arena = arena as *mut u8;
+        }
+        assert!(code.len() <= ARENA_SIZE, "compiled block is larger than the JIT arena");
+        if self.used + code.len() > ARENA_SIZE {
+            // Blocks only run from run_jit, never while this is called, so nothing still
+            // points into the arena
+            let pending = self.entries.drain()
+                .filter(|(_, entry)| matches!(entry, Entry::Cold(_)))
+                .collect();
+            self.entries = pending;
+            for page in &mut self.pages {
+                page.clear();
+            }
+            self.used = 0;
+            self.generation += 1;
+        }
+        unsafe {
+            let entry = self.arena.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), entry, code.len());
+            self.used += code.len();
+            std::mem::transmute::<*mut u8, BlockFn>(entry)
+        }
+    }
+
+    // Drops every compiled block with code at `address`
+    pub fn invalidate(&mut self, address: u16) {
+        let page = address as usize >> 8;
+        if self.pages[page].is_empty() {
+            return;
+        }
+        let stale: Vec<(u16, u16)> = self.pages[page].iter()
+            .filter_map(|start| {
+                let len = match self.entries.get(start) {
+                    Some(Entry::Compiled { len, .. }) => *len,
+                    _ => 1,
+                };
+                (address.wrapping_sub(*start) < len).then_some((*start, len))
+            })
+            .collect();
+        for (start, len) in stale {
+            if let Some(Entry::Compiled { .. }) = self.entries.remove(&start) {
+                self.generation += 1;
+            }
+            for page in pages(start, len) {
+                self.pages[page].retain(|other| *other != start);
+            }
+        }
+    }
+
+    // Forgets every block, but keeps the arena for reuse
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        for page in &mut self.pages {
+            page.clear();
+        }
+        self.used = 0;
+        self.generation += 1;
+    }
+}
+
+impl Drop for JitCache {
+    fn drop(&mut self) {
+        if !self.arena.is_null() {
+            unsafe { libc::munmap(self.arena as *mut libc::c_void, ARENA_SIZE) };
+        }
+    }
+}
+
+fn pages(start: u16, len: u16) -> impl Iterator<Item = usize> {
+    let first = start as usize >> 8;
+    let last = start.wrapping_add(len - 1) as usize >> 8;
+    let count = (last + PAGE_COUNT - first) % PAGE_COUNT + 1;
+    (0..count).map(move |n| (first + n) % PAGE_COUNT)
+}
+
+// I/O and interrupt control are left to the interpreter, and so is DAA
+fn supported(op: Op) -> bool {
+    !matches!(op, Op::Daa | Op::Out | Op::In | Op::Hlt | Op::Ei | Op::Di)
+}
+
+// Sign, zero and parity flags for every result, laid out like the flags register
+static SZP: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut flags = (n as u8) & 0x80;
+        if n == 0 {
+            flags |= 0x40;
+        }
+        if (n as u8).count_ones().is_multiple_of(2) {
+            flags |= 0x04;
+        }
+        table[n] = flags;
+        n += 1;
+    }
+    table
+};
+
+struct Compiler {
+    asm: Assembler,
+    helpers: Helpers,
+    // Jumps to the epilogue, which expects the next PC in eax, states in ecx and the
+    // instruction count in edx
+    exits: Vec<Label>,
+}
+
+fn compile(ops: &[DecodedOp], helpers: Helpers) -> Vec<u8> {
+    let mut compiler = Compiler { asm: Assembler::new(), helpers, exits: Vec::new() };
+    compiler.prologue();
+    let mut ticks = 0;
+    for (index, decoded) in ops.iter().enumerate() {
+        let (not_taken, taken) = decoded.op.states();
+        let (not_taken, taken) = (not_taken as u32, taken as u32);
+        let done = Done { next_pc: decoded.next_pc, ticks: ticks + not_taken, taken_ticks: ticks + taken, count: index as u32 + 1 };
+        compiler.op(decoded, &done);
+        ticks += not_taken;
+    }
+    let last = ops.last().unwrap();
+    if !last.op.ends_block() {
+        compiler.exit(last.next_pc as u32, ticks, ops.len() as u32);
+    }
+    compiler.epilogue();
+    compiler.asm.code
+}
+
+// Where the block stands once an instruction has finished
+struct Done {
+    next_pc: u16,
+    ticks: u32,
+    // States if a conditional branch, call or return is taken
+    taken_ticks: u32,
+    count: u32,
+}
+
+impl Compiler {
+    fn prologue(&mut self) {
+        for reg in [Reg::Rbx, Reg::Rbp, Reg::R12, Reg::R13, Reg::R14, Reg::R15] {
+            self.asm.push(reg);
+        }
+        // Six pushes plus the return address leave the stack 8 bytes off alignment
+        self.asm.sub_rsp(STACK_SLOTS);
+        self.asm.store64(Reg::Rsp, FRAME_SLOT, Reg::Rdi);
+        self.asm.load32(BC, Reg::Rdi, FRAME_BC);
+        self.asm.load32(DE, Reg::Rdi, FRAME_DE);
+        self.asm.load32(HL, Reg::Rdi, FRAME_HL);
+        self.asm.load32(A, Reg::Rdi, FRAME_A);
+        self.asm.load32(F, Reg::Rdi, FRAME_F);
+        self.asm.load32(SP, Reg::Rdi, FRAME_SP);
+    }
+
+    fn epilogue(&mut self) {
+        for label in std::mem::take(&mut self.exits) {
+            self.asm.bind(label);
+        }
+        self.asm.load64(Reg::Rdi, Reg::Rsp, FRAME_SLOT);
+        self.asm.store32(Reg::Rdi, FRAME_BC, BC);
+        self.asm.store32(Reg::Rdi, FRAME_DE, DE);
+        self.asm.store32(Reg::Rdi, FRAME_HL, HL);
+        self.asm.store32(Reg::Rdi, FRAME_A, A);
+        self.asm.store32(Reg::Rdi, FRAME_F, F);
+        self.asm.store32(Reg::Rdi, FRAME_SP, SP);
+        self.asm.store32(Reg::Rdi, FRAME_PC, Reg::Rax);
+        self.asm.store32(Reg::Rdi, FRAME_TICKS, Reg::Rcx);
+        self.asm.store32(Reg::Rdi, FRAME_INSTRUCTIONS, Reg::Rdx);
+        self.asm.add_rsp(STACK_SLOTS);
+        for reg in [Reg::R15, Reg::R14, Reg::R13, Reg::R12, Reg::Rbp, Reg::Rbx] {
+            self.asm.pop(reg);
+        }
+        self.asm.ret();
+    }
+
+    fn exit(&mut self, pc: u32, ticks: u32, count: u32) {
+        self.asm.mov_imm(Reg::Rax, pc);
+        self.exit_to_eax(ticks, count);
+    }
+
+    fn exit_to_eax(&mut self, ticks: u32, count: u32) {
+        self.asm.mov_imm(Reg::Rcx, ticks);
+        self.asm.mov_imm(Reg::Rdx, count);
+        let label = self.asm.jump();
+        self.exits.push(label);
+    }
+
+    // Leaves the block after an instruction that wrote memory if the write hit compiled code
+    fn check_invalidated(&mut self, done: &Done) {
+        self.asm.load64(Reg::Rdi, Reg::Rsp, FRAME_SLOT);
+        self.asm.cmp_mem8_imm(Reg::Rdi, FRAME_INVALIDATED, 0);
+        let skip = self.asm.jump_if(Cond::Equal);
+        self.exit(done.next_pc as u32, done.ticks, done.count);
+        self.asm.bind(skip);
+    }
+
+    // eax = byte at `address`. Clobbers every caller-saved register.
+    fn read(&mut self, address: Reg) {
+        if address != Reg::Rsi {
+            self.asm.mov(Reg::Rsi, address);
+        }
+        self.asm.load64(Reg::Rdi, Reg::Rsp, FRAME_SLOT);
+        self.asm.mov_imm64(Reg::Rax, self.helpers.read as u64);
+        self.asm.call(Reg::Rax);
+    }
+
+    fn write(&mut self, address: Reg, value: Reg) {
+        assert!(value != Reg::Rsi, "write value would be overwritten by the address");
+        if address != Reg::Rsi {
+            self.asm.mov(Reg::Rsi, address);
+        }
+        if value != Reg::Rdx {
+            self.asm.mov(Reg::Rdx, value);
+        }
+        self.asm.load64(Reg::Rdi, Reg::Rsp, FRAME_SLOT);
+        self.asm.mov_imm64(Reg::Rax, self.helpers.write as u64);
+        self.asm.call(Reg::Rax);
+    }
+
+    fn pair(rp: u8) -> Reg {
+        [BC, DE, HL, SP][rp as usize]
+    }
+
+    // dst = register `r` (0-5 and 7, M is handled by the caller)
+    fn get8(&mut self, dst: Reg, r: u8) {
+        match r {
+            7 => self.asm.mov(dst, A),
+            _ => {
+                let pair = Compiler::pair(r / 2);
+                if r.is_multiple_of(2) {
+                    self.asm.mov(dst, pair);
+                    self.asm.shr(dst, 8);
+                } else {
+                    self.asm.movzx8(dst, pair);
+                }
+            }
+        }
+    }
+
+    // Register `r` = src, which must hold a byte and is clobbered
+    fn set8(&mut self, r: u8, src: Reg) {
+        match r {
+            7 => self.asm.mov(A, src),
+            _ => {
+                let pair = Compiler::pair(r / 2);
+                if r.is_multiple_of(2) {
+                    self.asm.alu_imm(Alu::And, pair, 0x00FF);
+                    self.asm.shl(src, 8);
+                } else {
+                    self.asm.alu_imm(Alu::And, pair, 0xFF00);
+                }
+                self.asm.alu(Alu::Or, pair, src);
+            }
+        }
+    }
+
+    // Sets S, Z and P from the byte in eax. Clobbers esi and ecx.
+    fn szp(&mut self) {
+        self.asm.mov_imm64(Reg::Rsi, SZP.as_ptr() as u64);
+        self.asm.movzx8_indexed(Reg::Rcx, Reg::Rsi, Reg::Rax);
+        self.asm.alu_imm(Alu::And, F, 0xFF & !0xC4);
+        self.asm.alu(Alu::Or, F, Reg::Rcx);
+    }
+
+    // SP -= 2, then writes the bytes `low` and `high` leave in ecx, low byte first
+    fn push_word(&mut self, low: impl Fn(&mut Compiler), high: impl Fn(&mut Compiler)) {
+        self.asm.alu_imm(Alu::Sub, SP, 2);
+        self.asm.alu_imm(Alu::And, SP, 0xFFFF);
+        low(self);
+        self.write(SP, Reg::Rcx);
+        self.asm.mov(Reg::Rax, SP);
+        self.asm.alu_imm(Alu::Add, Reg::Rax, 1);
+        self.asm.alu_imm(Alu::And, Reg::Rax, 0xFFFF);
+        high(self);
+        self.write(Reg::Rax, Reg::Rcx);
+    }
+
+    fn push_constant(&mut self, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.push_word(|c| c.asm.mov_imm(Reg::Rcx, lo as u32), |c| c.asm.mov_imm(Reg::Rcx, hi as u32));
+    }
+
+    // eax = word at SP, SP += 2
+    fn pop_word(&mut self) {
+        self.read(SP);
+        self.asm.store32(Reg::Rsp, SCRATCH_SLOT, Reg::Rax);
+        self.asm.mov(Reg::Rax, SP);
+        self.asm.alu_imm(Alu::Add, Reg::Rax, 1);
+        self.asm.alu_imm(Alu::And, Reg::Rax, 0xFFFF);
+        self.read(Reg::Rax);
+        self.asm.shl(Reg::Rax, 8);
+        self.asm.load32(Reg::Rcx, Reg::Rsp, SCRATCH_SLOT);
+        self.asm.alu(Alu::Or, Reg::Rax, Reg::Rcx);
+        self.asm.alu_imm(Alu::Add, SP, 2);
+        self.asm.alu_imm(Alu::And, SP, 0xFFFF);
+    }
+
+    // Jumps to the returned label when condition `cc` is false
+    fn unless(&mut self, cc: u8) -> Label {
+        let mask = [0x40, 0x01, 0x04, 0x80][(cc / 2) as usize];
+        self.asm.test_imm(F, mask);
+        // Even conditions hold when the flag is clear
+        self.asm.jump_if(if cc.is_multiple_of(2) { Cond::NotEqual } else { Cond::Equal })
+    }
+
+    // ALU operation on A and the byte in edx, with the interpreter's flag rules
+    fn alu(&mut self, alu: u8) {
+        match alu {
+            4..=6 => {
+                self.asm.mov(Reg::Rax, A);
+                let op = [Alu::And, Alu::Xor, Alu::Or][alu as usize - 4];
+                self.asm.alu(op, Reg::Rax, Reg::Rdx);
+                if alu == 4 {
+                    // ANA sets AC from bit 3 of either operand
+                    self.asm.mov(Reg::R8, A);
+                    self.asm.alu(Alu::Or, Reg::R8, Reg::Rdx);
+                    self.asm.alu_imm(Alu::And, Reg::R8, 0x08);
+                    self.asm.shl(Reg::R8, 1);
+                }
+                self.szp();
+                self.asm.alu_imm(Alu::And, F, 0xEE);
+                if alu == 4 {
+                    self.asm.alu(Alu::Or, F, Reg::R8);
+                }
+                self.asm.mov(A, Reg::Rax);
+            }
+            _ => {
+                // Subtraction adds the complement plus the inverted borrow, like the interpreter
+                let subtract = matches!(alu, 2 | 3 | 7);
+                self.asm.mov(Reg::Rcx, Reg::Rdx);
+                if subtract {
+                    self.asm.alu_imm(Alu::Xor, Reg::Rcx, 0xFF);
+                }
+                self.asm.mov(Reg::Rax, A);
+                self.asm.alu(Alu::Add, Reg::Rax, Reg::Rcx);
+                match alu {
+                    1 | 3 => {
+                        self.asm.mov(Reg::R9, F);
+                        self.asm.alu_imm(Alu::And, Reg::R9, 0x01);
+                        if alu == 3 {
+                            self.asm.alu_imm(Alu::Xor, Reg::R9, 0x01);
+                        }
+                        self.asm.alu(Alu::Add, Reg::Rax, Reg::R9);
+                    }
+                    2 | 7 => self.asm.alu_imm(Alu::Add, Reg::Rax, 1),
+                    _ => {}
+                }
+                // AC is the carry into bit 4
+                self.asm.mov(Reg::R8, A);
+                self.asm.alu(Alu::Xor, Reg::R8, Reg::Rcx);
+                self.asm.alu(Alu::Xor, Reg::R8, Reg::Rax);
+                self.asm.alu_imm(Alu::And, Reg::R8, 0x10);
+                // CY is the carry out of bit 7, a borrow for subtraction
+                self.asm.mov(Reg::R9, Reg::Rax);
+                self.asm.shr(Reg::R9, 8);
+                if subtract {
+                    self.asm.alu_imm(Alu::Xor, Reg::R9, 0x01);
+                }
+                self.asm.alu_imm(Alu::And, Reg::Rax, 0xFF);
+                self.szp();
+                self.asm.alu_imm(Alu::And, F, 0xEE);
+                self.asm.alu(Alu::Or, F, Reg::R8);
+                self.asm.alu(Alu::Or, F, Reg::R9);
+                if alu != 7 {
+                    self.asm.mov(A, Reg::Rax);
+                }
+            }
+        }
+    }
+
+    // INR/DCR on the byte in eax, leaving the result in eax. CY is untouched.
+    fn increment(&mut self, delta: u32) {
+        self.asm.mov(Reg::Rdx, Reg::Rax);
+        self.asm.alu_imm(Alu::Add, Reg::Rax, delta);
+        self.asm.mov(Reg::R8, Reg::Rdx);
+        self.asm.alu(Alu::Xor, Reg::R8, Reg::Rax);
+        self.asm.alu_imm(Alu::Xor, Reg::R8, delta);
+        self.asm.alu_imm(Alu::And, Reg::R8, 0x10);
+        self.asm.alu_imm(Alu::And, Reg::Rax, 0xFF);
+        self.szp();
+        self.asm.alu_imm(Alu::And, F, 0xEF);
+        self.asm.alu(Alu::Or, F, Reg::R8);
+    }
+
+    fn op(&mut self, decoded: &DecodedOp, done: &Done) {
+        let imm8 = decoded.bytes[1] as u32;
+        let imm16 = u16::from_le_bytes([decoded.bytes[1], decoded.bytes[2]]);
+        match decoded.op {
+            Op::Nop => {}
+            Op::Lxi(rp) => self.asm.mov_imm(Compiler::pair(rp), imm16 as u32),
+            Op::Stax(rp) => {
+                self.write(Compiler::pair(rp), A);
+                self.check_invalidated(done);
+            }
+            Op::Inx(rp) | Op::Dcx(rp) => {
+                let pair = Compiler::pair(rp);
+                let op = if matches!(decoded.op, Op::Inx(_)) { Alu::Add } else { Alu::Sub };
+                self.asm.alu_imm(op, pair, 1);
+                self.asm.alu_imm(Alu::And, pair, 0xFFFF);
+            }
+            Op::Inr(r) | Op::Dcr(r) => {
+                let delta = if matches!(decoded.op, Op::Inr(_)) { 1 } else { 0xFF };
+                if r == 6 {
+                    self.read(HL);
+                    self.increment(delta);
+                    self.write(HL, Reg::Rax);
+                    self.check_invalidated(done);
+                } else {
+                    self.get8(Reg::Rax, r);
+                    self.increment(delta);
+                    self.set8(r, Reg::Rax);
+                }
+            }
+            Op::Mvi(r) => {
+                self.asm.mov_imm(Reg::Rax, imm8);
+                if r == 6 {
+                    self.write(HL, Reg::Rax);
+                    self.check_invalidated(done);
+                } else {
+                    self.set8(r, Reg::Rax);
+                }
+            }
+            Op::Dad(rp) => {
+                self.asm.mov(Reg::Rax, HL);
+                self.asm.alu(Alu::Add, Reg::Rax, Compiler::pair(rp));
+                self.asm.mov(Reg::Rcx, Reg::Rax);
+                self.asm.shr(Reg::Rcx, 16);
+                self.asm.alu_imm(Alu::And, F, 0xFE);
+                self.asm.alu(Alu::Or, F, Reg::Rcx);
+                self.asm.alu_imm(Alu::And, Reg::Rax, 0xFFFF);
+                self.asm.mov(HL, Reg::Rax);
+            }
+            Op::Ldax(rp) => {
+                self.read(Compiler::pair(rp));
+                self.asm.mov(A, Reg::Rax);
+            }
+            Op::Rlc | Op::Rrc | Op::Ral | Op::Rar => {
+                let left = matches!(decoded.op, Op::Rlc | Op::Ral);
+                // Bit going into A: its own end bit for RLC/RRC, the old carry for RAL/RAR
+                self.asm.mov(Reg::Rcx, F);
+                self.asm.alu_imm(Alu::And, Reg::Rcx, 0x01);
+                self.asm.mov(Reg::Rax, A);
+                if left {
+                    self.asm.shr(Reg::Rax, 7);
+                } else {
+                    self.asm.alu_imm(Alu::And, Reg::Rax, 0x01);
+                }
+                self.asm.alu_imm(Alu::And, F, 0xFE);
+                self.asm.alu(Alu::Or, F, Reg::Rax);
+                let fill = if matches!(decoded.op, Op::Rlc | Op::Rrc) { Reg::Rax } else { Reg::Rcx };
+                if left {
+                    self.asm.shl(A, 1);
+                    self.asm.alu(Alu::Or, A, fill);
+                    self.asm.alu_imm(Alu::And, A, 0xFF);
+                } else {
+                    self.asm.shr(A, 1);
+                    self.asm.shl(fill, 7);
+                    self.asm.alu(Alu::Or, A, fill);
+                }
+            }
+            Op::Shld => {
+                self.asm.mov_imm(Reg::Rax, imm16 as u32);
+                self.asm.movzx8(Reg::Rcx, HL);
+                self.write(Reg::Rax, Reg::Rcx);
+                self.asm.mov_imm(Reg::Rax, imm16.wrapping_add(1) as u32);
+                self.asm.mov(Reg::Rcx, HL);
+                self.asm.shr(Reg::Rcx, 8);
+                self.write(Reg::Rax, Reg::Rcx);
+                self.check_invalidated(done);
+            }
+            Op::Lhld => {
+                self.asm.mov_imm(Reg::Rax, imm16 as u32);
+                self.read(Reg::Rax);
+                self.asm.mov(HL, Reg::Rax);
+                self.asm.mov_imm(Reg::Rax, imm16.wrapping_add(1) as u32);
+                self.read(Reg::Rax);
+                self.asm.shl(Reg::Rax, 8);
+                self.asm.alu(Alu::Or, HL, Reg::Rax);
+            }
+            Op::Cma => self.asm.alu_imm(Alu::Xor, A, 0xFF),
+            Op::Sta => {
+                self.asm.mov_imm(Reg::Rax, imm16 as u32);
+                self.write(Reg::Rax, A);
+                self.check_invalidated(done);
+            }
+            Op::Lda => {
+                self.asm.mov_imm(Reg::Rax, imm16 as u32);
+                self.read(Reg::Rax);
+                self.asm.mov(A, Reg::Rax);
+            }
+            Op::Stc => self.asm.alu_imm(Alu::Or, F, 0x01),
+            Op::Cmc => self.asm.alu_imm(Alu::Xor, F, 0x01),
+            Op::Mov(d, s) => {
+                if s == 6 {
+                    self.read(HL);
+                } else {
+                    self.get8(Reg::Rax, s);
+                }
+                if d == 6 {
+                    self.write(HL, Reg::Rax);
+                    self.check_invalidated(done);
+                } else {
+                    self.set8(d, Reg::Rax);
+                }
+            }
+            Op::Alu(alu, s) => {
+                if s == 6 {
+                    self.read(HL);
+                    self.asm.mov(Reg::Rdx, Reg::Rax);
+                } else {
+                    self.get8(Reg::Rdx, s);
+                }
+                self.alu(alu);
+            }
+            Op::AluImmediate(alu) => {
+                self.asm.mov_imm(Reg::Rdx, imm8);
+                self.alu(alu);
+            }
+            Op::Rcc(cc) => {
+                let skip = self.unless(cc);
+                self.pop_word();
+                self.exit_to_eax(done.taken_ticks, done.count);
+                self.asm.bind(skip);
+                self.exit(done.next_pc as u32, done.ticks, done.count);
+            }
+            Op::Ret => {
+                self.pop_word();
+                self.exit_to_eax(done.ticks, done.count);
+            }
+            Op::Pop(rp) => {
+                if rp == 3 {
+                    self.read(SP);
+                    self.asm.mov(F, Reg::Rax);
+                    self.asm.alu_imm(Alu::And, F, 0xD7);
+                    self.asm.alu_imm(Alu::Or, F, 0x02);
+                    self.asm.mov(Reg::Rax, SP);
+                    self.asm.alu_imm(Alu::Add, Reg::Rax, 1);
+                    self.asm.alu_imm(Alu::And, Reg::Rax, 0xFFFF);
+                    self.read(Reg::Rax);
+                    self.asm.mov(A, Reg::Rax);
+                    self.asm.alu_imm(Alu::Add, SP, 2);
+                    self.asm.alu_imm(Alu::And, SP, 0xFFFF);
+                } else {
+                    self.pop_word();
+                    self.asm.mov(Compiler::pair(rp), Reg::Rax);
+                }
+            }
+            Op::Push(rp) => {
+                if rp == 3 {
+                    self.push_word(|c| c.asm.mov(Reg::Rcx, F), |c| c.asm.mov(Reg::Rcx, A));
+                } else {
+                    let pair = Compiler::pair(rp);
+                    self.push_word(|c| c.asm.movzx8(Reg::Rcx, pair), |c| {
+                        c.asm.mov(Reg::Rcx, pair);
+                        c.asm.shr(Reg::Rcx, 8);
+                    });
+                }
+                self.check_invalidated(done);
+            }
+            Op::Jcc(cc) => {
+                let skip = self.unless(cc);
+                self.exit(imm16 as u32, done.ticks, done.count);
+                self.asm.bind(skip);
+                self.exit(done.next_pc as u32, done.ticks, done.count);
+            }
+            Op::Jmp => self.exit(imm16 as u32, done.ticks, done.count),
+            Op::Ccc(cc) => {
+                let skip = self.unless(cc);
+                self.push_constant(done.next_pc);
+                self.exit(imm16 as u32, done.taken_ticks, done.count);
+                self.asm.bind(skip);
+                self.exit(done.next_pc as u32, done.ticks, done.count);
+            }
+            Op::Call => {
+                self.push_constant(done.next_pc);
+                self.exit(imm16 as u32, done.ticks, done.count);
+            }
+            Op::Rst(n) => {
+                self.push_constant(done.next_pc);
+                self.exit(n as u32 * 8, done.ticks, done.count);
+            }
+            Op::Pchl => {
+                self.asm.mov(Reg::Rax, HL);
+                self.exit_to_eax(done.ticks, done.count);
+            }
+            Op::Sphl => self.asm.mov(SP, HL),
+            Op::Xchg => {
+                self.asm.mov(Reg::Rax, DE);
+                self.asm.mov(DE, HL);
+                self.asm.mov(HL, Reg::Rax);
+            }
+            Op::Xthl => {
+                self.asm.store32(Reg::Rsp, SCRATCH_SLOT + 4, HL);
+                // Popping and pushing back leaves SP where it was
+                self.pop_word();
+                self.asm.mov(HL, Reg::Rax);
+                self.push_word(|c| {
+                    c.asm.load32(Reg::Rcx, Reg::Rsp, SCRATCH_SLOT + 4);
+                    c.asm.alu_imm(Alu::And, Reg::Rcx, 0xFF);
+                }, |c| {
+                    c.asm.load32(Reg::Rcx, Reg::Rsp, SCRATCH_SLOT + 4);
+                    c.asm.shr(Reg::Rcx, 8);
+                });
+                self.check_invalidated(done);
+            }
+            Op::Daa | Op::Out | Op::In | Op::Hlt | Op::Ei | Op::Di => {
+                unreachable!("{:?} is never compiled", decoded.op)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intel8080::{is_undocumented, ExecutionMode, OPCODES};
+    use crate::block_cache::matches_interpreter_on;
+
+    // Random straight-line code, so that every supported op gets compiled with all sorts of
+    // operands and flags, looping back to its start
+    fn random_program(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        };
+        let mut program = Vec::new();
+        while program.len() < len {
+            let opcode = random();
+            let op = OPCODES[opcode as usize];
+            if is_undocumented(opcode) || !supported(op) || op.ends_block() {
+                continue;
+            }
+            program.push(opcode);
+            for n in 1..op.size() {
+                // Keeps addresses in the top half of memory, away from the code
+                program.push(if n == 2 { random() | 0x80 } else { random() });
+            }
+        }
+        // JMP 0x0100
+        program.extend_from_slice(&[0xC3, 0x00, 0x01]);
+        program
+    }
+
+    #[test]
+    fn random_code_matches_interpreter(){
+        for seed in 1..=20 {
+            let program = random_program(seed, 300);
+            let mut cpus = [Intel8080::new(), Intel8080::new()];
+            cpus[1].execution_mode = ExecutionMode::JitDifferential;
+            cpus[1].set_jit_threshold(1);
+            for cpu in &mut cpus {
+                for (n, byte) in program.iter().enumerate() {
+                    cpu.memory.poke(0x0100 + n as u16, *byte);
+                }
+                let mut state = cpu.state();
+                state.pc = 0x0100;
+                state.sp = 0xF000;
+                cpu.set_state(&state);
+                cpu.run_cycles(100_000).unwrap();
+            }
+            assert_eq!(cpus[0].state(), cpus[1].state(), "seed {}", seed);
+            assert_eq!(cpus[0].total_instructions, cpus[1].total_instructions, "seed {}", seed);
+            assert!((0..=0xFFFF).all(|address| cpus[0].memory.peek(address) == cpus[1].memory.peek(address)), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn compiles_hot_blocks_only(){
+        let mut jit = JitCache::new();
+        let mut memory = crate::memory::FlatMemory::new();
+        // INR A; RET
+        memory.poke(0x0200, 0x3C);
+        memory.poke(0x0201, 0xC9);
+        let helpers = Helpers { read: 0, write: 0 };
+        jit.threshold = 3;
+        assert!(jit.lookup(&memory, 0x0200, helpers).is_none());
+        assert!(jit.lookup(&memory, 0x0200, helpers).is_none());
+        let block = jit.lookup(&memory, 0x0200, helpers).unwrap();
+        assert_eq!(block.prefix_ticks, 5);
+
+        let generation = jit.generation;
+        jit.invalidate(0x0202);
+        assert_eq!(jit.generation, generation);
+        jit.invalidate(0x0201);
+        assert_ne!(jit.generation, generation);
+        assert!(jit.pages.iter().all(Vec::is_empty));
+        // I/O always goes through the interpreter
+        memory.poke(0x0300, 0xD3);
+        jit.threshold = 0;
+        assert!(jit.lookup(&memory, 0x0300, helpers).is_none());
+    }
+
+    #[test]
+    fn matches_interpreter_on_cpu_tests(){
+        matches_interpreter_on("TST8080.COM", ExecutionMode::Jit);
+        matches_interpreter_on("8080PRE.COM", ExecutionMode::Jit);
+    }
+
+    // Takes minutes, run with cargo test --release --features jit -- --ignored
+    #[test]
+    #[ignore]
+    fn matches_interpreter_on_8080exm(){
+        matches_interpreter_on("8080EXM.COM", ExecutionMode::Jit);
+    }
+}
@@ -0,0 +1,39 @@
+// The core needs nothing beyond alloc. The std feature (on by default) adds file loading and
+// saving, console output on stdout, StdClock and the JIT.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod block_cache;
+#[cfg(feature = "dap")]
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
+pub mod flags;
+#[cfg(feature = "std")]
+pub mod gdb;
+pub mod golden;
+pub mod hash;
+pub mod host;
+pub mod intel8080;
+pub mod interrupts;
+pub mod invaders;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod memory;
+pub mod movie;
+pub mod recompiler;
+pub mod rewind;
+pub mod save_state;
+pub mod scheduler;
+pub mod shift_register;
+pub mod symbols;
+pub mod trace;
+#[cfg(feature = "jit")]
+pub mod x64;
+
+pub use debugger::Debugger;
+pub use disassembler::Disassembler;
+pub use intel8080::{CpuState, ExecutionMode, Flag, IOHandler, Intel8080, NullIO, StepResult, Trap, TrapPolicy};
+pub use memory::{FlatMemory, MappedMemory, MemoryBus};
+pub use shift_register::ShiftRegister;
@@ -1,19 +1,26 @@
-mod intel8080;
-mod shift_register;
-mod disassembler;
 mod audio;
 
 use std::{fs, thread};
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::channel;
-use std::time::Instant;
 use sdl2::audio::{AudioCallback, AudioSpecDesired, AudioSpecWAV};
-use crate::disassembler::Disassembler;
-use crate::intel8080::Intel8080;
-use crate::shift_register::ShiftRegister;
+use intel8080::debugger::Debugger;
+use intel8080::disassembler::Disassembler;
+use intel8080::gdb::GdbStub;
+#[cfg(feature = "dap")]
+use intel8080::dap::DapServer;
+use intel8080::Trap;
+use intel8080::intel8080::Intel8080;
+use intel8080::hash::fnv1a64;
+use intel8080::invaders::{Inputs, Invaders, REFRESH_RATE};
+use intel8080::movie::Movie;
+use intel8080::rewind::RewindBuffer;
+use intel8080::save_state;
+use intel8080::host::StdClock;
+use intel8080::scheduler::FrameScheduler;
 use sdl2::event::Event;
 use sdl2::keyboard::Scancode;
 use sdl2::libc::{printf, sleep, sprintf};
@@ -23,10 +30,16 @@ use sdl2::render::{TextureQuery, WindowCanvas};
 use sdl2::mixer::{Chunk, Channel, AUDIO_S16LSB, DEFAULT_CHANNELS, InitFlag};
 use crate::audio::MySdl2Audio;
 
+const ROM_PATH: &str = "cpu_tests/invaders.concatenated";
 const VIDEO_WIDTH: usize = 256;
 const VIDEO_HEIGHT: usize = 224;
 const VIDEO_SCALE: u32 = 5;
-const REFRESH_RATE: u32 = 60;
+const SAVE_STATE_DIR: &str = "saves";
+const REWIND_KEY: Scancode = Scancode::Backspace;
+const DEBUG_KEY: Scancode = Scancode::F9;
+// A snapshot every 6 frames, keeping the last minute
+const REWIND_INTERVAL: usize = 6;
+const REWIND_CAPACITY: usize = 600;
 
 struct Playback {
     data: Arc<Mutex<Vec<u8>>>,
@@ -88,6 +101,145 @@ fn sound(port:u8,data:u8,prev_data:u8,audio:&MySdl2Audio) {
     }
 }
 
+// F1-F4 save to slots 1-4, F5-F8 load them. Returns the slot and whether it is a load.
+fn save_slot(scancode: Scancode) -> Option<(u8, bool)> {
+    match scancode {
+        Scancode::F1 => Some((1, false)),
+        Scancode::F2 => Some((2, false)),
+        Scancode::F3 => Some((3, false)),
+        Scancode::F4 => Some((4, false)),
+        Scancode::F5 => Some((1, true)),
+        Scancode::F6 => Some((2, true)),
+        Scancode::F7 => Some((3, true)),
+        Scancode::F8 => Some((4, true)),
+        _ => None,
+    }
+}
+
+fn save_state_path(slot: u8) -> PathBuf {
+    Path::new(SAVE_STATE_DIR).join(format!("slot{}.state", slot))
+}
+
+fn use_save_slot(slot: u8, load: bool, rom_hash: u64, machine: &mut Invaders) {
+    let path = save_state_path(slot);
+    if load {
+        match save_state::load_from_file(&path, rom_hash, machine) {
+            Ok(()) => println!("Loaded state from slot {}", slot),
+            Err(error) => eprintln!("Could not load slot {}: {}", slot, error),
+        }
+    } else {
+        match save_state::save_to_file(&path, rom_hash, machine) {
+            Ok(()) => println!("Saved state to slot {}", slot),
+            Err(error) => eprintln!("Could not save slot {}: {}", slot, error),
+        }
+    }
+}
+
+// --record FILE [--from-slot N] records the session's inputs to a movie, starting at power-on
+// or from a save slot. --play FILE plays a movie in the window, --verify FILE plays it
+// headless and prints the RAM hash it ends with. --debug starts in the debugger, --gdb PORT
+// and --dap PORT wait for a GDB or DAP client to attach on PORT before opening the window.
+struct Options {
+    record: Option<PathBuf>,
+    from_slot: Option<u8>,
+    play: Option<PathBuf>,
+    verify: Option<PathBuf>,
+    debug: bool,
+    gdb: Option<u16>,
+    dap: Option<u16>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options { record: None, from_slot: None, play: None, verify: None, debug: false, gdb: None, dap: None };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--record" => options.record = Some(PathBuf::from(value()?)),
+            "--play" => options.play = Some(PathBuf::from(value()?)),
+            "--verify" => options.verify = Some(PathBuf::from(value()?)),
+            "--debug" => options.debug = true,
+            "--gdb" => {
+                let port = value()?;
+                options.gdb = Some(port.parse().map_err(|_| format!("bad port {}", port))?);
+            }
+            #[cfg(feature = "dap")]
+            "--dap" => {
+                let port = value()?;
+                options.dap = Some(port.parse().map_err(|_| format!("bad port {}", port))?);
+            }
+            "--from-slot" => {
+                let slot = value()?;
+                options.from_slot = match slot.parse() {
+                    Ok(slot @ 1..=4) => Some(slot),
+                    _ => return Err(format!("save slot must be 1-4, got {}", slot)),
+                };
+            }
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    if options.record.is_some() && options.play.is_some() {
+        return Err("--record and --play can't be used together".to_string());
+    }
+    if [options.debug, options.gdb.is_some(), options.dap.is_some()].iter().filter(|&&set| set).count() > 1 {
+        return Err("only one of --debug, --gdb and --dap can be used".to_string());
+    }
+    if options.from_slot.is_some() && options.record.is_none() {
+        return Err("--from-slot only applies to --record".to_string());
+    }
+    Ok(options)
+}
+
+// What drives the CPU: the terminal debugger, which stays out of the way until F9 or --debug
+// enters it, or a GDB or DAP client
+enum Control {
+    Terminal(Debugger),
+    Gdb(GdbStub),
+    #[cfg(feature = "dap")]
+    Dap(Box<DapServer>),
+}
+
+impl Control {
+    fn run_frame(&mut self, machine: &mut Invaders) -> Result<(), Trap> {
+        match self {
+            Control::Terminal(debugger) => {
+                let (stdin, mut stdout) = (std::io::stdin(), std::io::stdout());
+                machine.run_frame_with(|cpu, cycles| {
+                    debugger.run_cycles_interactive(cpu, cycles, &mut stdin.lock(), &mut stdout)
+                })
+            }
+            Control::Gdb(stub) => machine.run_frame_with(|cpu, cycles| stub.run_cycles(cpu, cycles)),
+            #[cfg(feature = "dap")]
+            Control::Dap(server) => machine.run_frame_with(|cpu, cycles| server.run_cycles(cpu, cycles)),
+        }
+    }
+
+    // The debugger or client asked for the emulator to close
+    fn quit(&self) -> bool {
+        match self {
+            Control::Terminal(debugger) => debugger.quit,
+            Control::Gdb(stub) => stub.killed,
+            #[cfg(feature = "dap")]
+            Control::Dap(server) => server.terminated,
+        }
+    }
+
+    // F9 only applies to the terminal debugger, clients have their own way to pause
+    fn pause(&mut self) {
+        if let Control::Terminal(debugger) = self {
+            debugger.pause();
+        }
+    }
+}
+
+fn verify_movie(path: &Path, rom: &[u8]) -> Result<(), String> {
+    let movie = Movie::load_from_file(path).map_err(|error| error.to_string())?;
+    let machine = movie.play(rom).map_err(|error| error.to_string())?;
+    println!("Played {} frames, ending on frame {}", movie.len(), machine.frame);
+    println!("RAM hash: {:016x}", machine.ram_hash());
+    Ok(())
+}
+
 fn map_scancode_to_inputs_enable(scancode: Scancode, inputs: &mut Inputs){
     match scancode {
         Scancode::A =>inputs.p1left = true,
@@ -118,61 +270,6 @@ fn map_scancode_to_inputs_disable(scancode: Scancode, inputs: &mut Inputs){
     }
 }
 
-struct Inputs {
-    p2start: bool,
-    p1start: bool,
-    p1shoot: bool,
-    p1left: bool,
-    p1right: bool,
-    p2shoot: bool,
-    p2left: bool,
-    p2right: bool,
-    coin: bool,
-}
-
-impl Inputs {
-    fn new() -> Inputs {
-        Inputs{
-            p2start:false,
-            p1start: false,
-            p1shoot: false,
-            p1left: false,
-            p1right: false,
-            p2shoot: false,
-            p2left: false,
-            p2right: false,
-            coin: false,
-        }
-    }
-}
-
-fn input0(inputs: &Inputs) ->u8{
-    let mut result = 0b00001110;
-    if inputs.p1shoot {result |= 1<<4} else {result &= !(1<<4)};
-    if inputs.p1left {result |= 1<<5} else {result &= !(1<<5)};
-    if inputs.p1right {result |= 1<<6} else {result &= !(1<<6)};
-    result
-}
-
-
-fn input1(inputs: &Inputs)->u8{
-    let mut result = 0b00001000;
-    if inputs.coin{result|=1} else  {result &= !(1)};
-    if inputs.p2start {result |= 1<<1} else {result &= !(1<<1)};
-    if inputs.p1start {result |= 1<<2} else {result &= !(1<<2)};
-    if inputs.p1shoot {result |= 1<<4} else {result &= !(1<<4)};
-    if inputs.p1left {result |= 1<<5} else {result &= !(1<<5)};
-    if inputs.p1right {result |= 1<<6} else {result &= !(1<<6)};
-    result
-}
-fn input2(inputs: &Inputs)->u8 {
-    let mut result = 0b00000011;
-    if inputs.p2shoot {result |= 1<<4} else {result &= !(1<<4)};
-    if inputs.p2left {result |= 1<<5} else {result &= !(1<<5)};
-    if inputs.p2right {result |= 1<<6} else {result &= !(1<<6)};
-    result
-}
-
 pub fn display_canvas(display_data: &[u8; VIDEO_WIDTH * VIDEO_HEIGHT / 8], scale: u32, canvas: &mut WindowCanvas) {
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
@@ -204,6 +301,23 @@ pub fn display_canvas(display_data: &[u8; VIDEO_WIDTH * VIDEO_HEIGHT / 8], scale
 }
 
 fn main() -> Result<(), String> {
+    let options = parse_options()?;
+    let prog = fs::read(ROM_PATH).expect("Unable to read file");
+    if let Some(path) = &options.verify {
+        return verify_movie(path, &prog);
+    }
+
+    let mut control = Control::Terminal(Debugger::new());
+    if options.debug {
+        control.pause();
+    }
+    if let Some(port) = options.gdb {
+        control = Control::Gdb(GdbStub::listen(port).map_err(|error| format!("GDB stub: {}", error))?);
+    }
+    #[cfg(feature = "dap")]
+    if let Some(port) = options.dap {
+        control = Control::Dap(Box::new(DapServer::listen(port).map_err(|error| format!("DAP server: {}", error))?));
+    }
 
     let sdl_context = sdl2::init()?;
     let video = sdl_context.video()?;
@@ -262,28 +376,37 @@ fn main() -> Result<(), String> {
     // let font = ttf_context.load_font(font_path, 24)?;
     // let texture_creator = canvas.texture_creator();
 
-    let mut shift_register = ShiftRegister::new();
-
-
-    let mut intel8080 = Intel8080::new();
-    let prog = fs::read("cpu_tests/invaders.concatenated").expect("Unable to read file");
     // let mut zeroes = vec![0u8; 0x100];
     // zeroes.extend(prog);
     // zeroes[0] = 0b11000011;
     // zeroes[1] = 0x00;
     // zeroes[2] = 0x01;
-    intel8080.load_program(prog);
-
-    let begin = Instant::now();
-    let mut last_interrupt = Instant::now();
-    let mut count = 0;
-    let mut count_interrupt = 1;
-    let mut countp = 0;
-    struct Avg{avg:f64,n:usize};
-    let mut avg_time = Avg{avg: 0.0,n:0};
-    let mut inputs = Inputs::new();
-    let mut prev_port3:u8=0;
-    let mut prev_port5:u8=0;
+    let rom_hash = fnv1a64(&prog);
+    let mut machine = Invaders::new(&prog);
+
+    let mut recording = match &options.record {
+        Some(_) => match options.from_slot {
+            Some(slot) => {
+                save_state::load_from_file(&save_state_path(slot), rom_hash, &mut machine)
+                    .map_err(|error| format!("Could not load slot {}: {}", slot, error))?;
+                Some(Movie::from_machine(rom_hash, &machine))
+            }
+            None => Some(Movie::power_on(rom_hash)),
+        },
+        None => None,
+    };
+    let mut playback = match &options.play {
+        Some(path) => {
+            let movie = Movie::load_from_file(path).map_err(|error| error.to_string())?;
+            machine = movie.start_machine(&prog).map_err(|error| error.to_string())?;
+            Some(movie)
+        }
+        None => None,
+    };
+
+    let mut scheduler = FrameScheduler::new(REFRESH_RATE, StdClock::new());
+    let mut rewind = RewindBuffer::new(REWIND_INTERVAL, REWIND_CAPACITY);
+    let mut rewinding = false;
 
     // Render text to a surface, then to a texture
     // let surface = font
@@ -303,81 +426,104 @@ fn main() -> Result<(), String> {
     // // canvas.clear();
     // // canvas.copy(&texture, None, Some(target))?;
     // // canvas.present();
+    let mut result = Ok(());
     'main_loop: loop {
-        intel8080.cycle();
-        if intel8080.oport.len() > 0 {
-            let (port, data) = intel8080.oport.pop().unwrap();
-            match port {
-                2 => shift_register.write_offset(data),
-                3 => {sound(port,data,prev_port3,&audio);prev_port3 = data;},
-                4 => shift_register.insert(data),
-                5 => {sound(port,data,prev_port5,&audio);prev_port5 = data;},
-
-                _ => {}
-            }
-        }
-
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'main_loop,
 
+                Event::KeyDown {
+                    scancode: Some(REWIND_KEY),
+                    repeat,
+                    ..
+                } => {
+                    if recording.is_some() || playback.is_some() {
+                        if !repeat {
+                            eprintln!("Rewinding is disabled while a movie is recording or playing");
+                        }
+                    } else {
+                        rewinding = true;
+                    }
+                }
+
+                Event::KeyDown {
+                    scancode: Some(DEBUG_KEY),
+                    repeat: false,
+                    ..
+                } => control.pause(),
+
+                Event::KeyUp {
+                    scancode: Some(REWIND_KEY),
+                    ..
+                } => rewinding = false,
+
                 Event::KeyDown {
                     scancode: Some(scancode),
+                    repeat,
                     ..
                 } => {
-                    map_scancode_to_inputs_enable(scancode, &mut inputs);
+                    match save_slot(scancode) {
+                        Some((_, true)) if recording.is_some() || playback.is_some() => {
+                            eprintln!("Loading states is disabled while a movie is recording or playing");
+                        }
+                        Some((slot, load)) if !repeat => {
+                            use_save_slot(slot, load, rom_hash, &mut machine);
+                            scheduler.reset();
+                        }
+                        Some(_) => {}
+                        None => map_scancode_to_inputs_enable(scancode, &mut machine.cpu.io.inputs),
+                    }
                 }
 
                 Event::KeyUp {
                     scancode: Some(scancode),
                     ..
                 } => {
-                    map_scancode_to_inputs_disable(scancode, &mut inputs);
+                    map_scancode_to_inputs_disable(scancode, &mut machine.cpu.io.inputs);
                 }
 
                 _ => {}
             }
         }
-        intel8080.iport[0] = input0(&inputs);
-        intel8080.iport[1] = input1(&inputs);
-        intel8080.iport[2] = input2(&inputs);
-        intel8080.iport[3] = shift_register.result();
-
-        if (intel8080.total_ticks >33333*count as usize) {
-            count += 1;
-            // println!("display");
-            let display_data = &intel8080.memory[0x2400..=0x3FFF];
-            // for data in display_data {
-            //     if *data != 0 {
-            //         println!("Display data exist!!! {}",*data);
-            //     }
-            // }
-            display_canvas(display_data.try_into().expect(""),VIDEO_SCALE,&mut canvas);
-            intel8080.interrupt_data.push(0b11010111);
-        }
-
-        if (intel8080.total_ticks > 1000000*countp as usize){
-            countp+=1;
-            // println!("{}", intel8080.total_ticks as f32 / begin.elapsed().as_secs_f32());
-        }
 
-        if intel8080.total_ticks >16666*count_interrupt && intel8080.interrupt_enabled {
-            count_interrupt += 2;
-            avg_time.avg = (avg_time.avg * avg_time.n as f64 + last_interrupt.elapsed().as_secs_f64()) / (avg_time.n+1) as f64;
-            avg_time.n += 1;
-            // println!("Avg: {}", avg_time.avg);
-            intel8080.interrupt_data.push(0b11001111);
-            last_interrupt = Instant::now();
+        if rewinding {
+            rewind.rewind(&mut machine);
+        } else {
+            if let Some(movie) = &playback {
+                match movie.inputs_at(machine.frame) {
+                    Some(inputs) => machine.cpu.io.inputs = inputs,
+                    None => {
+                        println!("Movie finished on frame {}", machine.frame);
+                        machine.cpu.io.inputs = Inputs::new();
+                        playback = None;
+                    }
+                }
+            }
+            if let Some(movie) = &mut recording {
+                movie.record(machine.frame, &machine.cpu.io.inputs);
+            }
+            if let Err(trap) = control.run_frame(&mut machine) {
+                result = Err(trap.to_string());
+                break 'main_loop;
+            }
+            if control.quit() {
+                break 'main_loop;
+            }
+            rewind.record(&machine);
+            for write in machine.cpu.io.sound_writes.drain(..) {
+                sound(write.port, write.data, write.prev_data, &audio);
+            }
         }
+        display_canvas(machine.video_ram().try_into().expect(""),VIDEO_SCALE,&mut canvas);
 
-        intel8080.last_cycle_time = Instant::now();
-        //
-        // if (intel8080.total_ticks>100000){
-        //     break;
-        // }
-
-        // port_operations(&mut intel8080);
+        scheduler.wait_for_next_frame();
     }
 
-    Ok(())
-}
\ No newline at end of file
+    if let (Some(movie), Some(path)) = (&recording, &options.record) {
+        match movie.save_to_file(path) {
+            Ok(()) => println!("Recorded {} frames to {}", movie.len(), path.display()),
+            Err(error) => eprintln!("Could not save movie: {}", error),
+        }
+    }
+    result
+}
@@ -0,0 +1,279 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{Index, IndexMut};
+use crate::save_state::{SaveStateError, Snapshot, StateReader, StateWriter};
+
+// The CPU's view of the address space. read/write are bus cycles made by instructions,
+// fetch_opcode is the M1 cycle. peek/poke bypass the machine's mapping rules (ROM
+// protection, side effects) and are meant for loaders and tooling.
+pub trait MemoryBus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    fn fetch_opcode(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    fn peek(&self, address: u16) -> u8;
+    fn poke(&mut self, address: u16, value: u8);
+}
+
+// 64 KiB of plain RAM, the default bus. It lives on the heap so CPUs stay small to move
+// between threads.
+#[derive(Clone)]
+pub struct FlatMemory {
+    data: Box<[u8; 65536]>,
+}
+
+impl FlatMemory {
+    pub fn new() -> FlatMemory {
+        FlatMemory { data: vec![0; 65536].into_boxed_slice().try_into().unwrap() }
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> FlatMemory {
+        FlatMemory::new()
+    }
+}
+
+impl MemoryBus for FlatMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.data[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.data[address as usize] = value;
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.data[address as usize]
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        self.data[address as usize] = value;
+    }
+}
+
+impl Snapshot for FlatMemory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data[..]);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let len = self.data.len();
+        self.data.copy_from_slice(reader.read_bytes(len)?);
+        Ok(())
+    }
+}
+
+impl Index<usize> for FlatMemory {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        &self.data[index]
+    }
+}
+
+impl IndexMut<usize> for FlatMemory {
+    fn index_mut(&mut self, index: usize) -> &mut u8 {
+        &mut self.data[index]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RegionKind {
+    Rom,
+    Ram,
+    // Accesses are redirected to the same offset from the given address
+    Mirror(u16),
+    Unmapped,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+// Address space built from ROM, RAM, mirrored and unmapped regions. Storage is indexed by
+// the address an access resolves to, so a mirror shares bytes with the region it points at.
+// Later regions take priority over earlier ones where they overlap.
+#[derive(Clone)]
+pub struct MappedMemory {
+    storage: Vec<u8>,
+    regions: Vec<Region>,
+    open_bus: u8,
+}
+
+impl MappedMemory {
+    // Everything starts unmapped
+    pub fn new() -> MappedMemory {
+        MappedMemory { storage: vec![0; 65536], regions: Vec::new(), open_bus: 0xFF }
+    }
+
+    // 8K ROM, 8K RAM (including video RAM from 0x2400) and the RAM mirror at 0x4000
+    pub fn space_invaders() -> MappedMemory {
+        MappedMemory::new()
+            .rom(0x0000, 0x1FFF)
+            .ram(0x2000, 0x3FFF)
+            .mirror(0x4000, 0x5FFF, 0x2000)
+    }
+
+    pub fn rom(self, start: u16, end: u16) -> MappedMemory {
+        self.region(start, end, RegionKind::Rom)
+    }
+
+    pub fn ram(self, start: u16, end: u16) -> MappedMemory {
+        self.region(start, end, RegionKind::Ram)
+    }
+
+    pub fn mirror(self, start: u16, end: u16, target: u16) -> MappedMemory {
+        self.region(start, end, RegionKind::Mirror(target))
+    }
+
+    pub fn unmapped(self, start: u16, end: u16) -> MappedMemory {
+        self.region(start, end, RegionKind::Unmapped)
+    }
+
+    // Value seen when reading an unmapped address
+    pub fn with_open_bus(mut self, value: u8) -> MappedMemory {
+        self.open_bus = value;
+        self
+    }
+
+    fn region(mut self, start: u16, end: u16, kind: RegionKind) -> MappedMemory {
+        assert!(start <= end, "region start {:#06x} is after end {:#06x}", start, end);
+        self.regions.push(Region { start, end, kind });
+        self
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    // Backing bytes for a range of resolved addresses, e.g. video RAM for the renderer
+    pub fn slice(&self, start: u16, end: u16) -> &[u8] {
+        &self.storage[start as usize..=end as usize]
+    }
+
+    fn kind_at(&self, address: u16) -> RegionKind {
+        self.regions.iter()
+            .rev()
+            .find(|region| region.start <= address && address <= region.end)
+            .map_or(RegionKind::Unmapped, |region| match region.kind {
+                RegionKind::Mirror(target) => RegionKind::Mirror(target.wrapping_add(address - region.start)),
+                kind => kind,
+            })
+    }
+
+    // Follows mirrors until a ROM, RAM or unmapped region is reached
+    fn resolve(&self, address: u16) -> (u16, RegionKind) {
+        let mut address = address;
+        // A mirror chain longer than the region list must loop back on itself
+        for _ in 0..=self.regions.len() {
+            match self.kind_at(address) {
+                RegionKind::Mirror(target) => address = target,
+                kind => return (address, kind),
+            }
+        }
+        (address, RegionKind::Unmapped)
+    }
+}
+
+impl Default for MappedMemory {
+    fn default() -> MappedMemory {
+        MappedMemory::new()
+    }
+}
+
+impl MemoryBus for MappedMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if let (resolved, RegionKind::Ram) = self.resolve(address) {
+            self.storage[resolved as usize] = value;
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match self.resolve(address) {
+            (resolved, RegionKind::Rom | RegionKind::Ram) => self.storage[resolved as usize],
+            _ => self.open_bus,
+        }
+    }
+
+    // Writes through to ROM so loaders can fill it, but still drops writes to unmapped space
+    fn poke(&mut self, address: u16, value: u8) {
+        if let (resolved, RegionKind::Rom | RegionKind::Ram) = self.resolve(address) {
+            self.storage[resolved as usize] = value;
+        }
+    }
+}
+
+// Only RAM is saved, ROM contents are identified by the ROM hash in the save state header
+impl Snapshot for MappedMemory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for region in self.regions.iter().filter(|region| region.kind == RegionKind::Ram) {
+            writer.write_u32((region.end - region.start) as u32 + 1);
+            writer.write_bytes(self.slice(region.start, region.end));
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for region in self.regions.iter().filter(|region| region.kind == RegionKind::Ram) {
+            let len = (region.end - region.start) as usize + 1;
+            if reader.read_u32()? as usize != len {
+                return Err(SaveStateError::Corrupt("RAM region size differs"));
+            }
+            let bytes = reader.read_bytes(len)?;
+            self.storage[region.start as usize..=region.end as usize].copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_ignores_writes(){
+        let mut memory = MappedMemory::space_invaders();
+        memory.poke(0x0010, 0xab);
+        memory.write(0x0010, 0xcd);
+        assert_eq!(memory.read(0x0010), 0xab);
+    }
+
+    #[test]
+    fn mirror_shares_ram(){
+        let mut memory = MappedMemory::space_invaders();
+        memory.write(0x4123, 0x5a);
+        assert_eq!(memory.read(0x2123), 0x5a);
+        memory.write(0x3FFF, 0xa5);
+        assert_eq!(memory.read(0x5FFF), 0xa5);
+        assert_eq!(memory.slice(0x2123, 0x2123), &[0x5a]);
+    }
+
+    #[test]
+    fn unmapped_reads_open_bus(){
+        let mut memory = MappedMemory::space_invaders().with_open_bus(0x00);
+        memory.write(0x8000, 0x12);
+        memory.poke(0x8000, 0x12);
+        assert_eq!(memory.read(0x8000), 0x00);
+        assert_eq!(MappedMemory::new().read(0x1234), 0xFF);
+    }
+
+    #[test]
+    fn later_regions_take_priority(){
+        let mut memory = MappedMemory::new().ram(0x0000, 0xFFFF).rom(0x1000, 0x1FFF);
+        memory.write(0x1000, 0x11);
+        memory.write(0x2000, 0x22);
+        assert_eq!(memory.read(0x1000), 0x00);
+        assert_eq!(memory.read(0x2000), 0x22);
+    }
+}
@@ -0,0 +1,309 @@
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{fs, io, path::Path};
+use crate::hash::fnv1a64;
+use crate::intel8080::{ExecutionMode, Trap};
+use crate::invaders::{Inputs, Invaders};
+use crate::save_state::{self, SaveStateError, StateReader, StateWriter};
+
+// Movie files start with MAGIC, the format version, the ROM hash and the start state, then
+// the frame the movie starts on and the inputs held during each frame from there on. The
+// machine is deterministic, so replaying the inputs from the same start state reproduces
+// the session exactly.
+const MAGIC: [u8; 4] = *b"I8MV";
+pub const VERSION: u16 = 1;
+
+const START_POWER_ON: u8 = 0;
+const START_SAVE_STATE: u8 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    NotAMovie,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u64, found: u64 },
+    Truncated,
+    Corrupt(&'static str),
+    // The embedded start state could not be loaded
+    StartState(SaveStateError),
+    // The machine trapped during headless playback
+    Trap(Trap),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            MovieError::Io(error) => write!(f, "{}", error),
+            MovieError::NotAMovie => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "movie version {} is not supported (expected {})", version, VERSION)
+            }
+            MovieError::RomMismatch { expected, found } => {
+                write!(f, "movie was recorded with ROM {:016x}, but {:016x} is loaded", found, expected)
+            }
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Corrupt(what) => write!(f, "movie is corrupt: {}", what),
+            MovieError::StartState(error) => write!(f, "movie start state: {}", error),
+            MovieError::Trap(trap) => write!(f, "playback stopped on {}", trap),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for MovieError {
+    fn from(error: io::Error) -> MovieError {
+        MovieError::Io(error)
+    }
+}
+
+// Only the reader's own errors reach this, start states are decoded separately
+impl From<SaveStateError> for MovieError {
+    fn from(error: SaveStateError) -> MovieError {
+        match error {
+            SaveStateError::Truncated => MovieError::Truncated,
+            error => MovieError::StartState(error),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum StartState {
+    PowerOn,
+    // An encoded save state, made with the same ROM as the movie
+    SaveState(Vec<u8>),
+}
+
+pub struct Movie {
+    pub rom_hash: u64,
+    pub start: StartState,
+    // Value of Invaders::frame when the first recorded frame runs
+    pub start_frame: usize,
+    frames: Vec<u16>,
+}
+
+impl Movie {
+    pub fn power_on(rom_hash: u64) -> Movie {
+        Movie { rom_hash, start: StartState::PowerOn, start_frame: 0, frames: Vec::new() }
+    }
+
+    // Starts a recording from wherever the machine is now
+    pub fn from_machine(rom_hash: u64, machine: &Invaders) -> Movie {
+        Movie {
+            rom_hash,
+            start: StartState::SaveState(save_state::encode(rom_hash, machine)),
+            start_frame: machine.frame,
+            frames: Vec::new(),
+        }
+    }
+
+    // Builds the machine the movie starts from
+    pub fn start_machine(&self, rom: &[u8]) -> Result<Invaders, MovieError> {
+        let found = fnv1a64(rom);
+        if found != self.rom_hash {
+            return Err(MovieError::RomMismatch { expected: found, found: self.rom_hash });
+        }
+        let mut machine = Invaders::new(rom);
+        if let StartState::SaveState(state) = &self.start {
+            save_state::decode(state, self.rom_hash, &mut machine).map_err(MovieError::StartState)?;
+            if machine.frame != self.start_frame {
+                return Err(MovieError::Corrupt("start state is not on the start frame"));
+            }
+        }
+        Ok(machine)
+    }
+
+    // Call before running `frame`. Recording an earlier frame again, after a rewind,
+    // drops everything recorded after it.
+    pub fn record(&mut self, frame: usize, inputs: &Inputs) {
+        if frame < self.start_frame {
+            return;
+        }
+        let index = frame - self.start_frame;
+        assert!(index <= self.frames.len(), "movie frames must be recorded in order");
+        self.frames.truncate(index);
+        self.frames.push(inputs.to_bits());
+    }
+
+    // Inputs to hold during `frame`, None outside the recording
+    pub fn inputs_at(&self, frame: usize) -> Option<Inputs> {
+        let index = frame.checked_sub(self.start_frame)?;
+        self.frames.get(index).map(|bits| Inputs::from_bits(*bits))
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn end_frame(&self) -> usize {
+        self.start_frame + self.frames.len()
+    }
+
+    // Plays the whole movie without a frontend and returns the machine as it ends up. Uses the
+    // block cache, which must not change the result.
+    pub fn play(&self, rom: &[u8]) -> Result<Invaders, MovieError> {
+        let mut machine = self.start_machine(rom)?;
+        machine.cpu.execution_mode = ExecutionMode::BlockCache;
+        while let Some(inputs) = self.inputs_at(machine.frame) {
+            machine.cpu.io.inputs = inputs;
+            machine.run_frame().map_err(MovieError::Trap)?;
+            machine.cpu.io.sound_writes.clear();
+        }
+        Ok(machine)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(&MAGIC);
+        writer.write_u16(VERSION);
+        writer.write_u64(self.rom_hash);
+        match &self.start {
+            StartState::PowerOn => writer.write_u8(START_POWER_ON),
+            StartState::SaveState(state) => {
+                writer.write_u8(START_SAVE_STATE);
+                writer.write_u32(state.len() as u32);
+                writer.write_bytes(state);
+            }
+        }
+        writer.write_u64(self.start_frame as u64);
+        writer.write_u32(self.frames.len() as u32);
+        for bits in &self.frames {
+            writer.write_u16(*bits);
+        }
+        writer.into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Movie, MovieError> {
+        if bytes.len() < MAGIC.len() || bytes[..4] != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let mut reader = StateReader::new(&bytes[4..]);
+        let version = reader.read_u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_hash = reader.read_u64()?;
+        let start = match reader.read_u8()? {
+            START_POWER_ON => StartState::PowerOn,
+            START_SAVE_STATE => {
+                let len = reader.read_u32()? as usize;
+                StartState::SaveState(reader.read_bytes(len)?.to_vec())
+            }
+            _ => return Err(MovieError::Corrupt("unknown start state")),
+        };
+        let start_frame = reader.read_u64()? as usize;
+        if start == StartState::PowerOn && start_frame != 0 {
+            return Err(MovieError::Corrupt("power-on movie does not start on frame 0"));
+        }
+        let count = reader.read_u32()? as usize;
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            frames.push(reader.read_u16()?);
+        }
+        if !reader.is_empty() {
+            return Err(MovieError::Corrupt("trailing data"));
+        }
+        Ok(Movie { rom_hash, start, start_frame, frames })
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save_to_file(&self, path: &Path) -> Result<(), MovieError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, self.encode())?;
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_from_file(path: &Path) -> Result<Movie, MovieError> {
+        Movie::decode(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = include_bytes!("../cpu_tests/invaders.concatenated");
+
+    // Inserts a coin, starts a one player game and wanders about shooting
+    fn scripted_inputs(frame: usize) -> Inputs {
+        let mut inputs = Inputs::new();
+        inputs.coin = (60..70).contains(&frame);
+        inputs.p1start = (120..130).contains(&frame);
+        inputs.p1shoot = frame % 40 < 5;
+        inputs.p1left = frame % 200 < 100;
+        inputs.p1right = !inputs.p1left;
+        inputs
+    }
+
+    #[test]
+    fn playback_reproduces_recording(){
+        let rom_hash = fnv1a64(ROM);
+        let mut machine = Invaders::new(ROM);
+        let mut movie = Movie::power_on(rom_hash);
+        assert!(movie.is_empty());
+        for _ in 0..400 {
+            machine.cpu.io.inputs = scripted_inputs(machine.frame);
+            movie.record(machine.frame, &machine.cpu.io.inputs);
+            machine.run_frame().unwrap();
+        }
+
+        let movie = Movie::decode(&movie.encode()).unwrap();
+        assert_eq!(movie.len(), 400);
+        let played = movie.play(ROM).unwrap();
+        assert_eq!(played.frame, 400);
+        assert_eq!(played.ram_hash(), machine.ram_hash());
+        assert_eq!(played.cpu.state(), machine.cpu.state());
+    }
+
+    #[test]
+    fn starts_from_save_state(){
+        let rom_hash = fnv1a64(ROM);
+        let mut machine = Invaders::new(ROM);
+        for _ in 0..100 {
+            machine.run_frame().unwrap();
+        }
+        let mut movie = Movie::from_machine(rom_hash, &machine);
+        for _ in 0..100 {
+            machine.cpu.io.inputs = scripted_inputs(machine.frame);
+            movie.record(machine.frame, &machine.cpu.io.inputs);
+            machine.run_frame().unwrap();
+        }
+
+        let movie = Movie::decode(&movie.encode()).unwrap();
+        assert_eq!(movie.start_frame, 100);
+        assert_eq!(movie.end_frame(), 200);
+        assert_eq!(movie.play(ROM).unwrap().ram_hash(), machine.ram_hash());
+    }
+
+    #[test]
+    fn rerecording_truncates(){
+        let mut movie = Movie::power_on(0);
+        let mut inputs = Inputs::new();
+        for frame in 0..10 {
+            movie.record(frame, &inputs);
+        }
+        inputs.coin = true;
+        movie.record(4, &inputs);
+        assert_eq!(movie.len(), 5);
+        assert_eq!(movie.inputs_at(4), Some(inputs));
+        assert_eq!(movie.inputs_at(5), None);
+    }
+
+    #[test]
+    fn rejects_bad_files(){
+        let movie = Movie::power_on(0x1234);
+        assert!(matches!(movie.start_machine(ROM), Err(MovieError::RomMismatch { found: 0x1234, .. })));
+        let bytes = movie.encode();
+        assert!(matches!(Movie::decode(&bytes[..bytes.len() - 1]), Err(MovieError::Truncated)));
+        assert!(matches!(Movie::decode(b"I8SS"), Err(MovieError::NotAMovie)));
+    }
+}
@@ -0,0 +1,673 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use crate::block_cache::{decode_block, DecodedOp};
+use crate::hash::fnv1a64;
+use crate::intel8080::{IOHandler, Intel8080, Op, StepResult, Trap, TrapPolicy, OPCODES};
+use crate::memory::{FlatMemory, MemoryBus};
+
+// Static recompilation of 8080 code into Rust source. Code reachable from the entry points
+// through direct jumps, calls and fallthrough is split into basic blocks, and each block
+// becomes a function on a Machine. Anything that can't be followed statically (RET, PCHL,
+// interrupts, code in RAM) goes through the run_block dispatcher, and PCs without a block
+// fall back to the interpreter. The generated code assumes the ROM isn't written to.
+
+// A ROM split into blocks, ready for generate
+pub struct Program {
+    origin: u16,
+    rom_len: usize,
+    rom_hash: u64,
+    blocks: BTreeMap<u16, Vec<DecodedOp>>,
+}
+
+impl Program {
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // Bytes of the ROM covered by at least one block
+    pub fn covered_bytes(&self) -> usize {
+        let mut covered = vec![false; self.rom_len];
+        for ops in self.blocks.values() {
+            for decoded in ops {
+                let size = decoded.op.size();
+                for n in 1..=size {
+                    let offset = decoded.next_pc.wrapping_sub(n).wrapping_sub(self.origin);
+                    covered[offset as usize] = true;
+                }
+            }
+        }
+        covered.iter().filter(|covered| **covered).count()
+    }
+}
+
+// Finds every block reachable from `entries` in `rom`, which is mapped at `origin`. Interrupt
+// handlers have to be listed as entries, they are only ever reached through INTA.
+pub fn discover(rom: &[u8], origin: u16, entries: &[u16]) -> Program {
+    let mut memory = FlatMemory::new();
+    for (n, byte) in rom.iter().enumerate() {
+        memory.poke(origin.wrapping_add(n as u16), *byte);
+    }
+    let in_rom = |address: u16| (address.wrapping_sub(origin) as usize) < rom.len();
+
+    let mut blocks = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+    let mut pending = entries.to_vec();
+    while let Some(start) = pending.pop() {
+        if !in_rom(start) || !seen.insert(start) {
+            continue;
+        }
+        // EI and HLT change how interrupts are taken, so the interpreter runs them
+        let mut ops = decode_block(&memory, start, |op| !matches!(op, Op::Ei | Op::Hlt));
+        let in_bounds = ops.iter().take_while(|decoded| in_rom(decoded.next_pc.wrapping_sub(1))).count();
+        ops.truncate(in_bounds);
+        let Some(last) = ops.last() else {
+            if matches!(OPCODES[memory.peek(start) as usize], Op::Ei | Op::Hlt) {
+                pending.push(start.wrapping_add(1));
+            }
+            continue;
+        };
+
+        let target = u16::from_le_bytes([last.bytes[1], last.bytes[2]]);
+        match last.op {
+            Op::Jmp => pending.push(target),
+            Op::Jcc(_) | Op::Ccc(_) | Op::Call => pending.extend([target, last.next_pc]),
+            Op::Rst(n) => pending.extend([n as u16 * 8, last.next_pc]),
+            Op::Ret | Op::Pchl => {}
+            // Conditional returns, blocks that ran out of room and blocks that stopped before
+            // an instruction the interpreter has to run
+            _ => pending.push(last.next_pc),
+        }
+        blocks.insert(start, ops);
+    }
+    Program { origin, rom_len: rom.len(), rom_hash: fnv1a64(rom), blocks }
+}
+
+// Rust source for a module with a block_XXXX function per block and the run_block dispatcher.
+// `crate_path` is how the module refers to this crate, normally "intel8080".
+pub fn generate(program: &Program, crate_path: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "// Recompiled from a {} byte ROM at {:#06x} (FNV-1a {:016x}) by the recompile tool.",
+        program.rom_len, program.origin, program.rom_hash).unwrap();
+    writeln!(out, "// Don't edit, regenerate it instead.").unwrap();
+    writeln!(out, "use {}::intel8080::IOHandler;", crate_path).unwrap();
+    writeln!(out, "use {}::memory::MemoryBus;", crate_path).unwrap();
+    writeln!(out, "use {}::recompiler::Machine;", crate_path).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "// Runs the block at m.pc, stopping early once m.cpu.total_ticks reaches `until`. Returns").unwrap();
+    writeln!(out, "// false if there is no block there.").unwrap();
+    writeln!(out, "pub fn run_block<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) -> bool {{").unwrap();
+    writeln!(out, "    match m.pc {{").unwrap();
+    for start in program.blocks.keys() {
+        writeln!(out, "        0x{:04x} => block_{:04x}(m, until),", start, start).unwrap();
+    }
+    writeln!(out, "        _ => return false,").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "    true").unwrap();
+    writeln!(out, "}}").unwrap();
+    for (start, ops) in &program.blocks {
+        writeln!(out).unwrap();
+        let until = if ops.len() > 1 { "until" } else { "_until" };
+        writeln!(out, "fn block_{:04x}<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, {}: usize) {{", start, until).unwrap();
+        for (index, decoded) in ops.iter().enumerate() {
+            let address = decoded.next_pc.wrapping_sub(decoded.op.size());
+            writeln!(out, "    // {:04x}  {}", address, mnemonic(decoded)).unwrap();
+            for line in statements(decoded) {
+                writeln!(out, "    {}", line).unwrap();
+            }
+            let (states, _) = decoded.op.states();
+            if index + 1 < ops.len() {
+                writeln!(out, "    if m.retire({}) >= until {{ m.pc = 0x{:04x}; return; }}", states, decoded.next_pc).unwrap();
+            } else {
+                if !decoded.op.ends_block() {
+                    writeln!(out, "    m.pc = 0x{:04x};", decoded.next_pc).unwrap();
+                }
+                writeln!(out, "    m.retire({});", states).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "m", "a"];
+const PAIRS: [&str; 4] = ["bc", "de", "hl", "sp"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbb", "ana", "xra", "ora", "cmp"];
+
+// Rust statements for one instruction, leaving out its timing
+fn statements(decoded: &DecodedOp) -> Vec<String> {
+    let n = decoded.bytes[1];
+    let nn = u16::from_le_bytes([decoded.bytes[1], decoded.bytes[2]]);
+    let next = decoded.next_pc;
+    // Register pairs other than SP are read and written through methods
+    let pair = |rp: u8| if rp == 3 { "m.sp".to_string() } else { format!("m.{}()", PAIRS[rp as usize]) };
+    let set_pair = |rp: u8, value: String| if rp == 3 {
+        format!("m.sp = {};", value)
+    } else {
+        format!("m.set_{}({});", PAIRS[rp as usize], value)
+    };
+    let extra = decoded.op.states().1 - decoded.op.states().0;
+    match decoded.op {
+        Op::Nop => vec![],
+        Op::Lxi(rp) => vec![set_pair(rp, format!("0x{:04x}", nn))],
+        Op::Stax(rp) => vec![format!("m.write({}, m.a);", pair(rp))],
+        Op::Inx(rp) => vec![set_pair(rp, format!("{}.wrapping_add(1)", pair(rp)))],
+        Op::Dcx(rp) => vec![set_pair(rp, format!("{}.wrapping_sub(1)", pair(rp)))],
+        Op::Inr(6) | Op::Dcr(6) => {
+            let method = if matches!(decoded.op, Op::Inr(_)) { "inr" } else { "dcr" };
+            vec!["let v = m.read(m.hl());".to_string(), format!("let v = m.{}(v);", method), "m.write(m.hl(), v);".to_string()]
+        }
+        Op::Inr(r) => vec![format!("m.{} = m.inr(m.{});", REGISTERS[r as usize], REGISTERS[r as usize])],
+        Op::Dcr(r) => vec![format!("m.{} = m.dcr(m.{});", REGISTERS[r as usize], REGISTERS[r as usize])],
+        Op::Mvi(6) => vec![format!("m.write(m.hl(), 0x{:02x});", n)],
+        Op::Mvi(r) => vec![format!("m.{} = 0x{:02x};", REGISTERS[r as usize], n)],
+        Op::Dad(rp) => vec![format!("m.dad({});", pair(rp))],
+        Op::Ldax(rp) => vec![format!("m.a = m.read({});", pair(rp))],
+        Op::Rlc => vec!["m.rlc();".to_string()],
+        Op::Rrc => vec!["m.rrc();".to_string()],
+        Op::Ral => vec!["m.ral();".to_string()],
+        Op::Rar => vec!["m.rar();".to_string()],
+        Op::Shld => vec![format!("m.write_word(0x{:04x}, m.hl());", nn)],
+        Op::Daa => vec!["m.daa();".to_string()],
+        Op::Lhld => vec![format!("let v = m.read_word(0x{:04x});", nn), "m.set_hl(v);".to_string()],
+        Op::Cma => vec!["m.a = !m.a;".to_string()],
+        Op::Sta => vec![format!("m.write(0x{:04x}, m.a);", nn)],
+        Op::Stc => vec!["m.f |= 0x01;".to_string()],
+        Op::Lda => vec![format!("m.a = m.read(0x{:04x});", nn)],
+        Op::Cmc => vec!["m.f ^= 0x01;".to_string()],
+        Op::Mov(6, s) => vec![format!("m.write(m.hl(), m.{});", REGISTERS[s as usize])],
+        Op::Mov(d, 6) => vec![format!("m.{} = m.read(m.hl());", REGISTERS[d as usize])],
+        Op::Mov(d, s) => vec![format!("m.{} = m.{};", REGISTERS[d as usize], REGISTERS[s as usize])],
+        Op::Alu(alu, 6) => vec!["let v = m.read(m.hl());".to_string(), format!("m.{}(v);", ALU[alu as usize])],
+        Op::Alu(alu, s) => vec![format!("m.{}(m.{});", ALU[alu as usize], REGISTERS[s as usize])],
+        Op::AluImmediate(alu) => vec![format!("m.{}(0x{:02x});", ALU[alu as usize], n)],
+        Op::Rcc(cc) => vec![
+            format!("if m.condition({}) {{", cc),
+            format!("    m.cpu.total_ticks += {};", extra),
+            "    m.pc = m.pop();".to_string(),
+            "} else {".to_string(),
+            format!("    m.pc = 0x{:04x};", next),
+            "}".to_string(),
+        ],
+        Op::Pop(3) => vec!["let v = m.pop();".to_string(), "m.set_psw(v);".to_string()],
+        Op::Pop(rp) => vec!["let v = m.pop();".to_string(), set_pair(rp, "v".to_string())],
+        Op::Jcc(cc) => vec![format!("m.pc = if m.condition({}) {{ 0x{:04x} }} else {{ 0x{:04x} }};", cc, nn, next)],
+        Op::Jmp => vec![format!("m.pc = 0x{:04x};", nn)],
+        Op::Ccc(cc) => vec![
+            format!("if m.condition({}) {{", cc),
+            format!("    m.cpu.total_ticks += {};", extra),
+            format!("    m.push(0x{:04x});", next),
+            format!("    m.pc = 0x{:04x};", nn),
+            "} else {".to_string(),
+            format!("    m.pc = 0x{:04x};", next),
+            "}".to_string(),
+        ],
+        Op::Push(3) => vec!["m.push(m.psw());".to_string()],
+        Op::Push(rp) => vec![format!("m.push({});", pair(rp))],
+        Op::Rst(n) => vec![format!("m.push(0x{:04x});", next), format!("m.pc = 0x{:04x};", n as u16 * 8)],
+        Op::Ret => vec!["m.pc = m.pop();".to_string()],
+        Op::Call => vec![format!("m.push(0x{:04x});", next), format!("m.pc = 0x{:04x};", nn)],
+        Op::Out => vec![format!("m.cpu.io.output(0x{:02x}, m.a);", n)],
+        Op::In => vec![format!("m.a = m.cpu.io.input(0x{:02x});", n)],
+        Op::Xthl => vec!["let v = m.read_word(m.sp);".to_string(), "m.write_word(m.sp, m.hl());".to_string(), "m.set_hl(v);".to_string()],
+        Op::Pchl => vec!["m.pc = m.hl();".to_string()],
+        Op::Xchg => vec!["let v = m.de();".to_string(), "m.set_de(m.hl());".to_string(), "m.set_hl(v);".to_string()],
+        Op::Di => vec!["m.cpu.interrupt_enabled = false;".to_string()],
+        Op::Sphl => vec!["m.sp = m.hl();".to_string()],
+        Op::Ei | Op::Hlt => unreachable!("{:?} is never recompiled", decoded.op),
+    }
+}
+
+// Assembly for the comment above each instruction
+fn mnemonic(decoded: &DecodedOp) -> String {
+    let n = decoded.bytes[1];
+    let nn = u16::from_le_bytes([decoded.bytes[1], decoded.bytes[2]]);
+    let r = |r: u8| REGISTERS[r as usize].to_uppercase();
+    let rp = |rp: u8| ["B", "D", "H", "SP"][rp as usize];
+    let cc = |cc: u8| ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"][cc as usize];
+    match decoded.op {
+        Op::Nop => "NOP".to_string(),
+        Op::Lxi(p) => format!("LXI {},{:04X}H", rp(p), nn),
+        Op::Stax(p) => format!("STAX {}", rp(p)),
+        Op::Inx(p) => format!("INX {}", rp(p)),
+        Op::Inr(d) => format!("INR {}", r(d)),
+        Op::Dcr(d) => format!("DCR {}", r(d)),
+        Op::Mvi(d) => format!("MVI {},{:02X}H", r(d), n),
+        Op::Dad(p) => format!("DAD {}", rp(p)),
+        Op::Ldax(p) => format!("LDAX {}", rp(p)),
+        Op::Dcx(p) => format!("DCX {}", rp(p)),
+        Op::Rlc => "RLC".to_string(),
+        Op::Rrc => "RRC".to_string(),
+        Op::Ral => "RAL".to_string(),
+        Op::Rar => "RAR".to_string(),
+        Op::Shld => format!("SHLD {:04X}H", nn),
+        Op::Daa => "DAA".to_string(),
+        Op::Lhld => format!("LHLD {:04X}H", nn),
+        Op::Cma => "CMA".to_string(),
+        Op::Sta => format!("STA {:04X}H", nn),
+        Op::Stc => "STC".to_string(),
+        Op::Lda => format!("LDA {:04X}H", nn),
+        Op::Cmc => "CMC".to_string(),
+        Op::Mov(d, s) => format!("MOV {},{}", r(d), r(s)),
+        Op::Hlt => "HLT".to_string(),
+        Op::Alu(alu, s) => format!("{} {}", ALU[alu as usize].to_uppercase(), r(s)),
+        Op::Rcc(c) => format!("R{}", cc(c)),
+        Op::Pop(3) => "POP PSW".to_string(),
+        Op::Pop(p) => format!("POP {}", rp(p)),
+        Op::Jcc(c) => format!("J{} {:04X}H", cc(c), nn),
+        Op::Jmp => format!("JMP {:04X}H", nn),
+        Op::Ccc(c) => format!("C{} {:04X}H", cc(c), nn),
+        Op::Push(3) => "PUSH PSW".to_string(),
+        Op::Push(p) => format!("PUSH {}", rp(p)),
+        Op::AluImmediate(alu) => {
+            let name = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"][alu as usize];
+            format!("{} {:02X}H", name, n)
+        }
+        Op::Rst(v) => format!("RST {}", v),
+        Op::Ret => "RET".to_string(),
+        Op::Call => format!("CALL {:04X}H", nn),
+        Op::Out => format!("OUT {:02X}H", n),
+        Op::In => format!("IN {:02X}H", n),
+        Op::Xthl => "XTHL".to_string(),
+        Op::Pchl => "PCHL".to_string(),
+        Op::Xchg => "XCHG".to_string(),
+        Op::Di => "DI".to_string(),
+        Op::Sphl => "SPHL".to_string(),
+        Op::Ei => "EI".to_string(),
+    }
+}
+
+// Signature of a generated dispatcher
+pub type Dispatcher<M, IO> = fn(&mut Machine<M, IO>, usize) -> bool;
+
+// What recompiled code runs on: the registers as plain fields, with everything else (memory,
+// I/O, interrupts, counters) left in the wrapped CPU. The CPU's own registers are stale until
+// into_cpu or a fallback to the interpreter copies them back.
+pub struct Machine<M: MemoryBus, IO: IOHandler> {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub cpu: Intel8080<M, IO>,
+}
+
+impl<M: MemoryBus, IO: IOHandler> Machine<M, IO> {
+    pub fn new(cpu: Intel8080<M, IO>) -> Machine<M, IO> {
+        let mut machine = Machine { a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, sp: 0, pc: 0, cpu };
+        machine.load_registers();
+        machine
+    }
+
+    pub fn into_cpu(mut self) -> Intel8080<M, IO> {
+        self.store_registers();
+        self.cpu
+    }
+
+    fn load_registers(&mut self) {
+        let state = self.cpu.state();
+        (self.a, self.f, self.b, self.c) = (state.a, state.f, state.b, state.c);
+        (self.d, self.e, self.h, self.l) = (state.d, state.e, state.h, state.l);
+        (self.sp, self.pc) = (state.sp, state.pc);
+    }
+
+    fn store_registers(&mut self) {
+        let mut state = self.cpu.state();
+        (state.a, state.f, state.b, state.c) = (self.a, self.f, self.b, self.c);
+        (state.d, state.e, state.h, state.l) = (self.d, self.e, self.h, self.l);
+        (state.sp, state.pc) = (self.sp, self.pc);
+        self.cpu.set_state(&state);
+    }
+
+    // Same contract as Intel8080::run_cycles. Recompiled blocks run wherever `run_block` has
+    // one; interrupts, HLT, EI and code without a block go through the interpreter.
+    pub fn run_cycles(&mut self, cycles: usize, run_block: Dispatcher<M, IO>) -> Result<usize, Trap> {
+        let start = self.cpu.total_ticks;
+        while self.cpu.total_ticks - start < cycles {
+            let interrupt_due = self.cpu.interrupt_enabled && self.cpu.interrupts.pending();
+            if !self.cpu.halted && !interrupt_due && !self.cpu.state().ei_delay && run_block(self, start + cycles) {
+                continue;
+            }
+            self.store_registers();
+            let result = self.cpu.step();
+            self.load_registers();
+            match result {
+                StepResult::Trap { trap, .. } if self.cpu.trap_policy == TrapPolicy::Stop => return Err(trap),
+                _ => {}
+            }
+        }
+        Ok(self.cpu.total_ticks - start)
+    }
+
+    // Counts one finished instruction and returns total_ticks
+    pub fn retire(&mut self, states: usize) -> usize {
+        self.cpu.total_ticks += states;
+        self.cpu.total_instructions += 1;
+        self.cpu.total_ticks
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        self.cpu.memory.read(address)
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.cpu.memory.write(address, value);
+    }
+
+    pub fn read_word(&mut self, address: u16) -> u16 {
+        u16::from_le_bytes([self.read(address), self.read(address.wrapping_add(1))])
+    }
+
+    pub fn write_word(&mut self, address: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.write(address, lo);
+        self.write(address.wrapping_add(1), hi);
+    }
+
+    pub fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write_word(self.sp, value);
+    }
+
+    pub fn pop(&mut self) -> u16 {
+        let value = self.read_word(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn psw(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f])
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        [self.b, self.c] = value.to_be_bytes();
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        [self.d, self.e] = value.to_be_bytes();
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        [self.h, self.l] = value.to_be_bytes();
+    }
+
+    // The always-one and always-zero flag bits are forced the way the interpreter does
+    pub fn set_psw(&mut self, value: u16) {
+        let [a, f] = value.to_be_bytes();
+        self.a = a;
+        self.f = f & 0b11010111 | 0b00000010;
+    }
+
+    // cc as encoded in the opcode: NZ, Z, NC, C, PO, PE, P, M
+    pub fn condition(&self, cc: u8) -> bool {
+        let mask = [0x40, 0x01, 0x04, 0x80][(cc / 2) as usize];
+        (self.f & mask != 0) == (cc % 2 == 1)
+    }
+
+    // The flag helpers below follow the interpreter's rules exactly, quirks included
+
+    fn set_szp(&mut self, result: u8) {
+        self.f &= !0b11000100;
+        self.f |= result & 0x80;
+        if result == 0 {
+            self.f |= 0x40;
+        }
+        if result.count_ones().is_multiple_of(2) {
+            self.f |= 0x04;
+        }
+    }
+
+    fn set_flag(&mut self, mask: u8, value: bool) {
+        if value {
+            self.f |= mask;
+        } else {
+            self.f &= !mask;
+        }
+    }
+
+    fn add3(&mut self, i1: u8, i2: u8, i3: u8) -> u8 {
+        let result = i1.wrapping_add(i2).wrapping_add(i3);
+        self.set_szp(result);
+        self.set_flag(0x10, ((i1 & 0x0F) + (i2 & 0x0F) + (i3 & 0x0F)) & 0x10 != 0);
+        self.set_flag(0x01, i1 as u16 + i2 as u16 + i3 as u16 > 0xFF);
+        result
+    }
+
+    pub fn add(&mut self, value: u8) {
+        self.a = self.add3(self.a, value, 0);
+    }
+
+    pub fn adc(&mut self, value: u8) {
+        self.a = self.add3(self.a, value, self.f & 0x01);
+    }
+
+    pub fn sub(&mut self, value: u8) {
+        self.cmp(value);
+        self.a = self.a.wrapping_sub(value);
+    }
+
+    pub fn sbb(&mut self, value: u8) {
+        let carry = self.f & 0x01;
+        let a = self.a;
+        self.a = self.add3(a, !value, 1 - carry);
+        self.set_flag(0x01, value as u16 + carry as u16 > a as u16);
+    }
+
+    pub fn ana(&mut self, value: u8) {
+        let result = self.a & value;
+        self.set_szp(result);
+        self.f &= 0b11101110;
+        self.set_flag(0x10, (self.a | value) & 0x08 != 0);
+        self.a = result;
+    }
+
+    pub fn xra(&mut self, value: u8) {
+        self.a ^= value;
+        self.set_szp(self.a);
+        self.f &= 0b11101110;
+    }
+
+    pub fn ora(&mut self, value: u8) {
+        self.a |= value;
+        self.set_szp(self.a);
+        self.f &= 0b11101110;
+    }
+
+    pub fn cmp(&mut self, value: u8) {
+        self.add3(self.a, !value, 1);
+        self.set_flag(0x01, value > self.a);
+    }
+
+    pub fn inr(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.set_szp(result);
+        self.set_flag(0x10, value & 0x0F == 0x0F);
+        result
+    }
+
+    pub fn dcr(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.set_szp(result);
+        self.set_flag(0x10, value & 0x0F != 0);
+        result
+    }
+
+    pub fn dad(&mut self, value: u16) {
+        let hl = self.hl();
+        let result = hl.wrapping_add(value);
+        self.set_flag(0x01, result < hl);
+        self.set_hl(result);
+    }
+
+    pub fn daa(&mut self) {
+        let msb = self.a >> 4;
+        let lsb = self.a & 0x0F;
+        let mut add = 0;
+        if lsb > 9 || self.f & 0x10 != 0 {
+            add = 6;
+        }
+        if msb > 9 || self.f & 0x01 != 0 || (msb >= 9 && lsb > 9) {
+            add += 0x60;
+            self.f |= 0x01;
+        }
+        let result = self.a.wrapping_add(add);
+        self.set_szp(result);
+        self.set_flag(0x10, (self.a & 0x0F) + (add & 0x0F) > 0x0F);
+        self.a = result;
+    }
+
+    pub fn rlc(&mut self) {
+        self.set_flag(0x01, self.a & 0x80 != 0);
+        self.a = self.a.rotate_left(1);
+    }
+
+    pub fn rrc(&mut self) {
+        self.set_flag(0x01, self.a & 0x01 != 0);
+        self.a = self.a.rotate_right(1);
+    }
+
+    pub fn ral(&mut self) {
+        let carry = self.f & 0x01;
+        self.set_flag(0x01, self.a & 0x80 != 0);
+        self.a = self.a << 1 | carry;
+    }
+
+    pub fn rar(&mut self) {
+        let carry = self.f & 0x01;
+        self.set_flag(0x01, self.a & 0x01 != 0);
+        self.a = self.a >> 1 | carry << 7;
+    }
+}
+
+#[cfg(test)]
+mod sample;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sums a table with DAA, calls a subroutine and jumps through PCHL to code that is only
+    // known as an entry point, then halts
+    const SAMPLE: [u8; 72] = [
+        0x31, 0x00, 0xF0, // 0100 LXI SP,F000H
+        0x21, 0x40, 0x01, // 0103 LXI H,0140H
+        0x06, 0x08,       // 0106 MVI B,08H
+        0xAF,             // 0108 XRA A
+        0x86,             // 0109 ADD M
+        0x27,             // 010A DAA
+        0x23,             // 010B INX H
+        0x05,             // 010C DCR B
+        0xC2, 0x09, 0x01, // 010D JNZ 0109H
+        0x32, 0x00, 0x20, // 0110 STA 2000H
+        0xCD, 0x30, 0x01, // 0113 CALL 0130H
+        0xF5,             // 0116 PUSH PSW
+        0xC1,             // 0117 POP B
+        0x21, 0x20, 0x01, // 0118 LXI H,0120H
+        0xE9,             // 011B PCHL
+        0x00, 0x00, 0x00, 0x00,
+        0x3E, 0x99,       // 0120 MVI A,99H
+        0xD6, 0xA5,       // 0122 SUI A5H
+        0xDE, 0x01,       // 0124 SBI 01H
+        0x9F,             // 0126 SBB A
+        0xE6, 0x0F,       // 0127 ANI 0FH
+        0x17,             // 0129 RAL
+        0x0F,             // 012A RRC
+        0xEB,             // 012B XCHG
+        0xE3,             // 012C XTHL
+        0x76,             // 012D HLT
+        0x00, 0x00,
+        0x11, 0x34, 0x12, // 0130 LXI D,1234H
+        0x19,             // 0133 DAD D
+        0x22, 0x02, 0x20, // 0134 SHLD 2002H
+        0x3A, 0x00, 0x20, // 0137 LDA 2000H
+        0xFE, 0x50,       // 013A CPI 50H
+        0xD8,             // 013C RC
+        0x3C,             // 013D INR A
+        0xC9,             // 013E RET
+        0x00,
+        0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0,
+    ];
+
+    fn sample_cpu() -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        for (n, byte) in SAMPLE.iter().enumerate() {
+            cpu.memory.poke(0x0100 + n as u16, *byte);
+        }
+        cpu.PC = 0x0100;
+        cpu
+    }
+
+    #[test]
+    fn discovers_reachable_blocks(){
+        let program = discover(&SAMPLE, 0x0100, &[0x0100]);
+        let starts: Vec<u16> = program.blocks.keys().copied().collect();
+        // The loop body, the return site and the subroutine with its conditional return
+        assert_eq!(starts, [0x0100, 0x0109, 0x0110, 0x0116, 0x0130, 0x013d]);
+        // 0x0120 is only reachable through PCHL
+        let program = discover(&SAMPLE, 0x0100, &[0x0100, 0x0120]);
+        // Execution carries on after HLT once an interrupt arrives, which runs through the
+        // padding at 0x012e into the subroutine
+        assert_eq!(program.block_count(), 8);
+        assert_eq!(program.covered_bytes(), SAMPLE.len() - 4 - 1 - 1 - 8);
+    }
+
+    // The checked in module has to match what the generator makes now. Run the tests with
+    // REGENERATE set to rewrite it.
+    #[test]
+    fn sample_is_current(){
+        let source = generate(&discover(&SAMPLE, 0x0100, &[0x0100, 0x0120]), "crate");
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/recompiler/sample.rs");
+        if std::env::var_os("REGENERATE").is_some() {
+            std::fs::write(path, &source).unwrap();
+        }
+        assert!(source == std::fs::read_to_string(path).unwrap(), "src/recompiler/sample.rs is out of date");
+    }
+
+    #[test]
+    fn sample_matches_interpreter(){
+        let mut interpreter = sample_cpu();
+        interpreter.run_cycles(2000).unwrap();
+        assert!(interpreter.halted);
+
+        let mut machine = Machine::new(sample_cpu());
+        machine.run_cycles(2000, sample::run_block).unwrap();
+        let recompiled = machine.into_cpu();
+        assert_eq!(recompiled.state(), interpreter.state());
+        assert_eq!(recompiled.total_instructions, interpreter.total_instructions);
+        assert_eq!(recompiled.memory.peek(0x2000), interpreter.memory.peek(0x2000));
+        assert_eq!(recompiled.memory.peek(0x2003), interpreter.memory.peek(0x2003));
+        assert_eq!(recompiled.memory.peek(0xEFFE), interpreter.memory.peek(0xEFFE));
+    }
+
+    #[test]
+    fn sample_stops_on_cycle_budget(){
+        // Same instruction boundaries as the interpreter for every budget
+        for cycles in 1..300 {
+            let mut interpreter = sample_cpu();
+            let ran = interpreter.run_cycles(cycles).unwrap();
+            let mut machine = Machine::new(sample_cpu());
+            assert_eq!(machine.run_cycles(cycles, sample::run_block), Ok(ran), "{} cycles", cycles);
+            assert_eq!(machine.into_cpu().state(), interpreter.state(), "{} cycles", cycles);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Recompiled from a 72 byte ROM at 0x0100 (FNV-1a 32471f31d8444017) by the recompile tool.
+// Don't edit, regenerate it instead.
+use crate::intel8080::IOHandler;
+use crate::memory::MemoryBus;
+use crate::recompiler::Machine;
+
+// Runs the block at m.pc, stopping early once m.cpu.total_ticks reaches `until`. Returns
+// false if there is no block there.
+pub fn run_block<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) -> bool {
+    match m.pc {
+        0x0100 => block_0100(m, until),
+        0x0109 => block_0109(m, until),
+        0x0110 => block_0110(m, until),
+        0x0116 => block_0116(m, until),
+        0x0120 => block_0120(m, until),
+        0x012e => block_012e(m, until),
+        0x0130 => block_0130(m, until),
+        0x013d => block_013d(m, until),
+        _ => return false,
+    }
+    true
+}
+
+fn block_0100<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 0100  LXI SP,F000H
+    m.sp = 0xf000;
+    if m.retire(10) >= until { m.pc = 0x0103; return; }
+    // 0103  LXI H,0140H
+    m.set_hl(0x0140);
+    if m.retire(10) >= until { m.pc = 0x0106; return; }
+    // 0106  MVI B,08H
+    m.b = 0x08;
+    if m.retire(7) >= until { m.pc = 0x0108; return; }
+    // 0108  XRA A
+    m.xra(m.a);
+    if m.retire(4) >= until { m.pc = 0x0109; return; }
+    // 0109  ADD M
+    let v = m.read(m.hl());
+    m.add(v);
+    if m.retire(7) >= until { m.pc = 0x010a; return; }
+    // 010a  DAA
+    m.daa();
+    if m.retire(4) >= until { m.pc = 0x010b; return; }
+    // 010b  INX H
+    m.set_hl(m.hl().wrapping_add(1));
+    if m.retire(5) >= until { m.pc = 0x010c; return; }
+    // 010c  DCR B
+    m.b = m.dcr(m.b);
+    if m.retire(5) >= until { m.pc = 0x010d; return; }
+    // 010d  JNZ 0109H
+    m.pc = if m.condition(0) { 0x0109 } else { 0x0110 };
+    m.retire(10);
+}
+
+fn block_0109<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 0109  ADD M
+    let v = m.read(m.hl());
+    m.add(v);
+    if m.retire(7) >= until { m.pc = 0x010a; return; }
+    // 010a  DAA
+    m.daa();
+    if m.retire(4) >= until { m.pc = 0x010b; return; }
+    // 010b  INX H
+    m.set_hl(m.hl().wrapping_add(1));
+    if m.retire(5) >= until { m.pc = 0x010c; return; }
+    // 010c  DCR B
+    m.b = m.dcr(m.b);
+    if m.retire(5) >= until { m.pc = 0x010d; return; }
+    // 010d  JNZ 0109H
+    m.pc = if m.condition(0) { 0x0109 } else { 0x0110 };
+    m.retire(10);
+}
+
+fn block_0110<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 0110  STA 2000H
+    m.write(0x2000, m.a);
+    if m.retire(13) >= until { m.pc = 0x0113; return; }
+    // 0113  CALL 0130H
+    m.push(0x0116);
+    m.pc = 0x0130;
+    m.retire(17);
+}
+
+fn block_0116<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 0116  PUSH PSW
+    m.push(m.psw());
+    if m.retire(11) >= until { m.pc = 0x0117; return; }
+    // 0117  POP B
+    let v = m.pop();
+    m.set_bc(v);
+    if m.retire(10) >= until { m.pc = 0x0118; return; }
+    // 0118  LXI H,0120H
+    m.set_hl(0x0120);
+    if m.retire(10) >= until { m.pc = 0x011b; return; }
+    // 011b  PCHL
+    m.pc = m.hl();
+    m.retire(5);
+}
+
+fn block_0120<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 0120  MVI A,99H
+    m.a = 0x99;
+    if m.retire(7) >= until { m.pc = 0x0122; return; }
+    // 0122  SUI A5H
+    m.sub(0xa5);
+    if m.retire(7) >= until { m.pc = 0x0124; return; }
+    // 0124  SBI 01H
+    m.sbb(0x01);
+    if m.retire(7) >= until { m.pc = 0x0126; return; }
+    // 0126  SBB A
+    m.sbb(m.a);
+    if m.retire(4) >= until { m.pc = 0x0127; return; }
+    // 0127  ANI 0FH
+    m.ana(0x0f);
+    if m.retire(7) >= until { m.pc = 0x0129; return; }
+    // 0129  RAL
+    m.ral();
+    if m.retire(4) >= until { m.pc = 0x012a; return; }
+    // 012a  RRC
+    m.rrc();
+    if m.retire(4) >= until { m.pc = 0x012b; return; }
+    // 012b  XCHG
+    let v = m.de();
+    m.set_de(m.hl());
+    m.set_hl(v);
+    if m.retire(4) >= until { m.pc = 0x012c; return; }
+    // 012c  XTHL
+    let v = m.read_word(m.sp);
+    m.write_word(m.sp, m.hl());
+    m.set_hl(v);
+    m.pc = 0x012d;
+    m.retire(18);
+}
+
+fn block_012e<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 012e  NOP
+    if m.retire(4) >= until { m.pc = 0x012f; return; }
+    // 012f  NOP
+    if m.retire(4) >= until { m.pc = 0x0130; return; }
+    // 0130  LXI D,1234H
+    m.set_de(0x1234);
+    if m.retire(10) >= until { m.pc = 0x0133; return; }
+    // 0133  DAD D
+    m.dad(m.de());
+    if m.retire(10) >= until { m.pc = 0x0134; return; }
+    // 0134  SHLD 2002H
+    m.write_word(0x2002, m.hl());
+    if m.retire(16) >= until { m.pc = 0x0137; return; }
+    // 0137  LDA 2000H
+    m.a = m.read(0x2000);
+    if m.retire(13) >= until { m.pc = 0x013a; return; }
+    // 013a  CPI 50H
+    m.cmp(0x50);
+    if m.retire(7) >= until { m.pc = 0x013c; return; }
+    // 013c  RC
+    if m.condition(3) {
+        m.cpu.total_ticks += 6;
+        m.pc = m.pop();
+    } else {
+        m.pc = 0x013d;
+    }
+    m.retire(5);
+}
+
+fn block_0130<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 0130  LXI D,1234H
+    m.set_de(0x1234);
+    if m.retire(10) >= until { m.pc = 0x0133; return; }
+    // 0133  DAD D
+    m.dad(m.de());
+    if m.retire(10) >= until { m.pc = 0x0134; return; }
+    // 0134  SHLD 2002H
+    m.write_word(0x2002, m.hl());
+    if m.retire(16) >= until { m.pc = 0x0137; return; }
+    // 0137  LDA 2000H
+    m.a = m.read(0x2000);
+    if m.retire(13) >= until { m.pc = 0x013a; return; }
+    // 013a  CPI 50H
+    m.cmp(0x50);
+    if m.retire(7) >= until { m.pc = 0x013c; return; }
+    // 013c  RC
+    if m.condition(3) {
+        m.cpu.total_ticks += 6;
+        m.pc = m.pop();
+    } else {
+        m.pc = 0x013d;
+    }
+    m.retire(5);
+}
+
+fn block_013d<M: MemoryBus, IO: IOHandler>(m: &mut Machine<M, IO>, until: usize) {
+    // 013d  INR A
+    m.a = m.inr(m.a);
+    if m.retire(5) >= until { m.pc = 0x013e; return; }
+    // 013e  RET
+    m.pc = m.pop();
+    m.retire(10);
+}
@@ -0,0 +1,195 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::save_state::{Snapshot, StateReader, StateWriter};
+
+// Ring of machine snapshots taken every `interval` frames. Only the newest snapshot is kept
+// whole; each older one is stored as the XOR against the snapshot after it with runs of
+// zeroes squeezed out, which is small because most of RAM doesn't change between captures.
+pub struct RewindBuffer {
+    interval: usize,
+    capacity: usize,
+    frames_since_capture: usize,
+    latest: Option<Vec<u8>>,
+    // deltas.back() turns `latest` into the snapshot before it, deltas.front() is the oldest
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: usize, capacity: usize) -> RewindBuffer {
+        assert!(interval > 0 && capacity > 0, "rewind interval and capacity must be non-zero");
+        RewindBuffer { interval, capacity, frames_since_capture: 0, latest: None, deltas: VecDeque::new() }
+    }
+
+    // Call once per emulated frame
+    pub fn record(&mut self, machine: &impl Snapshot) {
+        if self.latest.is_some() && self.frames_since_capture + 1 < self.interval {
+            self.frames_since_capture += 1;
+            return;
+        }
+        self.frames_since_capture = 0;
+
+        let mut writer = StateWriter::new();
+        machine.save_state(&mut writer);
+        let snapshot = writer.into_bytes();
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(compress(&xor(&snapshot, &latest)));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(snapshot);
+    }
+
+    // Steps back one snapshot and restores it. At the oldest snapshot the machine is put back
+    // to it again and false is returned.
+    pub fn rewind(&mut self, machine: &mut impl Snapshot) -> bool {
+        let Some(latest) = &mut self.latest else {
+            return false;
+        };
+        let stepped = match self.deltas.pop_back() {
+            Some(delta) => {
+                *latest = xor(latest, &decompress(&delta, latest.len()));
+                true
+            }
+            None => false,
+        };
+        self.frames_since_capture = 0;
+        machine.load_state(&mut StateReader::new(latest))
+            .expect("restoring a rewind snapshot failed");
+        stepped
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // Bytes held by snapshot data
+    pub fn memory_used(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+// Snapshots of the same machine always have the same length
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    assert_eq!(a.len(), b.len(), "rewind snapshots changed size");
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+// Alternating zero run / literal run lengths as LEB128, each literal run followed by its bytes
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeroes = data[i..].iter().take_while(|byte| **byte == 0).count();
+        i += zeroes;
+        let literals = data[i..].iter().take_while(|byte| **byte != 0).count();
+        write_length(&mut out, zeroes);
+        write_length(&mut out, literals);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn decompress(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() {
+        let zeroes = read_length(data, &mut i);
+        let literals = read_length(data, &mut i);
+        out.resize(out.len() + zeroes, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out.resize(len, 0);
+    out
+}
+
+fn write_length(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_length(data: &[u8], i: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_state::SaveStateError;
+
+    struct Ram {
+        bytes: Vec<u8>,
+    }
+
+    impl Snapshot for Ram {
+        fn save_state(&self, writer: &mut StateWriter) {
+            writer.write_bytes(&self.bytes);
+        }
+
+        fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+            let len = self.bytes.len();
+            self.bytes.copy_from_slice(reader.read_bytes(len)?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn compression_round_trip(){
+        let data = [0, 0, 0, 5, 6, 0, 7, 0, 0, 0, 0];
+        assert_eq!(decompress(&compress(&data), data.len()), data);
+        let zeroes = vec![0; 1000];
+        assert_eq!(compress(&zeroes).len(), 3);
+        assert_eq!(decompress(&compress(&zeroes), 1000), zeroes);
+    }
+
+    #[test]
+    fn steps_back_through_snapshots(){
+        let mut buffer = RewindBuffer::new(2, 3);
+        let mut ram = Ram { bytes: vec![0; 256] };
+        assert!(buffer.is_empty());
+        // Frames 0, 2, 4 and 6 are captured, the oldest drops out
+        for frame in 0..8u8 {
+            ram.bytes[frame as usize] = frame + 1;
+            buffer.record(&ram);
+        }
+        assert_eq!(buffer.len(), 3);
+
+        assert!(buffer.rewind(&mut ram));
+        assert_eq!(&ram.bytes[..8], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        assert!(buffer.rewind(&mut ram));
+        assert_eq!(&ram.bytes[..8], &[1, 2, 3, 0, 0, 0, 0, 0]);
+        assert!(!buffer.rewind(&mut ram));
+        assert_eq!(&ram.bytes[..8], &[1, 2, 3, 0, 0, 0, 0, 0]);
+
+        // Recording carries on from the restored point
+        ram.bytes[200] = 9;
+        buffer.record(&ram);
+        ram.bytes[201] = 9;
+        buffer.record(&ram);
+        assert!(buffer.rewind(&mut ram));
+        assert_eq!(ram.bytes[200], 0);
+    }
+}
@@ -0,0 +1,238 @@
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{fs, io, path::Path};
+
+// Save state files start with MAGIC, the format version and the hash of the ROM they were
+// made with, followed by each component's state in a fixed order. Bump VERSION whenever a
+// component changes what it writes. Saves from any other version are rejected, there is no
+// migration, so bumping it makes older saves stop loading.
+const MAGIC: [u8; 4] = *b"I8SS";
+pub const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 4 + 2 + 8;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    NotASaveState,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u64, found: u64 },
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            SaveStateError::Io(error) => write!(f, "{}", error),
+            SaveStateError::NotASaveState => write!(f, "not a save state file"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported (expected {})", version, VERSION)
+            }
+            SaveStateError::RomMismatch { expected, found } => {
+                write!(f, "save state was made with ROM {:016x}, but {:016x} is loaded", found, expected)
+            }
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Corrupt(what) => write!(f, "save state is corrupt: {}", what),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for SaveStateError {
+    fn from(error: io::Error) -> SaveStateError {
+        SaveStateError::Io(error)
+    }
+}
+
+// Implemented by every component that is part of a machine snapshot. load_state must read
+// exactly what save_state wrote.
+pub trait Snapshot {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { bytes: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.bytes.len() < len {
+            return Err(SaveStateError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+pub fn encode(rom_hash: u64, machine: &impl Snapshot) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    writer.write_bytes(&MAGIC);
+    writer.write_u16(VERSION);
+    writer.write_u64(rom_hash);
+    machine.save_state(&mut writer);
+    writer.into_bytes()
+}
+
+// On error the machine is left exactly as it was before the call
+pub fn decode(bytes: &[u8], rom_hash: u64, machine: &mut impl Snapshot) -> Result<(), SaveStateError> {
+    if bytes.len() < HEADER_SIZE || bytes[..4] != MAGIC {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let mut reader = StateReader::new(&bytes[4..]);
+    let version = reader.read_u16()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let found = reader.read_u64()?;
+    if found != rom_hash {
+        return Err(SaveStateError::RomMismatch { expected: rom_hash, found });
+    }
+
+    let mut backup = StateWriter::new();
+    machine.save_state(&mut backup);
+    let result = machine.load_state(&mut reader).and_then(|_| {
+        if reader.is_empty() { Ok(()) } else { Err(SaveStateError::Corrupt("trailing data")) }
+    });
+    if result.is_err() {
+        machine.load_state(&mut StateReader::new(&backup.into_bytes()))
+            .expect("restoring the machine's own state failed");
+    }
+    result
+}
+
+#[cfg(feature = "std")]
+pub fn save_to_file(path: &Path, rom_hash: u64, machine: &impl Snapshot) -> Result<(), SaveStateError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, encode(rom_hash, machine))?;
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+pub fn load_from_file(path: &Path, rom_hash: u64, machine: &mut impl Snapshot) -> Result<(), SaveStateError> {
+    decode(&fs::read(path)?, rom_hash, machine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter {
+        value: u16,
+    }
+
+    impl Snapshot for Counter {
+        fn save_state(&self, writer: &mut StateWriter) {
+            writer.write_u16(self.value);
+        }
+
+        fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+            self.value = reader.read_u16()?;
+            if self.value == 0xFFFF {
+                return Err(SaveStateError::Corrupt("counter overflow"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn round_trip(){
+        let bytes = encode(0x1234, &Counter { value: 42 });
+        let mut counter = Counter { value: 0 };
+        decode(&bytes, 0x1234, &mut counter).unwrap();
+        assert_eq!(counter.value, 42);
+    }
+
+    #[test]
+    fn rejects_other_rom_and_version(){
+        let mut bytes = encode(0x1234, &Counter { value: 42 });
+        let mut counter = Counter { value: 7 };
+        assert!(matches!(decode(&bytes, 0x5678, &mut counter), Err(SaveStateError::RomMismatch { expected: 0x5678, found: 0x1234 })));
+        bytes[4] = 0xFF;
+        assert!(matches!(decode(&bytes, 0x1234, &mut counter), Err(SaveStateError::UnsupportedVersion(0x00FF))));
+        bytes[4..6].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        assert!(matches!(decode(&bytes, 0x1234, &mut counter), Err(SaveStateError::UnsupportedVersion(_))));
+        assert!(matches!(decode(b"nope", 0x1234, &mut counter), Err(SaveStateError::NotASaveState)));
+        assert_eq!(counter.value, 7);
+    }
+
+    #[test]
+    fn failed_load_leaves_machine_untouched(){
+        let mut counter = Counter { value: 7 };
+        let bytes = encode(0x1234, &Counter { value: 0xFFFF });
+        assert!(matches!(decode(&bytes, 0x1234, &mut counter), Err(SaveStateError::Corrupt(_))));
+        let bytes = encode(0x1234, &Counter { value: 42 });
+        assert!(matches!(decode(&bytes[..bytes.len() - 1], 0x1234, &mut counter), Err(SaveStateError::Truncated)));
+        assert_eq!(counter.value, 7);
+    }
+}
@@ -0,0 +1,76 @@
+use core::time::Duration;
+use crate::host::Clock;
+
+// Paces emulation against the wall clock once per frame. The CPU core only counts cycles,
+// so anything that doesn't need real time (tests, tools, headless runs) just skips this.
+pub struct FrameScheduler<C: Clock> {
+    frame_duration: Duration,
+    next_frame: Duration,
+    clock: C,
+}
+
+impl<C: Clock> FrameScheduler<C> {
+    pub fn new(frames_per_second: u32, clock: C) -> FrameScheduler<C> {
+        FrameScheduler {
+            frame_duration: Duration::from_secs(1) / frames_per_second,
+            next_frame: clock.now(),
+            clock,
+        }
+    }
+
+    // Sleeps until the current frame's slot has passed. When the host falls more than a
+    // frame behind, the schedule is reset instead of running frames back to back to catch up.
+    pub fn wait_for_next_frame(&mut self) {
+        self.next_frame += self.frame_duration;
+        let now = self.clock.now();
+        if self.next_frame > now {
+            self.clock.sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_duration {
+            self.next_frame = now;
+        }
+    }
+
+    // Restarts pacing from now, e.g. after a save state was loaded
+    pub fn reset(&mut self) {
+        self.next_frame = self.clock.now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only moves when slept on, or when a test pushes it along
+    struct FakeClock {
+        now: Duration,
+        slept: Duration,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.slept += duration;
+            self.now += duration;
+        }
+    }
+
+    #[test]
+    fn sleeps_out_the_rest_of_each_frame(){
+        let frame = Duration::from_millis(20);
+        let start = Duration::from_secs(5);
+        let mut scheduler = FrameScheduler::new(50, FakeClock { now: start, slept: Duration::ZERO });
+        scheduler.clock.now += Duration::from_millis(5);
+        scheduler.wait_for_next_frame();
+        assert_eq!(scheduler.clock.slept, Duration::from_millis(15));
+
+        // Falling more than a frame behind restarts the schedule rather than catching up
+        scheduler.clock.now += frame * 3;
+        scheduler.wait_for_next_frame();
+        scheduler.wait_for_next_frame();
+        assert_eq!(scheduler.clock.slept, Duration::from_millis(15) + frame);
+        assert_eq!(scheduler.clock.now, start + frame * 5);
+    }
+}
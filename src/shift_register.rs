@@ -1,3 +1,6 @@
+use crate::save_state::{SaveStateError, Snapshot, StateReader, StateWriter};
+
+#[derive(Clone)]
 pub struct ShiftRegister {
     data: u16,
     offset: u8,
@@ -23,8 +26,21 @@ impl ShiftRegister {
     }
 }
 
+impl Snapshot for ShiftRegister {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.data);
+        writer.write_u8(self.offset);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.data = reader.read_u16()?;
+        self.offset = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
 mod tests{
-    use sdl2::libc::printf;
     use super::*;
     #[test]
     fn test1(){
@@ -0,0 +1,143 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+
+// Names and source lines for a ROM, loaded from files made alongside it, so debuggers can
+// show more than bare addresses.
+
+// Hex, as 1A2B, 0x1A2B, $1A2B or 1A2BH. Every tool that takes an address uses this.
+pub fn parse_address(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')).unwrap_or(text);
+    let digits = digits.strip_suffix(['h', 'H']).unwrap_or(digits);
+    if digits.is_empty() || digits.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// One `NAME ADDR` per line, `NAME EQU ADDR` and `NAME = ADDR` work too. Addresses are hex,
+// `;` starts a comment.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    by_name: BTreeMap<String, u16>,
+    by_address: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let mut words = line.split_whitespace().filter(|word| !word.eq_ignore_ascii_case("equ") && *word != "=");
+            let (Some(name), Some(address), None) = (words.next(), words.next(), words.next()) else {
+                continue;
+            };
+            if let Some(address) = parse_address(address) {
+                symbols.insert(name.trim_end_matches(':'), address);
+            }
+        }
+        symbols
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.by_name.insert(name.to_string(), address);
+        self.by_address.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    // The closest symbol at or below `address` and how far past it `address` is
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        let (&start, name) = self.by_address.range(..=address).next_back()?;
+        Some((name, address - start))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+// An assembler listing, or anything else where lines of code start with their address as four
+// hex digits, e.g. `1A2B  3E 42   MVI A,42H` or the disassemble tool's output.
+// Line numbers count from 1.
+#[derive(Clone, Default)]
+pub struct Listing {
+    lines: BTreeMap<u16, usize>,
+    addresses: BTreeMap<usize, u16>,
+}
+
+impl Listing {
+    pub fn parse(text: &str) -> Listing {
+        let mut listing = Listing::default();
+        for (index, line) in text.lines().enumerate() {
+            if line.starts_with(char::is_whitespace) {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (Some(first), Some(_)) = (words.next(), words.next()) else {
+                continue;
+            };
+            let first = first.trim_end_matches(':');
+            if first.len() != 4 {
+                continue;
+            }
+            if let Some(address) = parse_address(first) {
+                // A label on its own line shares the address of the code after it, which wins
+                listing.lines.insert(address, index + 1);
+                listing.addresses.insert(index + 1, address);
+            }
+        }
+        listing
+    }
+
+    // The line for the instruction at `address`
+    pub fn line(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).copied()
+    }
+
+    // The address of the first code at or after `line`, and the line that code is shown on
+    pub fn address(&self, line: usize) -> Option<(usize, u16)> {
+        let (_, &address) = self.addresses.range(line..).next()?;
+        Some((self.lines[&address], address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        for text in ["1A2B", "0x1A2B", "$1A2B", "1A2BH", "1a2bh"] {
+            assert_eq!(parse_address(text), Some(0x1A2B), "{}", text);
+        }
+        for text in ["", "0x", "12345", "1G", "-1"] {
+            assert_eq!(parse_address(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn parses_symbols_and_listings() {
+        let symbols = Symbols::parse("; Space Invaders\nReset 0000\nDrawShot: EQU 1A3BH\nTimer = $2000 ; frame counter\nnot a symbol\n");
+        assert_eq!(symbols.address("DrawShot"), Some(0x1A3B));
+        assert_eq!(symbols.lookup(0x1A40), Some(("DrawShot", 5)));
+        assert_eq!(symbols.lookup(0x2000), Some(("Timer", 0)));
+        assert_eq!(symbols.address("not"), None);
+
+        let listing = Listing::parse("        ORG 0\n0000 START:\n0000 3E 42    MVI A,42H\n0002 C3 00 00 JMP START\nADD B\n0005\n");
+        assert_eq!(listing.line(0x0000), Some(3));
+        assert_eq!(listing.line(0x0002), Some(4));
+        assert_eq!(listing.address(1), Some((3, 0x0000)));
+        assert_eq!(listing.address(4), Some((4, 0x0002)));
+        assert_eq!(listing.address(5), None);
+    }
+}
@@ -0,0 +1,264 @@
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ops::RangeInclusive;
+use crate::disassembler::Disassembler;
+use crate::intel8080::{Access, CpuState, Flag, IOHandler, Intel8080, Op, StepResult, Trap, TrapPolicy, OPCODES};
+use crate::memory::MemoryBus;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceFormat {
+    // Cycles, PC, instruction bytes, disassembly, registers and flags:
+    //      12345  1A2B  3E 42     MVI A 66        A=00 F=-Z-P- BC=0000 DE=0000 HL=2400 SP=23FE
+    Full,
+    // The line many 8080 emulators log for the CPU test ROMs, to diff against them:
+    // PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0	(C3 AB 01 00)
+    Compact,
+}
+
+// The groups of the datasheet's instruction set summary
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum InstructionClass {
+    Transfer,
+    Arithmetic,
+    Logical,
+    Branch,
+    Stack,
+    Io,
+    Control,
+}
+
+impl InstructionClass {
+    pub const ALL: [InstructionClass; 7] = [
+        InstructionClass::Transfer,
+        InstructionClass::Arithmetic,
+        InstructionClass::Logical,
+        InstructionClass::Branch,
+        InstructionClass::Stack,
+        InstructionClass::Io,
+        InstructionClass::Control,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InstructionClass::Transfer => "transfer",
+            InstructionClass::Arithmetic => "arithmetic",
+            InstructionClass::Logical => "logical",
+            InstructionClass::Branch => "branch",
+            InstructionClass::Stack => "stack",
+            InstructionClass::Io => "io",
+            InstructionClass::Control => "control",
+        }
+    }
+
+    pub fn of(opcode: u8) -> InstructionClass {
+        match OPCODES[opcode as usize] {
+            Op::Mov(..) | Op::Mvi(_) | Op::Lxi(_) | Op::Lda | Op::Sta | Op::Lhld | Op::Shld | Op::Ldax(_) | Op::Stax(_)
+            | Op::Xchg => InstructionClass::Transfer,
+            Op::Alu(alu, _) | Op::AluImmediate(alu) if alu < 4 => InstructionClass::Arithmetic,
+            Op::Inr(_) | Op::Dcr(_) | Op::Inx(_) | Op::Dcx(_) | Op::Dad(_) | Op::Daa => InstructionClass::Arithmetic,
+            Op::Alu(..) | Op::AluImmediate(_) | Op::Rlc | Op::Rrc | Op::Ral | Op::Rar | Op::Cma | Op::Stc | Op::Cmc => {
+                InstructionClass::Logical
+            }
+            Op::Jmp | Op::Jcc(_) | Op::Call | Op::Ccc(_) | Op::Ret | Op::Rcc(_) | Op::Rst(_) | Op::Pchl => InstructionClass::Branch,
+            Op::Push(_) | Op::Pop(_) | Op::Xthl | Op::Sphl => InstructionClass::Stack,
+            Op::In | Op::Out => InstructionClass::Io,
+            Op::Ei | Op::Di | Op::Hlt | Op::Nop => InstructionClass::Control,
+        }
+    }
+}
+
+// Writes a line for every instruction run, showing the state from before it ran. Steps over
+// the CPU one instruction at a time, like the debugger.
+pub struct Tracer {
+    pub format: TraceFormat,
+    // Only instructions at addresses in one of these are traced, or all of them when empty
+    pub ranges: Vec<RangeInclusive<u16>>,
+    // Only instructions of these classes are traced, or all of them when empty
+    pub classes: BTreeSet<InstructionClass>,
+    // Reaching start turns tracing on and reaching stop turns it off again (before the
+    // instruction there runs). Without a start it is on from the beginning.
+    pub start: Option<u16>,
+    pub stop: Option<u16>,
+    // Port writes, accepted interrupts and traps are noted at the end of the line
+    pub annotate: bool,
+    active: Option<bool>,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat) -> Tracer {
+        Tracer {
+            format,
+            ranges: Vec::new(),
+            classes: BTreeSet::new(),
+            start: None,
+            stop: None,
+            annotate: true,
+            active: None,
+        }
+    }
+
+    fn traces(&mut self, pc: u16, opcode: u8) -> bool {
+        let active = self.active.get_or_insert(self.start.is_none());
+        if self.start == Some(pc) {
+            *active = true;
+        }
+        if self.stop == Some(pc) {
+            *active = false;
+        }
+        *active
+            && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+            && (self.classes.is_empty() || self.classes.contains(&InstructionClass::of(opcode)))
+    }
+
+    // Runs cpu.step(), appending the line for what it ran to `out` if that is traced. Idle
+    // periods in the halt state aren't.
+    pub fn step<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, out: &mut String) -> StepResult {
+        let logging = self.annotate && cpu.accesses.is_none();
+        if logging {
+            cpu.accesses = Some(Vec::new());
+        }
+        let (before, bytes, result) = traced_step(cpu);
+        let accesses = if logging { cpu.accesses.take().unwrap_or_default() } else { Vec::new() };
+        if before.halted && matches!(result, StepResult::Halted { .. }) {
+            return result;
+        }
+        if !self.traces(before.pc, bytes[0]) {
+            return result;
+        }
+        match self.format {
+            TraceFormat::Full => write_full(out, &before, &bytes),
+            TraceFormat::Compact => write_compact(out, &before, &bytes),
+        }
+        if self.annotate {
+            for access in accesses {
+                if let Access::Output(port, value) = access {
+                    let _ = write!(out, " ; port {:02X} <- {:02X}", port, value);
+                }
+            }
+            match result {
+                StepResult::InterruptAccepted { .. } => out.push_str(" ; interrupt"),
+                StepResult::Trap { trap, .. } => {
+                    let _ = write!(out, " ; {}", trap);
+                }
+                _ => {}
+            }
+        }
+        out.push('\n');
+        result
+    }
+
+    // Like Intel8080::run_cycles, tracing every instruction into `out`
+    pub fn run_cycles<M: MemoryBus, IO: IOHandler>(&mut self, cpu: &mut Intel8080<M, IO>, cycles: usize,
+                                                   out: &mut String) -> Result<usize, Trap> {
+        let start = cpu.total_ticks;
+        while cpu.total_ticks - start < cycles {
+            if let StepResult::Trap { trap, .. } = self.step(cpu, out) && cpu.trap_policy == TrapPolicy::Stop {
+                return Err(trap);
+            }
+        }
+        Ok(cpu.total_ticks - start)
+    }
+}
+
+// Runs cpu.step() and returns the state from before it and the bytes of the instruction it ran.
+// An idle period in the halt state (Halted from a halted state) ran nothing.
+pub(crate) fn traced_step<M: MemoryBus, IO: IOHandler>(cpu: &mut Intel8080<M, IO>) -> (CpuState, [u8; 4], StepResult) {
+    let before = cpu.state();
+    let mut bytes = [0, 1, 2, 3].map(|offset| cpu.memory.peek(before.pc.wrapping_add(offset)));
+    let result = cpu.step();
+    if let StepResult::InterruptAccepted { vector, .. } = result {
+        // The instruction came from the bus, not from memory at PC
+        bytes = [0xFF; 4];
+        bytes[..vector.bytes().len()].copy_from_slice(vector.bytes());
+    }
+    (before, bytes, result)
+}
+
+// Set flags by letter, e.g. -Z-P- for zero and parity
+pub(crate) fn flag_letters(f: u8) -> String {
+    Flag::ALL.iter().zip("SZAPC".chars()).map(|(flag, name)| if f & flag.mask() != 0 { name } else { '-' }).collect()
+}
+
+pub(crate) fn write_full(out: &mut String, state: &CpuState, bytes: &[u8; 4]) {
+    let mut disassembler = Disassembler::new();
+    disassembler.load(bytes.to_vec());
+    let text = disassembler.next_instruction();
+    let mut hex = String::new();
+    for byte in &bytes[..disassembler.position()] {
+        let _ = write!(hex, "{:02X} ", byte);
+    }
+    let _ = write!(out, "{:>10}  {:04X}  {:<9} {:<16}A={:02X} F={} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X}",
+                   state.total_cycles, state.pc, hex, text, state.a, flag_letters(state.f), state.b, state.c, state.d, state.e, state.h,
+                   state.l, state.sp);
+}
+
+fn write_compact(out: &mut String, state: &CpuState, bytes: &[u8; 4]) {
+    let _ = write!(out, "PC: {:04X}, AF: {:02X}{:02X}, BC: {:02X}{:02X}, DE: {:02X}{:02X}, HL: {:02X}{:02X}, SP: {:04X}, CYC: {}\t({:02X} {:02X} {:02X} {:02X})",
+                   state.pc, state.a, state.f, state.b, state.c, state.d, state.e, state.h, state.l, state.sp,
+                   state.total_cycles, bytes[0], bytes[1], bytes[2], bytes[3]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::InterruptVector;
+
+    fn program(bytes: &[u8]) -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.load_program(bytes.to_vec());
+        cpu
+    }
+
+    #[test]
+    fn traces_instructions_with_annotations() {
+        // MVI A,20h; OUT 3; EI; NOP; NOP
+        let mut cpu = program(&[0x3E, 0x20, 0xD3, 0x03, 0xFB, 0x00, 0x00]);
+        let mut tracer = Tracer::new(TraceFormat::Full);
+        let mut out = String::new();
+        for _ in 0..3 {
+            tracer.step(&mut cpu, &mut out);
+        }
+        cpu.interrupts.raise(1);
+        tracer.step(&mut cpu, &mut out);
+        tracer.step(&mut cpu, &mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "         0  0000  3E 20     MVI A 32        A=00 F=----- BC=0000 DE=0000 HL=0000 SP=0000");
+        assert!(lines[1].starts_with("         7  0002  D3 03     OUT 3 ") && lines[1].ends_with(" ; port 03 <- 20"));
+        assert!(lines[4].starts_with("        25  0006  CF ") && lines[4].ends_with(" ; interrupt"));
+        assert_eq!(InstructionClass::of(InterruptVector::rst(1).bytes()[0]), InstructionClass::Branch);
+
+        let mut cpu = program(&[0xC3, 0xAB, 0x01]);
+        let mut tracer = Tracer::new(TraceFormat::Compact);
+        let mut out = String::new();
+        tracer.step(&mut cpu, &mut out);
+        assert_eq!(out, "PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\t(C3 AB 01 00)\n");
+    }
+
+    #[test]
+    fn filters_and_triggers() {
+        // 0: INR B; 1: JMP 4; 4: INR C; 5: MOV A,B; 6: JMP 0
+        let mut cpu = program(&[0x04, 0xC3, 0x04, 0x00, 0x0C, 0x78, 0xC3, 0x00, 0x00]);
+        let mut tracer = Tracer::new(TraceFormat::Compact);
+        tracer.classes.insert(InstructionClass::Arithmetic);
+        tracer.ranges.push(0x0004..=0x0007);
+        let mut out = String::new();
+        for _ in 0..10 {
+            tracer.step(&mut cpu, &mut out);
+        }
+        let pcs: Vec<&str> = out.lines().map(|line| &line[4..8]).collect();
+        assert_eq!(pcs, ["0004", "0004"]);
+
+        let mut cpu = program(&[0x04, 0xC3, 0x04, 0x00, 0x0C, 0x78, 0xC3, 0x00, 0x00]);
+        let mut tracer = Tracer::new(TraceFormat::Compact);
+        tracer.start = Some(0x0004);
+        tracer.stop = Some(0x0006);
+        let mut out = String::new();
+        tracer.run_cycles(&mut cpu, 100, &mut out).unwrap();
+        let pcs: Vec<&str> = out.lines().map(|line| &line[4..8]).collect();
+        assert_eq!(&pcs[..4], ["0004", "0005", "0004", "0005"]);
+        assert!(pcs.iter().all(|&pc| pc == "0004" || pc == "0005"));
+    }
+}
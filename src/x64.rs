@@ -0,0 +1,287 @@
+// Just enough of an x86-64 assembler for the JIT. Register operands are 32 bits wide unless
+// the method name says otherwise; writing a 32-bit register clears the upper half.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn high(self) -> u8 {
+        self as u8 >> 3
+    }
+}
+
+// Two-operand ALU instructions, numbered by their /digit in the 0x81 group
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Cond {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+}
+
+// A rel32 field waiting for its target
+#[must_use]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Assembler {
+    pub code: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler { code: Vec::new() }
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, base: u8, force: bool) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | base >> 3;
+        if rex != 0x40 || force {
+            self.code.push(rex);
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: Reg) {
+        self.code.push(0xC0 | (reg & 7) << 3 | rm.low());
+    }
+
+    // [base + disp8]
+    fn modrm_mem(&mut self, reg: u8, base: Reg, disp: i8) {
+        self.code.push(0x40 | (reg & 7) << 3 | base.low());
+        if base.low() == 4 {
+            self.code.push(0x24);
+        }
+        self.code.push(disp as u8);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, src as u8, dst as u8, false);
+        self.code.push(0x89);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    pub fn mov_imm(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst as u8, false);
+        self.code.push(0xB8 + dst.low());
+        self.imm32(imm);
+    }
+
+    pub fn mov_imm64(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, 0, dst as u8, false);
+        self.code.push(0xB8 + dst.low());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.rex(false, src as u8, dst as u8, false);
+        self.code.push((op as u8) << 3 | 0x01);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    pub fn alu_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst as u8, false);
+        self.code.push(0x81);
+        self.modrm_reg(op as u8, dst);
+        self.imm32(imm);
+    }
+
+    pub fn test_imm(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst as u8, false);
+        self.code.push(0xF7);
+        self.modrm_reg(0, dst);
+        self.imm32(imm);
+    }
+
+    pub fn shl(&mut self, dst: Reg, count: u8) {
+        self.rex(false, 0, dst as u8, false);
+        self.code.push(0xC1);
+        self.modrm_reg(4, dst);
+        self.code.push(count);
+    }
+
+    pub fn shr(&mut self, dst: Reg, count: u8) {
+        self.rex(false, 0, dst as u8, false);
+        self.code.push(0xC1);
+        self.modrm_reg(5, dst);
+        self.code.push(count);
+    }
+
+    // movzx dst, low byte of src
+    pub fn movzx8(&mut self, dst: Reg, src: Reg) {
+        // REX is needed to reach SPL/BPL/SIL/DIL rather than AH/CH/DH/BH
+        self.rex(false, dst as u8, src as u8, src.low() >= 4);
+        self.code.extend_from_slice(&[0x0F, 0xB6]);
+        self.modrm_reg(dst as u8, src);
+    }
+
+    // movzx dst, byte [base + index]
+    pub fn movzx8_indexed(&mut self, dst: Reg, base: Reg, index: Reg) {
+        assert!(base.low() != 5, "base register needs a displacement");
+        let rex = 0x40 | (dst.high()) << 2 | index.high() << 1 | base.high();
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+        self.code.extend_from_slice(&[0x0F, 0xB6, (dst.low()) << 3 | 0x04, index.low() << 3 | base.low()]);
+    }
+
+    pub fn load32(&mut self, dst: Reg, base: Reg, disp: i8) {
+        self.rex(false, dst as u8, base as u8, false);
+        self.code.push(0x8B);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    pub fn load64(&mut self, dst: Reg, base: Reg, disp: i8) {
+        self.rex(true, dst as u8, base as u8, false);
+        self.code.push(0x8B);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    pub fn store32(&mut self, base: Reg, disp: i8, src: Reg) {
+        self.rex(false, src as u8, base as u8, false);
+        self.code.push(0x89);
+        self.modrm_mem(src as u8, base, disp);
+    }
+
+    pub fn store64(&mut self, base: Reg, disp: i8, src: Reg) {
+        self.rex(true, src as u8, base as u8, false);
+        self.code.push(0x89);
+        self.modrm_mem(src as u8, base, disp);
+    }
+
+    pub fn cmp_mem8_imm(&mut self, base: Reg, disp: i8, imm: u8) {
+        self.rex(false, 0, base as u8, false);
+        self.code.push(0x80);
+        self.modrm_mem(7, base, disp);
+        self.code.push(imm);
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, reg as u8, false);
+        self.code.push(0x50 + reg.low());
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, reg as u8, false);
+        self.code.push(0x58 + reg.low());
+    }
+
+    pub fn add_rsp(&mut self, imm: i8) {
+        self.code.extend_from_slice(&[0x48, 0x83, 0xC4, imm as u8]);
+    }
+
+    pub fn sub_rsp(&mut self, imm: i8) {
+        self.code.extend_from_slice(&[0x48, 0x83, 0xEC, imm as u8]);
+    }
+
+    pub fn call(&mut self, target: Reg) {
+        self.rex(false, 0, target as u8, false);
+        self.code.push(0xFF);
+        self.modrm_reg(2, target);
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+
+    pub fn jump(&mut self) -> Label {
+        self.code.push(0xE9);
+        self.imm32(0);
+        Label(self.code.len() - 4)
+    }
+
+    pub fn jump_if(&mut self, cond: Cond) -> Label {
+        self.code.extend_from_slice(&[0x0F, 0x80 + cond as u8]);
+        self.imm32(0);
+        Label(self.code.len() - 4)
+    }
+
+    // Points a forward jump at the current position
+    pub fn bind(&mut self, label: Label) {
+        let offset = (self.code.len() - (label.0 + 4)) as u32;
+        self.code[label.0..label.0 + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected bytes checked against a reference assembler
+    #[test]
+    fn encodings(){
+        let mut asm = Assembler::new();
+        asm.mov(Reg::R13, Reg::Rax);
+        asm.mov(Reg::Rax, Reg::Rbx);
+        asm.alu_imm(Alu::And, Reg::R14, 0x3B);
+        asm.alu(Alu::Xor, Reg::R8, Reg::Rcx);
+        asm.movzx8(Reg::Rax, Reg::Rbp);
+        asm.movzx8(Reg::Rax, Reg::R12);
+        asm.movzx8_indexed(Reg::Rcx, Reg::Rsi, Reg::Rax);
+        asm.load64(Reg::Rdi, Reg::Rsp, 0);
+        asm.store32(Reg::Rdi, 8, Reg::R12);
+        asm.cmp_mem8_imm(Reg::Rdi, 36, 0);
+        asm.mov_imm64(Reg::Rax, 0x1122334455667788);
+        asm.call(Reg::Rax);
+        asm.push(Reg::R15);
+        asm.shr(Reg::R9, 8);
+        assert_eq!(asm.code, [
+            0x41, 0x89, 0xC5,
+            0x89, 0xD8,
+            0x41, 0x81, 0xE6, 0x3B, 0x00, 0x00, 0x00,
+            0x41, 0x31, 0xC8,
+            0x40, 0x0F, 0xB6, 0xC5,
+            0x41, 0x0F, 0xB6, 0xC4,
+            0x0F, 0xB6, 0x0C, 0x06,
+            0x48, 0x8B, 0x7C, 0x24, 0x00,
+            0x44, 0x89, 0x67, 0x08,
+            0x80, 0x7F, 0x24, 0x00,
+            0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+            0xFF, 0xD0,
+            0x41, 0x57,
+            0x41, 0xC1, 0xE9, 0x08,
+        ]);
+    }
+
+    #[test]
+    fn forward_jumps(){
+        let mut asm = Assembler::new();
+        let label = asm.jump_if(Cond::NotEqual);
+        asm.ret();
+        asm.bind(label);
+        assert_eq!(asm.code, [0x0F, 0x85, 0x01, 0x00, 0x00, 0x00, 0xC3]);
+    }
+}